@@ -0,0 +1,153 @@
+use std::path::Path;
+use exr::prelude::*;
+use crate::color::Chromaticities;
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+/// Compositing passes of one accumulate buffer entry, averaged over `frames` frames.
+///
+/// The four lighting passes add up to the path tracer's image. Depth and object ID come
+/// from the latest sample instead, so that they do not blend across edges.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Aov
+{
+    /// Lighting of the first diffuse hit.
+    direct_diffuse: [f32; 3],
+    /// Distance along the camera ray to the first hit.
+    depth: f32,
+    /// Light that bounced again after the first diffuse hit.
+    indirect_diffuse: [f32; 3],
+    /// Primitive kind in the high 16 bits and its index in the low ones, plus one; zero is the background.
+    object_id: u32,
+    /// Light arriving through a clean coat reflection or glass at the first hit.
+    specular: [f32; 3],
+    frames: u32,
+    /// Lights, emissive materials and the sky seen directly.
+    emission: [f32; 3],
+    _padding0: f32,
+    albedo: [f32; 3],
+    _padding1: f32,
+    normal: [f32; 3],
+    _padding2: f32,
+}
+
+impl GpuLayout for Aov
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Aov { direct_diffuse, depth, indirect_diffuse, object_id, specular, frames, emission, albedo, normal })
+    }
+}
+
+/// Size of the AOV buffer, which holds a single unused entry unless the passes are recorded.
+pub fn buffer_size(entries: u32, enabled: bool) -> wgpu::BufferAddress
+{
+    let entries = if enabled { entries as usize } else { 1 };
+    (entries * std::mem::size_of::<Aov>()) as wgpu::BufferAddress
+}
+
+/// Accumulate buffer entry shown at a pixel of a `width` by `height` image, top row first,
+/// as `pixel_index` in the shader maps it.
+fn entry_index(x: usize, y: usize, width: usize, height: usize, grid: usize) -> usize
+{
+    let cell_x = x * grid / width;
+    let cell_y = (height - 1 - y) * grid / height;
+    cell_y + cell_x * grid
+}
+
+/// Reads one of the three channel passes of an entry.
+type Pass = fn(&Aov) -> [f32; 3];
+
+/// Writes the image as a multi-channel EXR: the beauty pass as R, G and B, and the AOVs,
+/// if given, in layers named like `diffuse_direct.R`, tagged with the working space's `chromaticities`.
+///
+/// `accumulation` and `aovs` are the `grid` by `grid` accumulate and AOV buffers.
+pub fn write_exr(
+    path: &Path,
+    width: u32,
+    height: u32,
+    grid: u32,
+    accumulation: &[f32],
+    aovs: Option<&[Aov]>,
+    chromaticities: Chromaticities,
+) -> Result<()>
+{
+    let (width, height, grid) = (width as usize, height as usize, grid as usize);
+    let entries: Vec<usize> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| entry_index(x, y, width, height, grid)))
+        .collect();
+    let channel = |name: &str, value: &dyn Fn(usize) -> f32| {
+        AnyChannel::new(name, FlatSamples::F32(entries.iter().map(|&i| value(i)).collect()))
+    };
+    let mut channels: Vec<AnyChannel<FlatSamples>> = ["R", "G", "B"].iter().enumerate()
+        .map(|(c, name)| channel(name, &|i| accumulation[4 * i + c]))
+        .collect();
+    if let Some(aovs) = aovs
+    {
+        let passes: [(&str, [&str; 3], Pass); 6] = [
+            ("diffuse_direct", ["R", "G", "B"], |aov| aov.direct_diffuse),
+            ("diffuse_indirect", ["R", "G", "B"], |aov| aov.indirect_diffuse),
+            ("specular", ["R", "G", "B"], |aov| aov.specular),
+            ("emission", ["R", "G", "B"], |aov| aov.emission),
+            ("albedo", ["R", "G", "B"], |aov| aov.albedo),
+            ("normal", ["X", "Y", "Z"], |aov| aov.normal),
+        ];
+        for (layer, names, pass) in passes {
+            for (c, name) in names.iter().enumerate() {
+                channels.push(channel(&format!("{}.{}", layer, name), &|i| pass(&aovs[i])[c]));
+            }
+        }
+        channels.push(channel("depth.Z", &|i| aovs[i].depth));
+        channels.push(AnyChannel::new("object_id.id", FlatSamples::U32(entries.iter().map(|&i| aovs[i].object_id).collect())));
+    }
+    let layer = Layer::new(
+        (width, height),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(SmallVec::from_vec(channels)),
+    );
+    let mut image = Image::from_layer(layer);
+    let xy = |[x, y]: [f32; 2]| Vec2(x, y);
+    image.attributes.chromaticities = Some(exr::meta::attribute::Chromaticities {
+        red: xy(chromaticities.red),
+        green: xy(chromaticities.green),
+        blue: xy(chromaticities.blue),
+        white: xy(chromaticities.white),
+    });
+    image.write().to_file(path)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bytemuck::Zeroable;
+    use crate::color::WorkingSpace;
+
+    #[test]
+    fn entries_follow_pixel_index()
+    {
+        // the bottom left pixel is the first entry, and x strides over whole columns
+        assert_eq!(entry_index(0, 1, 2, 2, 4), 0);
+        assert_eq!(entry_index(1, 1, 2, 2, 4), 2 * 4);
+        assert_eq!(entry_index(0, 0, 2, 2, 4), 2);
+    }
+
+    #[test]
+    fn writes_every_pass()
+    {
+        let path = crate::checkpoint::temp_path("passes.exr");
+        let accumulation = vec![0.5f32; 4 * 4];
+        let aovs = vec![Aov { object_id: 3, ..Aov::zeroed() }; 4];
+        write_exr(&path, 2, 2, 2, &accumulation, Some(&aovs), WorkingSpace::AcesCg.chromaticities()).unwrap();
+        let image = read_all_flat_layers_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.attributes.chromaticities.map(|c| c.green.x()), Some(0.165));
+        let channels = &image.layer_data[0].channel_data.list;
+        assert_eq!(channels.len(), 3 + 6 * 3 + 2);
+        let names: Vec<String> = channels.iter().map(|channel| channel.name.to_string()).collect();
+        assert!(names.contains(&"diffuse_indirect.G".to_string()) && names.contains(&"normal.Z".to_string()), "{:?}", names);
+        let id = channels.iter().find(|channel| channel.name.eq("object_id.id")).unwrap();
+        assert!(matches!(&id.sample_data, FlatSamples::U32(ids) if ids == &vec![3; 4]));
+    }
+}
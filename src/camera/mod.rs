@@ -1,6 +1,9 @@
 use std::ops::{Add, Mul};
 use cgmath::{Point3, Vector3, Matrix4, Rad, InnerSpace};
+use serde::{Deserialize, Serialize};
 use winit::event::*;
+use crate::exposure::Exposure;
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
@@ -10,19 +13,169 @@ pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
     0.0, 0.0, 0.5, 1.0,
 );
 
+/// How camera rays leave the camera, numbered as in the shader.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Projection
+{
+    /// Pinhole camera with the sensor and focal length of the camera.
+    #[default]
+    Perspective,
+    /// Parallel rays over a view `orthographic_height` scene units high.
+    Orthographic,
+    /// Full-frame fisheye whose image diagonal spans 180°, with the angle off the axis
+    /// proportional to the distance from the centre.
+    FisheyeEquidistant,
+    /// Full-frame fisheye like `FisheyeEquidistant`, which keeps areas in proportion instead.
+    FisheyeEquisolid,
+    /// Latitude-longitude panorama of every direction, for images twice as wide as high.
+    Equirectangular,
+    /// Panorama all the way around, with the perspective camera's vertical field of view.
+    Cylindrical,
+}
+
+impl Projection
+{
+    pub fn next(self) -> Self
+    {
+        match self {
+            Projection::Perspective => Projection::Orthographic,
+            Projection::Orthographic => Projection::FisheyeEquidistant,
+            Projection::FisheyeEquidistant => Projection::FisheyeEquisolid,
+            Projection::FisheyeEquisolid => Projection::Equirectangular,
+            Projection::Equirectangular => Projection::Cylindrical,
+            Projection::Cylindrical => Projection::Perspective,
+        }
+    }
+}
+
+/// How the two eyes of a stereo image share the window, numbered as in the shader.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Stereo
+{
+    /// A single image from the camera
+    #[default]
+    Off,
+    /// Left eye in the left half, right eye in the right half
+    SideBySide,
+    /// Left eye in the top half, right eye in the bottom half
+    TopBottom,
+}
+
+impl Stereo
+{
+    /// Number of eye images across and down the window.
+    pub fn layout(self) -> [u32; 2]
+    {
+        match self {
+            Stereo::Off => [1, 1],
+            Stereo::SideBySide => [2, 1],
+            Stereo::TopBottom => [1, 2],
+        }
+    }
+}
+
+/// Common sensor sizes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SensorFormat
+{
+    /// 36 by 24mm, as on 35mm film.
+    FullFrame,
+    /// 23.6 by 15.6mm.
+    ApsC,
+    /// 17.3 by 13mm.
+    MicroFourThirds,
+}
+
+impl SensorFormat
+{
+    /// Width and height in meters.
+    pub fn size(self) -> [f32; 2]
+    {
+        match self {
+            SensorFormat::FullFrame => [0.036, 0.024],
+            SensorFormat::ApsC => [0.0236, 0.0156],
+            SensorFormat::MicroFourThirds => [0.0173, 0.013],
+        }
+    }
+}
+
+/// Where a camera is and which way it looks.
+#[derive(Copy, Clone, Debug)]
+pub struct CameraPose {
+    pub position: Point3<f32>,
+    pub direction: Vector3<f32>,
+    pub up: Vector3<f32>,
+}
+
 pub struct Camera {
     pub position: Point3<f32>,
     pub direction: Vector3<f32>,
     pub up: Vector3<f32>,
+    /// Sensor size and focal length in meters.
     pub width: f32,
     pub height: f32,
     pub focal_length: f32,
     pub znear: f32,
     pub zfar: f32,
+    pub projection: Projection,
+    /// Height of the view in scene units with the orthographic projection.
+    pub orthographic_height: f32,
+    pub stereo: Stereo,
+    /// Distance between the eyes in scene units.
+    pub interpupillary_distance: f32,
+    /// Photographic exposure, without which the image shows radiance as it is.
+    pub exposure: Option<Exposure>,
+    /// Meter the exposure from the image instead.
+    pub auto_exposure: bool,
+    /// Darken the image towards its edges by the natural cos⁴ falloff of a lens.
+    pub vignetting: bool,
+    /// Fraction of the frame interval that the shutter stays open, like a shutter angle over 360°.
+    pub shutter: f32,
+    /// Pose when the shutter closes, if the camera moves while it is open.
+    pub shutter_close: Option<CameraPose>,
     pub frame_idx: u32,
 }
 
+impl Default for Camera
+{
+    /// The built-in Cornell box's camera, with a 25mm square sensor behind a 35mm lens.
+    fn default() -> Self
+    {
+        Self {
+            position: (275.0, 275.0, -800.0).into(),
+            direction: (0.0, 0.0, 1.0).into(),
+            up: Vector3::unit_y(),
+            width: 0.025,
+            height: 0.025,
+            focal_length: 0.035,
+            znear: 0.1,
+            zfar: 100.0,
+            projection: Projection::Perspective,
+            orthographic_height: 600.0,
+            stereo: Stereo::Off,
+            interpupillary_distance: 0.0,
+            exposure: None,
+            auto_exposure: false,
+            vignetting: false,
+            shutter: 0.0,
+            shutter_close: None,
+            frame_idx: 0,
+        }
+    }
+}
+
+#[allow(clippy::needless_return)]
 impl Camera {
+    pub fn pose(&self) -> CameraPose
+    {
+        CameraPose { position: self.position, direction: self.direction, up: self.up }
+    }
+    pub fn set_pose(&mut self, pose: CameraPose)
+    {
+        self.position = pose.position;
+        self.direction = pose.direction;
+        self.up = pose.up;
+    }
     pub fn view_matrix(&self) -> Matrix4<f32>
     {
         return Matrix4::look_at_rh(self.position, self.position.add(self.direction), self.up);
@@ -35,18 +188,28 @@ impl Camera {
     }
     pub fn view_projection_matrix(&self) -> Matrix4<f32>
     {
-        return OPENGL_TO_WGPU_MATRIX * self.view_matrix() * self.projection_matrix();
+        return OPENGL_TO_WGPU_MATRIX * self.projection_matrix() * self.view_matrix();
     }
     pub fn raygen_matrix(&self) -> Matrix4<f32>
     {
-        let up = self.up.mul(0.5 * self.height);
-        let forward = self.direction.mul(self.focal_length);
-        let right = self.direction.cross(self.up).mul(0.5 * self.width);
+        self.pose_raygen_matrix(self.pose())
+    }
+    /// Ray generation matrix when the shutter closes, which the shader blends towards
+    /// from `raygen_matrix` over the shutter interval.
+    pub fn raygen_close_matrix(&self) -> Matrix4<f32>
+    {
+        self.pose_raygen_matrix(self.shutter_close.unwrap_or(self.pose()))
+    }
+    fn pose_raygen_matrix(&self, pose: CameraPose) -> Matrix4<f32>
+    {
+        let up = pose.up.mul(0.5 * self.height);
+        let forward = pose.direction.mul(self.focal_length);
+        let right = pose.direction.cross(pose.up).mul(0.5 * self.width);
         return Matrix4::new(
             right.x, right.y, right.z, 0.0,
             up.x, up.y, up.z, 0.0,
             forward.x, forward.y, forward.z, 0.0,
-            self.position.x, self.position.y, self.position.z, 0.0,
+            pose.position.x, pose.position.y, pose.position.z, 0.0,
         );
     }
 }
@@ -55,9 +218,22 @@ impl Camera {
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
     raygen: [[f32; 4]; 4],
+    raygen_close: [[f32; 4]; 4],
     view_proj: [[f32; 4]; 4],
+    /// The previous frame's `view_proj`, to find where surfaces were on screen.
+    prev_view_proj: [[f32; 4]; 4],
     frame_idx: u32,
-    _padding: [u32; 3],
+    projection: u32,
+    /// Size of the window in pixels.
+    resolution: [u32; 2],
+    orthographic_height: f32,
+    stereo: u32,
+    interpupillary_distance: f32,
+    /// Factor from radiance to the displayed value, unless auto exposure meters it.
+    exposure: f32,
+    auto_exposure: u32,
+    vignetting: u32,
+    _padding: [u32; 2],
 }
 
 impl CameraUniform
@@ -67,16 +243,44 @@ impl CameraUniform
         use cgmath::SquareMatrix;
         Self {
             raygen: Matrix4::identity().into(),
+            raygen_close: Matrix4::identity().into(),
             view_proj: Matrix4::identity().into(),
+            prev_view_proj: Matrix4::identity().into(),
             frame_idx: 0,
-            _padding: [0; 3],
+            projection: 0,
+            resolution: [1; 2],
+            orthographic_height: 1.0,
+            stereo: 0,
+            interpupillary_distance: 0.0,
+            exposure: 1.0,
+            auto_exposure: 0,
+            vignetting: 0,
+            _padding: [0; 2],
         }
     }
-    pub fn update(&mut self, camera: &Camera)
+    pub fn update(&mut self, camera: &Camera, resolution: [u32; 2])
     {
         self.raygen = camera.raygen_matrix().into();
+        self.raygen_close = camera.raygen_close_matrix().into();
+        self.prev_view_proj = self.view_proj;
         self.view_proj = camera.view_projection_matrix().into();
         self.frame_idx = camera.frame_idx;
+        self.projection = camera.projection as u32;
+        self.resolution = resolution;
+        self.orthographic_height = camera.orthographic_height;
+        self.stereo = camera.stereo as u32;
+        self.interpupillary_distance = camera.interpupillary_distance;
+        self.exposure = camera.exposure.map_or(1.0, |exposure| exposure.scale());
+        self.auto_exposure = camera.auto_exposure as u32;
+        self.vignetting = camera.vignetting as u32;
+    }
+}
+
+impl GpuLayout for CameraUniform
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(CameraUniform { raygen, raygen_close, view_proj, prev_view_proj, frame_idx, projection, resolution, orthographic_height, stereo, interpupillary_distance, exposure, auto_exposure, vignetting })
     }
 }
 
@@ -150,10 +354,44 @@ impl CameraController
             camera.position += right * self.speed;
         }
         if self.is_forward_pressed | self.is_backward_pressed | self.is_left_pressed | self.is_right_pressed {
+            // steering takes over from an animated camera until the next frame of the animation
+            camera.shutter_close = None;
             camera.frame_idx = 0;
         }
         else {
             camera.frame_idx += 1;
         }
     }
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use cgmath::{Vector4, Transform};
+
+    #[test]
+    fn projections_cycle_in_shader_order()
+    {
+        let mut projection = Projection::Perspective;
+        for i in 0..6 {
+            assert_eq!(projection as u32, i);
+            projection = projection.next();
+        }
+        assert_eq!(projection, Projection::Perspective);
+    }
+
+    #[test]
+    fn view_projection_matches_raygen()
+    {
+        let camera = Camera { height: 0.02, ..Default::default() };
+        let raygen = camera.raygen_matrix();
+        // without a pose for when the shutter closes, the camera stays still while it is open
+        assert_eq!(camera.raygen_close_matrix(), raygen);
+        let view_proj = camera.view_projection_matrix();
+        for ndc in [[0.0, 0.0], [0.5, -0.25], [-1.0, 1.0]] {
+            let direction = (raygen * Vector4::new(ndc[0], ndc[1], 1.0, 0.0)).truncate();
+            let projected = view_proj.transform_point(camera.position + direction * 10.0);
+            assert!((projected.x - ndc[0]).abs() < 1e-3 && (projected.y - ndc[1]).abs() < 1e-3, "{:?} for {:?}", projected, ndc);
+        }
+    }
 }
\ No newline at end of file
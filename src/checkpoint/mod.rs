@@ -0,0 +1,227 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use bytemuck::Zeroable;
+use crate::camera::Camera;
+use crate::scene::Scene;
+use crate::settings::Settings;
+
+const MAGIC: [u8; 8] = *b"SPTCKPT\0";
+const VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct Header
+{
+    magic: [u8; 8],
+    version: u32,
+    frame_count: u32,
+    width: u32,
+    height: u32,
+    scene_hash: u64,
+    camera_hash: u64,
+    camera: [f32; 12],
+}
+
+/// Snapshot of a progressive render.
+///
+/// The shader seeds its random numbers from the pixel index and the frame index,
+/// so `frame_count` is all the RNG state needed to continue the sequence.
+pub struct Checkpoint
+{
+    /// Number of frames averaged into `accumulation`.
+    pub frame_count: u32,
+    pub width: u32,
+    pub height: u32,
+    pub scene_hash: u64,
+    pub camera_hash: u64,
+    /// Position, direction, up, sensor width, sensor height and focal length.
+    pub camera: [f32; 12],
+    /// Linear radiance, four floats per pixel as laid out in the accumulate buffer.
+    pub accumulation: Vec<f32>,
+}
+
+impl Checkpoint
+{
+    /// Writes to a temporary file first, so that a crash mid-write keeps the previous checkpoint.
+    pub fn save(&self, path: &Path) -> io::Result<()>
+    {
+        let header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            frame_count: self.frame_count,
+            width: self.width,
+            height: self.height,
+            scene_hash: self.scene_hash,
+            camera_hash: self.camera_hash,
+            camera: self.camera,
+        };
+        let temporary = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temporary)?);
+            writer.write_all(bytemuck::bytes_of(&header))?;
+            writer.write_all(bytemuck::cast_slice(&self.accumulation))?;
+            writer.flush()?;
+        }
+        std::fs::rename(temporary, path)
+    }
+    pub fn load(path: &Path) -> io::Result<Self>
+    {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = Header::zeroed();
+        reader.read_exact(bytemuck::bytes_of_mut(&mut header))?;
+        if header.magic != MAGIC || header.version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a checkpoint file"));
+        }
+        // the size in the header is only trusted once the file is known to hold that much
+        let entries = (header.width as u64).checked_mul(header.height as u64).and_then(|pixels| pixels.checked_mul(4));
+        let expected = entries.and_then(|entries| entries.checked_mul(std::mem::size_of::<f32>() as u64));
+        if expected != Some(file_size - std::mem::size_of::<Header>() as u64) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "checkpoint size does not match its header"));
+        }
+        let mut accumulation = vec![0.0f32; entries.unwrap() as usize];
+        reader.read_exact(bytemuck::cast_slice_mut(&mut accumulation))?;
+        Ok(Self {
+            frame_count: header.frame_count,
+            width: header.width,
+            height: header.height,
+            scene_hash: header.scene_hash,
+            camera_hash: header.camera_hash,
+            camera: header.camera,
+            accumulation,
+        })
+    }
+}
+
+pub fn camera_parameters(camera: &Camera) -> [f32; 12]
+{
+    [
+        camera.position.x, camera.position.y, camera.position.z,
+        camera.direction.x, camera.direction.y, camera.direction.z,
+        camera.up.x, camera.up.y, camera.up.z,
+        camera.width, camera.height, camera.focal_length,
+    ]
+}
+
+pub fn camera_hash(camera: &Camera) -> u64
+{
+    let parameters = hash_bytes(bytemuck::cast_slice(&camera_parameters(camera)));
+    let projection = hash_bytes(bytemuck::bytes_of(&[
+        camera.projection as u32,
+        camera.orthographic_height.to_bits(),
+        camera.stereo as u32,
+        camera.interpupillary_distance.to_bits(),
+        camera.shutter.to_bits(),
+    ]));
+    hash_bytes(bytemuck::bytes_of(&[parameters, projection]))
+}
+
+/// Hash of everything but the camera that the accumulated image depends on.
+pub fn scene_hash(scene: &Scene, settings: &Settings) -> u64
+{
+    let spheres = hash_bytes(bytemuck::cast_slice(&scene.spheres));
+    let planes = hash_bytes(bytemuck::cast_slice(&scene.planes));
+    let quads = hash_bytes(bytemuck::cast_slice(&scene.quads));
+    let boxes = hash_bytes(bytemuck::cast_slice(&scene.boxes));
+    let lights = hash_bytes(bytemuck::cast_slice(&scene.gpu_lights()));
+    let ies_profiles = hash_bytes(bytemuck::cast_slice(&scene.ies_profiles));
+    let sky = hash_bytes(bytemuck::bytes_of(&scene.sky_uniform()));
+    let materials = hash_bytes(bytemuck::cast_slice(&scene.gpu_materials()));
+    let media = hash_bytes(bytemuck::cast_slice(&scene.gpu_media()));
+    let grids = hash_bytes(bytemuck::cast_slice(&scene.grid_atlas().1));
+    let textures = hash_bytes(bytemuck::cast_slice(&scene.gpu_textures()));
+    let images = hash_bytes(&scene.image_array().1);
+    // the sky and image textures are converted in the shader
+    let working_space = hash_bytes(&[scene.color.working_space as u8]);
+    let settings = hash_bytes(bytemuck::bytes_of(&settings.uniform()));
+    hash_bytes(bytemuck::bytes_of(&[spheres, planes, quads, boxes, lights, ies_profiles, sky, materials, media, grids, textures, images, working_space, settings]))
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is stable across builds.
+fn hash_bytes(bytes: &[u8]) -> u64
+{
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Path in the temporary directory named after the test process, so that concurrent runs
+/// of the tests do not share files.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> std::path::PathBuf
+{
+    std::env::temp_dir().join(format!("smallpt_{}_{}", std::process::id(), name))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::settings::{DebugView, Integrator};
+
+    #[test]
+    fn round_trip()
+    {
+        let path = temp_path("round_trip.ckpt");
+        let checkpoint = Checkpoint {
+            frame_count: 42,
+            width: 2,
+            height: 2,
+            scene_hash: scene_hash(&Scene::default(), &Settings::default()),
+            camera_hash: 7,
+            camera: [1.0; 12],
+            accumulation: (0..16).map(|i| i as f32).collect(),
+        };
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.frame_count, 42);
+        assert_eq!(loaded.scene_hash, checkpoint.scene_hash);
+        assert_eq!(loaded.camera_hash, 7);
+        assert_eq!(loaded.camera, [1.0; 12]);
+        assert_eq!(loaded.accumulation, checkpoint.accumulation);
+    }
+
+    #[test]
+    fn rejects_foreign_files()
+    {
+        let path = temp_path("foreign.ckpt");
+        std::fs::write(&path, [0u8; 128]).unwrap();
+        let result = Checkpoint::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn rejects_sizes_beyond_the_file()
+    {
+        let path = temp_path("truncated.ckpt");
+        let header = Header { magic: MAGIC, version: VERSION, width: u32::MAX, height: u32::MAX, ..Header::zeroed() };
+        std::fs::write(&path, bytemuck::bytes_of(&header)).unwrap();
+        let result = Checkpoint::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn scene_hash_tracks_changes()
+    {
+        let mut scene = Scene::default();
+        let mut settings = Settings::default();
+        let original = scene_hash(&scene, &settings);
+        settings.spectral = true;
+        assert_ne!(scene_hash(&scene, &settings), original);
+        settings.spectral = false;
+        settings.integrator = Integrator::Bdpt;
+        assert_ne!(scene_hash(&scene, &settings), original);
+        settings.integrator = Integrator::Path;
+        settings.restir = true;
+        assert_ne!(scene_hash(&scene, &settings), original);
+        settings.restir = false;
+        settings.debug_view = DebugView::Normal;
+        assert_ne!(scene_hash(&scene, &settings), original);
+        settings.debug_view = DebugView::Off;
+        scene.spheres.pop();
+        assert_ne!(scene_hash(&scene, &settings), original);
+    }
+}
@@ -0,0 +1,335 @@
+use cgmath::{Matrix3, SquareMatrix, Vector3};
+use serde::{Deserialize, Serialize};
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+/// CIE 1931 xy chromaticities of the D65 white point, that of sRGB, Rec.2020 and Display P3.
+const D65: [f32; 2] = [0.3127, 0.3290];
+/// White point of the ACES colour spaces, close to D60.
+const ACES_WHITE: [f32; 2] = [0.32168, 0.33767];
+
+/// Primaries and white point of an RGB colour space as xy chromaticities.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Chromaticities
+{
+    pub red: [f32; 2],
+    pub green: [f32; 2],
+    pub blue: [f32; 2],
+    pub white: [f32; 2],
+}
+
+/// Linear RGB space that the renderer computes in and that written images are in.
+///
+/// Colours in scene files and textures are sRGB whichever space is chosen, and are
+/// converted into it when the scene is uploaded.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WorkingSpace
+{
+    /// Rec.709 primaries, the same as sRGB.
+    #[default]
+    Srgb,
+    /// The AP1 primaries of ACES for rendering and compositing.
+    AcesCg,
+    Rec2020,
+}
+
+/// Colour space of the window, both with the sRGB transfer function.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplaySpace
+{
+    #[default]
+    Srgb,
+    DisplayP3,
+}
+
+impl WorkingSpace
+{
+    pub fn chromaticities(self) -> Chromaticities
+    {
+        match self {
+            WorkingSpace::Srgb => SRGB,
+            WorkingSpace::AcesCg => Chromaticities {
+                red: [0.713, 0.293],
+                green: [0.165, 0.830],
+                blue: [0.128, 0.044],
+                white: ACES_WHITE,
+            },
+            WorkingSpace::Rec2020 => Chromaticities {
+                red: [0.708, 0.292],
+                green: [0.170, 0.797],
+                blue: [0.131, 0.046],
+                white: D65,
+            },
+        }
+    }
+}
+
+impl DisplaySpace
+{
+    pub fn chromaticities(self) -> Chromaticities
+    {
+        match self {
+            DisplaySpace::Srgb => SRGB,
+            DisplaySpace::DisplayP3 => Chromaticities {
+                red: [0.680, 0.320],
+                green: [0.265, 0.690],
+                blue: [0.150, 0.060],
+                white: D65,
+            },
+        }
+    }
+}
+
+/// HDR presentation to ask the surface for.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Hdr
+{
+    #[default]
+    Off,
+    /// Linear Rec.709 in a half float surface, with 1 as 80 nits and beyond the gamut below 0.
+    Scrgb,
+}
+
+/// How the shader turns displayed colour into values of the surface format, numbered as
+/// ENCODING_* in the shader.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DisplayEncoding
+{
+    Srgb,
+    /// The surface format applies the sRGB transfer function itself.
+    Linear,
+    Scrgb,
+}
+
+/// Surface format for `hdr` and the encoding it takes, falling back to 8-bit SDR where the
+/// surface does not offer the HDR format.
+pub fn surface_format(formats: &[wgpu::TextureFormat], hdr: Hdr) -> (wgpu::TextureFormat, DisplayEncoding)
+{
+    let wanted = match hdr {
+        Hdr::Off => None,
+        Hdr::Scrgb => Some((wgpu::TextureFormat::Rgba16Float, DisplayEncoding::Scrgb)),
+    };
+    if let Some((format, encoding)) = wanted {
+        if formats.contains(&format) {
+            return (format, encoding);
+        }
+        log::warn!("the surface does not offer {:?} for {:?} output, falling back to SDR", format, hdr);
+    }
+    let format = formats.iter().copied().find(|f| f.is_srgb()).unwrap_or(formats[0]);
+    (format, if format.is_srgb() { DisplayEncoding::Linear } else { DisplayEncoding::Srgb })
+}
+
+const SRGB: Chromaticities = Chromaticities {
+    red: [0.64, 0.33],
+    green: [0.30, 0.60],
+    blue: [0.15, 0.06],
+    white: D65,
+};
+
+/// How the scene's colours are rendered and shown.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ColorDesc
+{
+    #[serde(default)]
+    pub working_space: WorkingSpace,
+    #[serde(default)]
+    pub display_space: DisplaySpace,
+    /// Colour temperature in kelvin of the light that is shown as white, D65 if left out.
+    #[serde(default)]
+    pub white_balance: Option<f32>,
+}
+
+/// Conversions between the working space, sRGB, CIE XYZ and the display, each a `mat3x3` with
+/// columns padded to 16 bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ColorUniform
+{
+    srgb_to_working: [[f32; 4]; 3],
+    working_to_srgb: [[f32; 4]; 3],
+    xyz_to_working: [[f32; 4]; 3],
+    working_to_display: [[f32; 4]; 3],
+    encoding: u32,
+    /// Luminance in nits that HDR output shows diffuse white at.
+    paper_white: f32,
+    _padding: [u32; 2],
+}
+
+impl GpuLayout for ColorUniform
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(ColorUniform { srgb_to_working, working_to_srgb, xyz_to_working, working_to_display, encoding, paper_white })
+    }
+}
+
+impl ColorDesc
+{
+    pub fn validate(&self) -> Result<(), String>
+    {
+        if self.white_balance.is_some_and(|kelvin| kelvin.is_nan() || !(1667.0..=25000.0).contains(&kelvin)) {
+            return Err("white_balance must be between 1667 and 25000 kelvin".into());
+        }
+        Ok(())
+    }
+    /// Converts an sRGB colour from the scene file into the working space.
+    pub fn to_working(&self, rgb: [f32; 3]) -> [f32; 3]
+    {
+        (self.srgb_to_working() * Vector3::from(rgb)).into()
+    }
+    fn srgb_to_working(&self) -> Matrix3<f32>
+    {
+        conversion(SRGB, self.working_space.chromaticities())
+    }
+    /// scRGB fixes the display primaries to Rec.709.
+    pub fn uniform(&self, encoding: DisplayEncoding, paper_white: f32) -> ColorUniform
+    {
+        let working = self.working_space.chromaticities();
+        let display = match encoding {
+            DisplayEncoding::Scrgb => SRGB,
+            DisplayEncoding::Srgb | DisplayEncoding::Linear => self.display_space.chromaticities(),
+        };
+        let mut working_to_display = conversion(working, display);
+        if let Some(kelvin) = self.white_balance {
+            // neutralises light of that temperature once the image is relative to the display white
+            let xyz_to_display = rgb_to_xyz(display).invert().unwrap();
+            working_to_display = xyz_to_display * adaptation(white_point(kelvin), display.white) * rgb_to_xyz(display) * working_to_display;
+        }
+        ColorUniform {
+            srgb_to_working: columns(self.srgb_to_working()),
+            working_to_srgb: columns(conversion(working, SRGB)),
+            // the spectral path integrates under D65, which becomes the working white
+            xyz_to_working: columns(rgb_to_xyz(working).invert().unwrap() * adaptation(D65, working.white)),
+            working_to_display: columns(working_to_display),
+            encoding: encoding as u32,
+            paper_white,
+            _padding: [0; 2],
+        }
+    }
+}
+
+fn columns(m: Matrix3<f32>) -> [[f32; 4]; 3]
+{
+    [m.x, m.y, m.z].map(|column| [column.x, column.y, column.z, 0.0])
+}
+
+fn xy_to_xyz([x, y]: [f32; 2]) -> Vector3<f32>
+{
+    Vector3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// From linear RGB to CIE XYZ, with the white point at Y = 1.
+fn rgb_to_xyz(space: Chromaticities) -> Matrix3<f32>
+{
+    let primaries = Matrix3::from_cols(xy_to_xyz(space.red), xy_to_xyz(space.green), xy_to_xyz(space.blue));
+    let scale = primaries.invert().unwrap() * xy_to_xyz(space.white);
+    Matrix3::from_cols(primaries.x * scale.x, primaries.y * scale.y, primaries.z * scale.z)
+}
+
+/// Bradford chromatic adaptation of XYZ colours from one white point to another.
+fn adaptation(from: [f32; 2], to: [f32; 2]) -> Matrix3<f32>
+{
+    let bradford = Matrix3::new(
+        0.8951, -0.7502, 0.0389,
+        0.2664, 1.7135, -0.0685,
+        -0.1614, 0.0367, 1.0296,
+    );
+    let (from, to) = (bradford * xy_to_xyz(from), bradford * xy_to_xyz(to));
+    let scale = Matrix3::from_diagonal(Vector3::new(to.x / from.x, to.y / from.y, to.z / from.z));
+    bradford.invert().unwrap() * scale * bradford
+}
+
+/// From one linear RGB space to another, keeping whites white.
+fn conversion(from: Chromaticities, to: Chromaticities) -> Matrix3<f32>
+{
+    rgb_to_xyz(to).invert().unwrap() * adaptation(from.white, to.white) * rgb_to_xyz(from)
+}
+
+/// Chromaticity of the CIE daylight illuminant at `kelvin`, or of a black body below 4000 K
+/// where the daylight series ends (Kim et al., "Design of Advanced Color Temperature Control
+/// System for HDTV Applications", 2002).
+fn white_point(kelvin: f32) -> [f32; 2]
+{
+    let t = kelvin as f64;
+    let (t2, t3) = (t * t, t * t * t);
+    let (x, y) = if t < 4000.0 {
+        let x = -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910;
+        let y = if t < 2222.0 {
+            -1.1063814 * x * x * x - 1.34811020 * x * x + 2.18555832 * x - 0.20219683
+        } else {
+            -0.9549476 * x * x * x - 1.37418593 * x * x + 2.09137015 * x - 0.16748867
+        };
+        (x, y)
+    } else {
+        let x = if t <= 7000.0 {
+            -4.6070e9 / t3 + 2.9678e6 / t2 + 0.09911e3 / t + 0.244063
+        } else {
+            -2.0064e9 / t3 + 1.9018e6 / t2 + 0.24748e3 / t + 0.237040
+        };
+        (x, -3.0 * x * x + 2.87 * x - 0.275)
+    };
+    [x as f32, y as f32]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::layout::shader_constant;
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool
+    {
+        a.iter().zip(b).all(|(a, b)| (a - b).abs() < 2e-3)
+    }
+
+    #[test]
+    fn white_stays_white()
+    {
+        for working_space in [WorkingSpace::Srgb, WorkingSpace::AcesCg, WorkingSpace::Rec2020] {
+            let color = ColorDesc { working_space, ..ColorDesc::default() };
+            assert!(close(color.to_working([1.0; 3]), [1.0; 3]));
+        }
+        // the published sRGB to XYZ matrix
+        let srgb = rgb_to_xyz(SRGB);
+        assert!(close(srgb.x.into(), [0.4124, 0.2126, 0.0193]));
+        assert!(close((srgb * Vector3::new(1.0, 1.0, 1.0)).into(), xy_to_xyz(D65).into()));
+    }
+
+    #[test]
+    fn wide_gamuts_hold_srgb_inside()
+    {
+        let color = ColorDesc { working_space: WorkingSpace::Rec2020, ..ColorDesc::default() };
+        // pure sRGB red is a less saturated red in Rec.2020
+        let red = color.to_working([1.0, 0.0, 0.0]);
+        assert!(close(red, [0.6274, 0.0691, 0.0164]));
+    }
+
+    #[test]
+    fn hdr_falls_back_to_sdr()
+    {
+        use wgpu::TextureFormat;
+        let sdr = [TextureFormat::Bgra8UnormSrgb, TextureFormat::Bgra8Unorm];
+        assert_eq!(surface_format(&sdr, Hdr::Scrgb), (TextureFormat::Bgra8UnormSrgb, DisplayEncoding::Linear));
+        assert_eq!(surface_format(&sdr[1..], Hdr::Off), (TextureFormat::Bgra8Unorm, DisplayEncoding::Srgb));
+        let hdr = [TextureFormat::Bgra8UnormSrgb, TextureFormat::Rgba16Float];
+        assert_eq!(surface_format(&hdr, Hdr::Scrgb), (TextureFormat::Rgba16Float, DisplayEncoding::Scrgb));
+        assert_eq!(surface_format(&hdr, Hdr::Off).1, DisplayEncoding::Linear);
+    }
+
+    #[test]
+    fn shader_constants_match()
+    {
+        assert_eq!(shader_constant("ENCODING_SRGB"), DisplayEncoding::Srgb as u32 as f64);
+        assert_eq!(shader_constant("ENCODING_LINEAR"), DisplayEncoding::Linear as u32 as f64);
+        assert_eq!(shader_constant("ENCODING_SCRGB"), DisplayEncoding::Scrgb as u32 as f64);
+    }
+
+    #[test]
+    fn white_balance_follows_daylight()
+    {
+        let d65 = white_point(6504.0);
+        assert!((d65[0] - D65[0]).abs() < 1e-3 && (d65[1] - D65[1]).abs() < 1e-3);
+        // warmer light is yellower
+        assert!(white_point(3200.0)[0] > white_point(5500.0)[0]);
+        assert!(ColorDesc { white_balance: Some(1000.0), ..ColorDesc::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use crate::scene::LUMENS_PER_WATT;
+
+/// Side of the square workgroups of the histogram pass.
+pub const WORKGROUP_SIZE: u32 = 8;
+/// Bins of the log luminance histogram, which the averaging pass covers with one workgroup.
+pub const HISTOGRAM_BINS: u32 = 128;
+
+/// Photographic exposure of the image on a sensor of speed `iso`.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Exposure
+{
+    pub iso: f32,
+    /// Shutter speed in seconds.
+    pub shutter_time: f32,
+    /// Focal length over aperture diameter.
+    pub f_number: f32,
+}
+
+impl Exposure
+{
+    /// Exposure value at ISO 100 of these settings.
+    pub fn ev100(&self) -> f32
+    {
+        (self.f_number * self.f_number / self.shutter_time * 100.0 / self.iso).log2()
+    }
+    /// Factor from radiance to the displayed value, which saturates at the luminance that
+    /// the saturation-based speed of ISO 12232 ties to the exposure value.
+    pub fn scale(&self) -> f32
+    {
+        LUMENS_PER_WATT / (1.2 * self.ev100().exp2())
+    }
+    pub fn validate(&self) -> Result<(), String>
+    {
+        if [self.iso, self.shutter_time, self.f_number].iter().any(|x| x.is_nan() || *x <= 0.0) {
+            return Err("exposure iso, shutter_time and f_number must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// Histogram bins followed by the metered exposure scale, stored as bits.
+pub fn buffer_size() -> wgpu::BufferAddress
+{
+    ((HISTOGRAM_BINS as usize + 1) * std::mem::size_of::<u32>()) as wgpu::BufferAddress
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::layout::{shader_constant, shader_workgroup_size};
+
+    #[test]
+    fn exposure_follows_settings()
+    {
+        let sunny_sixteen = Exposure { iso: 100.0, shutter_time: 1.0 / 100.0, f_number: 16.0 };
+        assert!((sunny_sixteen.ev100() - 14.64).abs() < 0.01);
+        // a stop more light through the lens, or a stop faster film, doubles the exposure
+        let opened = Exposure { f_number: 16.0 / 2.0f32.sqrt(), ..sunny_sixteen };
+        let faster = Exposure { iso: 200.0, ..sunny_sixteen };
+        assert!((opened.scale() / sunny_sixteen.scale() - 2.0).abs() < 1e-4);
+        assert!((faster.scale() / sunny_sixteen.scale() - 2.0).abs() < 1e-4);
+        assert!(Exposure { shutter_time: 0.0, ..sunny_sixteen }.validate().is_err());
+    }
+
+    #[test]
+    fn shader_constants_match()
+    {
+        assert_eq!(shader_constant("EXPOSURE_BINS"), HISTOGRAM_BINS as f64);
+        assert_eq!(shader_constant("INTEGRATOR_COUNTERS") * 4.0, buffer_size() as f64);
+        assert_eq!(shader_workgroup_size("exposure_average"), [HISTOGRAM_BINS, 1, 1]);
+        assert_eq!(shader_workgroup_size("exposure_histogram"), [WORKGROUP_SIZE, WORKGROUP_SIZE, 1]);
+    }
+}
@@ -0,0 +1,205 @@
+use naga::TypeInner;
+
+/// Size and member offsets of a host struct that is uploaded to the GPU.
+///
+/// Padding fields are left out; they only exist on the Rust side.
+#[derive(Debug)]
+pub struct StructLayout
+{
+    pub name: &'static str,
+    pub size: usize,
+    pub fields: Vec<(&'static str, usize)>,
+}
+
+/// Host structs that mirror a struct of the same name in `shader.wgsl`.
+pub trait GpuLayout
+{
+    fn layout() -> StructLayout;
+}
+
+/// Builds the [`StructLayout`] of a `#[repr(C)]` struct from the listed fields.
+macro_rules! struct_layout {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        $crate::layout::StructLayout {
+            name: stringify!($ty),
+            size: std::mem::size_of::<$ty>(),
+            fields: vec![$((stringify!($field), std::mem::offset_of!($ty, $field))),*],
+        }
+    };
+}
+pub(crate) use struct_layout;
+
+pub fn host_layouts() -> Vec<StructLayout>
+{
+    use crate::aov::Aov;
+    use crate::camera::CameraUniform;
+    use crate::color::ColorUniform;
+    use crate::mlt::MltChain;
+    use crate::post::PostUniform;
+    use crate::restir::Reservoir;
+    use crate::scene::{Aabb, Geometry, Light, Material, Medium, Plane, Quad, Shading, SkyUniform, Sphere, Texture};
+    use crate::settings::SettingsUniform;
+    use crate::sppm::SppmPixel;
+    vec![
+        CameraUniform::layout(),
+        SettingsUniform::layout(),
+        Sphere::layout(),
+        Plane::layout(),
+        Quad::layout(),
+        Aabb::layout(),
+        Light::layout(),
+        SkyUniform::layout(),
+        Material::layout(),
+        Medium::layout(),
+        Texture::layout(),
+        Geometry::layout(),
+        Shading::layout(),
+        SppmPixel::layout(),
+        MltChain::layout(),
+        Reservoir::layout(),
+        Aov::layout(),
+        PostUniform::layout(),
+        ColorUniform::layout(),
+    ]
+}
+
+/// Reflects `source` and checks every host layout against the shader struct of the same name.
+pub fn validate(source: &str, layouts: &[StructLayout]) -> Result<(), String>
+{
+    let module = naga::front::wgsl::parse_str(source)
+        .map_err(|e| e.emit_to_string(source))?;
+    let mut errors = Vec::new();
+    for layout in layouts {
+        let Some((members, span)) = module.types.iter().find_map(|(_, ty)| match &ty.inner {
+            TypeInner::Struct { members, span } if ty.name.as_deref() == Some(layout.name) => Some((members, *span)),
+            _ => None,
+        }) else {
+            errors.push(format!("{}: no struct of that name in the shader", layout.name));
+            continue;
+        };
+        if span as usize != layout.size {
+            errors.push(format!("{}: size is {} bytes on the host but {} in the shader", layout.name, layout.size, span));
+        }
+        for member in members {
+            let name = member.name.as_deref().unwrap_or_default();
+            match layout.fields.iter().find(|(field, _)| *field == name) {
+                Some((_, offset)) if *offset != member.offset as usize => errors.push(format!(
+                    "{}.{}: offset is {} on the host but {} in the shader",
+                    layout.name, name, offset, member.offset,
+                )),
+                Some(_) => {}
+                None => errors.push(format!("{}.{}: missing on the host", layout.name, name)),
+            }
+        }
+        for (field, _) in &layout.fields {
+            if !members.iter().any(|member| member.name.as_deref() == Some(field)) {
+                errors.push(format!("{}.{}: missing in the shader", layout.name, field));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+/// `shader.wgsl` as naga reflects it, parsed once for the tests that compare against it.
+#[cfg(test)]
+fn shader_module() -> &'static naga::Module
+{
+    static MODULE: std::sync::OnceLock<naga::Module> = std::sync::OnceLock::new();
+    MODULE.get_or_init(|| naga::front::wgsl::parse_str(include_str!("../shader.wgsl")).unwrap())
+}
+
+/// Value of the scalar `const` of that name in `shader.wgsl`, whatever its type.
+#[cfg(test)]
+pub fn shader_constant(name: &str) -> f64
+{
+    let module = shader_module();
+    let constant = module.constants.iter()
+        .map(|(_, constant)| constant)
+        .find(|constant| constant.name.as_deref() == Some(name))
+        .unwrap_or_else(|| panic!("no constant {} in shader.wgsl", name));
+    match constant.inner {
+        naga::ConstantInner::Scalar { value: naga::ScalarValue::Sint(value), .. } => value as f64,
+        naga::ConstantInner::Scalar { value: naga::ScalarValue::Uint(value), .. } => value as f64,
+        naga::ConstantInner::Scalar { value: naga::ScalarValue::Float(value), .. } => value,
+        _ => panic!("{} is not a number in shader.wgsl", name),
+    }
+}
+
+/// Workgroup size of the compute entry point of that name in `shader.wgsl`.
+#[cfg(test)]
+pub fn shader_workgroup_size(entry_point: &str) -> [u32; 3]
+{
+    shader_module().entry_points.iter()
+        .find(|entry| entry.name == entry_point)
+        .unwrap_or_else(|| panic!("no entry point {} in shader.wgsl", entry_point))
+        .workgroup_size
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SHADER: &str = include_str!("../shader.wgsl");
+
+    #[test]
+    fn host_structs_match_shader()
+    {
+        if let Err(e) = validate(SHADER, &host_layouts()) {
+            panic!("host structs do not match shader.wgsl:\n{}", e);
+        }
+    }
+
+    #[test]
+    fn shader_validates()
+    {
+        let module = naga::front::wgsl::parse_str(SHADER).unwrap_or_else(|e| panic!("{}", e.emit_to_string(SHADER)));
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::empty())
+            .validate(&module)
+            .unwrap_or_else(|e| panic!("shader.wgsl failed validation: {:?}", e));
+    }
+
+    #[test]
+    fn storage_buffer_counts_match_shader()
+    {
+        use crate::state::{ACCUMULATE_STORAGE_BUFFERS, GEOMETRY_STORAGE_BUFFERS, MATERIAL_STORAGE_BUFFERS};
+        let module = naga::front::wgsl::parse_str(SHADER).unwrap();
+        let storage_buffers = |group: u32| module.global_variables.iter()
+            .filter(|(_, global)| matches!(global.space, naga::AddressSpace::Storage { .. }))
+            .filter(|(_, global)| global.binding.as_ref().is_some_and(|binding| binding.group == group))
+            .count() as u32;
+        assert_eq!(storage_buffers(1), GEOMETRY_STORAGE_BUFFERS);
+        assert_eq!(storage_buffers(2), MATERIAL_STORAGE_BUFFERS);
+        assert_eq!(storage_buffers(3), ACCUMULATE_STORAGE_BUFFERS);
+        let total = GEOMETRY_STORAGE_BUFFERS + MATERIAL_STORAGE_BUFFERS + ACCUMULATE_STORAGE_BUFFERS;
+        assert!(total <= wgpu::Limits::default().max_storage_buffers_per_shader_stage);
+    }
+
+    #[test]
+    fn detects_mismatched_offset()
+    {
+        let layout = StructLayout {
+            name: "Sphere",
+            size: 32,
+            fields: vec![("radius", 0), ("material_idx", 4), ("center", 8)],
+        };
+        let error = validate(SHADER, &[layout]).unwrap_err();
+        assert!(error.contains("Sphere.center"), "{}", error);
+    }
+
+    #[test]
+    fn detects_missing_field()
+    {
+        let layout = StructLayout {
+            name: "Material",
+            size: 32,
+            fields: vec![("color", 0), ("emission", 16)],
+        };
+        let error = validate(SHADER, &[layout]).unwrap_err();
+        assert!(error.contains("Material.clean_coat: missing on the host"), "{}", error);
+    }
+}
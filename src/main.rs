@@ -1,26 +1,73 @@
+mod aov;
 mod camera;
+mod checkpoint;
+mod color;
+mod exposure;
+mod layout;
+mod mlt;
+mod options;
+mod post;
+mod restir;
 mod scene;
+mod sequence;
+mod settings;
+mod spectrum;
+mod sppm;
 mod state;
+mod watcher;
 
 
+use clap::Parser;
+use options::Options;
+use scene::Scene;
+use sequence::Sequence;
 use state::State;
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
-use winit::dpi::PhysicalSize;
 
 pub async fn run() {
     env_logger::init();
+    let options = Options::parse();
+    if !(0.0..=1.0).contains(&options.shutter) {
+        log::error!("--shutter must lie between 0 and 1, not {}", options.shutter);
+        std::process::exit(1);
+    }
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().with_title("ssgi").with_inner_size(PhysicalSize::new(1024, 1024)).build(&event_loop).unwrap();
-    let mut state = State::new(window).await;
+    // with --frames the window stays hidden and frames are drawn as fast as the event loop turns
+    let headless = options.frames.is_some();
+    let scene = options.scene.as_deref()
+        .map(|path| Scene::load(path).unwrap_or_else(|e| {
+            log::error!("failed to load scene {}: {}", path.display(), e);
+            std::process::exit(1);
+        }))
+        .unwrap_or_default();
+    // every eye of a stereo image takes the shape of the camera's sensor, sized before
+    // State::new so that no resize restarts a resumed checkpoint
+    let window = WindowBuilder::new()
+        .with_title("ssgi")
+        .with_inner_size(state::window_size(scene.camera.sensor_size(), options.stereo))
+        .with_visible(!headless)
+        .build(&event_loop)
+        .unwrap();
+    let mut state = State::new(window, &options, scene).await;
+    let mut sequence = options.sequence.as_deref().map(|directory| {
+        let Some(animation) = state.scene.animation.as_ref() else {
+            log::error!("--sequence needs a scene with an animation");
+            std::process::exit(1);
+        };
+        Sequence::new(directory, animation).unwrap_or_else(|e| {
+            log::error!("failed to create {}: {}", directory.display(), e);
+            std::process::exit(1);
+        })
+    });
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent {
             ref event,
             window_id,
-        } if window_id == state.window().id() => if !state.input(event) {
+        } if window_id == state.window().id() && !state.input(event) => {
             match event {
                 WindowEvent::CloseRequested
                 | WindowEvent::KeyboardInput {
@@ -32,6 +79,7 @@ pub async fn run() {
                     },
                     ..
                 } => {
+                    state.save_checkpoint();
                     *control_flow = ControlFlow::Exit;
                 }
                 WindowEvent::Resized(physical_size) => {
@@ -45,12 +93,27 @@ pub async fn run() {
             }
         },
         Event::RedrawRequested(window_id) if window_id == state.window().id() => {
-            state.update();
-            match state.render() {
-                Ok(_) => {}
-                Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
-                Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-                Err(e) => eprintln!("{:#?}", e),
+            redraw(&mut state, control_flow);
+        }
+        Event::MainEventsCleared if headless => {
+            redraw(&mut state, control_flow);
+            if options.frames.is_none_or(|frames| state.camera.frame_idx + 1 < frames) {
+                return;
+            }
+            match sequence.as_mut() {
+                Some(sequence) => {
+                    state.save_image(&sequence.path());
+                    if sequence.advance() {
+                        state.set_time(sequence.time());
+                    } else {
+                        *control_flow = ControlFlow::Exit;
+                    }
+                }
+                None => {
+                    state.save_image(&options.output);
+                    state.save_checkpoint();
+                    *control_flow = ControlFlow::Exit;
+                }
             }
         }
         Event::MainEventsCleared => {
@@ -60,6 +123,16 @@ pub async fn run() {
     });
 }
 
+fn redraw(state: &mut State, control_flow: &mut ControlFlow) {
+    state.update();
+    match state.render() {
+        Ok(_) => {}
+        Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
+        Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
+        Err(e) => eprintln!("{:#?}", e),
+    }
+}
+
 fn main() {
     pollster::block_on(run());
 }
@@ -0,0 +1,57 @@
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+/// Markov chains run side by side, matching `MLT_CHAINS` in the shader.
+pub const CHAINS: u32 = 1 << 16;
+/// Primary sample space dimensions kept per chain, matching `MLT_DIMENSIONS` in the shader.
+pub const DIMENSIONS: usize = 128;
+/// Threads per workgroup of the bootstrap and mutation passes.
+pub const WORKGROUP_SIZE: u32 = 64;
+/// Bytes in front of the chains, where the bootstrap pass sums its estimate of the normalisation.
+const HEADER_SIZE: usize = 16;
+
+/// Current state of one Markov chain: its primary samples and what the path tracer made of them.
+///
+/// The bootstrap pass fills in every chain on the first frame after the buffer is cleared, and
+/// the mutation passes then replace a chain whenever they accept a proposal.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MltChain
+{
+    samples: [f32; DIMENSIONS],
+    radiance: [f32; 3],
+    luminance: f32,
+    ndc: [f32; 2],
+    _padding: [u32; 2],
+}
+
+impl GpuLayout for MltChain
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(MltChain { samples, radiance, luminance, ndc })
+    }
+}
+
+/// Size of the buffer holding the header and every chain, `MltState` in the shader,
+/// or a single unused chain unless the Metropolis integrator is used.
+pub fn state_buffer_size(enabled: bool) -> wgpu::BufferAddress
+{
+    let chains = if enabled { CHAINS as usize } else { 1 };
+    (HEADER_SIZE + chains * std::mem::size_of::<MltChain>()) as wgpu::BufferAddress
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::layout::{shader_constant, shader_workgroup_size};
+
+    #[test]
+    fn shader_constants_match()
+    {
+        assert_eq!(shader_constant("MLT_CHAINS"), CHAINS as f64);
+        assert_eq!(shader_constant("MLT_DIMENSIONS"), DIMENSIONS as f64);
+        assert_eq!(shader_workgroup_size("mlt_bootstrap"), [WORKGROUP_SIZE, 1, 1]);
+        assert_eq!(shader_workgroup_size("mlt_mutate"), [WORKGROUP_SIZE, 1, 1]);
+    }
+}
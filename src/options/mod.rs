@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+use clap::Parser;
+use crate::camera::Stereo;
+use crate::color::Hdr;
+use crate::settings::{DebugView, Integrator};
+
+#[derive(Parser, Debug)]
+#[command(about = "A small wgpu path tracer")]
+pub struct Options
+{
+    /// Scene file to render instead of the built-in Cornell box
+    #[arg(long)]
+    pub scene: Option<PathBuf>,
+    /// Load the shader from disk and reload it and the scene file whenever they change
+    #[arg(long)]
+    pub watch: bool,
+    /// Shader source used in watch mode
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/src/shader.wgsl"))]
+    pub shader: PathBuf,
+    /// Checkpoint file to resume from at startup and to save progress into
+    #[arg(long)]
+    pub checkpoint: Option<PathBuf>,
+    /// Seconds between checkpoint saves
+    #[arg(long, default_value_t = 60)]
+    pub checkpoint_interval: u64,
+    /// Start in spectral mode, which M toggles while running
+    #[arg(long)]
+    pub spectral: bool,
+    /// Light transport algorithm to start with, which B cycles while running
+    #[arg(long, value_enum, default_value_t = Integrator::Path)]
+    pub integrator: Integrator,
+    /// Start with reservoir resampled direct lighting in the path tracer, which R toggles while running
+    #[arg(long)]
+    pub restir: bool,
+    /// Show a debug view instead of the rendered image, which V cycles while running
+    #[arg(long, value_enum, default_value_t = DebugView::Off)]
+    pub debug_view: DebugView,
+    /// Render an image for each eye into a window twice as wide or high; panoramas become omni-directional stereo
+    #[arg(long, value_enum, default_value_t = Stereo::Off)]
+    pub stereo: Stereo,
+    /// Distance between the eyes in meters for --stereo
+    #[arg(long, default_value_t = 0.064)]
+    pub ipd: f32,
+    /// Present HDR through a half float surface where the display offers one, SDR otherwise
+    #[arg(long, value_enum, default_value_t = Hdr::Off)]
+    pub hdr: Hdr,
+    /// Luminance in nits that --hdr shows diffuse white at
+    #[arg(long, default_value_t = 203.0)]
+    pub paper_white: f32,
+    /// Record compositing passes in the path tracer, written into the EXR alongside the image
+    #[arg(long)]
+    pub aov: bool,
+    /// EXR file that P writes the image into
+    #[arg(long, default_value = "render.exr")]
+    pub output: PathBuf,
+    /// Render this many frames without showing a window, write --output and exit
+    #[arg(long)]
+    pub frames: Option<u32>,
+    /// Fraction of the frame interval the shutter stays open, blurring what the animation moves
+    #[arg(long, default_value_t = 0.0)]
+    pub shutter: f32,
+    /// Render the scene's animation into numbered EXR files in this directory, --frames frames each, and exit
+    #[arg(long, requires = "frames")]
+    pub sequence: Option<PathBuf>,
+}
@@ -0,0 +1,246 @@
+use serde::{Deserialize, Serialize};
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+/// Side of the square workgroups of the bloom and glare passes.
+pub const WORKGROUP_SIZE: u32 = 8;
+/// Side of the first bloom mip, half that of the accumulate buffer.
+pub const BLOOM_SIZE: u32 = 512;
+/// Bloom mips, halving down to 8 by 8.
+pub const BLOOM_LEVELS: u32 = 7;
+/// Glare streaks are traced over the second bloom mip.
+pub const GLARE_SIZE: u32 = BLOOM_SIZE / 2;
+/// Aperture blades glare may have, which the glare pass traces up to twice as many spikes for.
+const MAX_BLADES: u32 = 16;
+
+/// Effects applied to the linear image before it is exposed and shown; each is off unless given.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PostDesc
+{
+    #[serde(default)]
+    pub bloom: Option<Bloom>,
+    #[serde(default)]
+    pub glare: Option<Glare>,
+    #[serde(default)]
+    pub chromatic_aberration: Option<ChromaticAberration>,
+    #[serde(default)]
+    pub vignette: Option<Vignette>,
+    #[serde(default)]
+    pub grain: Option<Grain>,
+}
+
+/// Blend towards the average of the bloom mips, which keeps the total energy.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Bloom
+{
+    pub intensity: f32,
+}
+
+/// Diffraction spikes from the aperture blades, longer in red than in blue.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Glare
+{
+    pub intensity: f32,
+    /// An even number of blades makes as many spikes, an odd number twice as many.
+    #[serde(default = "default_blades")]
+    pub blades: u32,
+    /// Reach of the spikes as a share of the image.
+    #[serde(default = "default_glare_length")]
+    pub length: f32,
+}
+
+/// Lateral colour fringes, red spread outwards and blue inwards towards the corners.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ChromaticAberration
+{
+    pub strength: f32,
+}
+
+/// Darkening by the lens barrel beyond `radius` from the centre, over `softness`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Vignette
+{
+    pub strength: f32,
+    #[serde(default = "default_vignette_radius")]
+    pub radius: f32,
+    #[serde(default = "default_vignette_softness")]
+    pub softness: f32,
+}
+
+/// Film grain, strongest in the mid tones and new every frame.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Grain
+{
+    pub intensity: f32,
+}
+
+fn default_blades() -> u32
+{
+    6
+}
+
+fn default_glare_length() -> f32
+{
+    0.1
+}
+
+fn default_vignette_radius() -> f32
+{
+    0.7
+}
+
+fn default_vignette_softness() -> f32
+{
+    0.6
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PostUniform
+{
+    bloom_intensity: f32,
+    glare_intensity: f32,
+    glare_streaks: u32,
+    glare_length: f32,
+    chromatic_aberration: f32,
+    vignette_strength: f32,
+    vignette_radius: f32,
+    vignette_softness: f32,
+    grain_intensity: f32,
+}
+
+impl GpuLayout for PostUniform
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(PostUniform {
+            bloom_intensity, glare_intensity, glare_streaks, glare_length, chromatic_aberration,
+            vignette_strength, vignette_radius, vignette_softness, grain_intensity,
+        })
+    }
+}
+
+impl PostDesc
+{
+    pub fn validate(&self) -> Result<(), String>
+    {
+        let strengths = [
+            self.bloom.map(|bloom| bloom.intensity),
+            self.glare.map(|glare| glare.intensity),
+            self.vignette.map(|vignette| vignette.strength),
+        ];
+        if strengths.into_iter().flatten().any(|x| x.is_nan() || !(0.0..=1.0).contains(&x)) {
+            return Err("bloom, glare and vignette strengths must be between 0 and 1".into());
+        }
+        let amounts = [
+            self.glare.map(|glare| glare.length),
+            self.chromatic_aberration.map(|aberration| aberration.strength),
+            self.vignette.map(|vignette| vignette.radius),
+            self.grain.map(|grain| grain.intensity),
+        ];
+        if amounts.into_iter().flatten().any(|x| !x.is_finite() || x < 0.0) {
+            return Err("glare length, chromatic aberration strength, vignette radius and grain intensity cannot be negative".into());
+        }
+        // the vignette is a smoothstep over its softness, undefined for an empty edge
+        if self.vignette.is_some_and(|vignette| !vignette.softness.is_finite() || vignette.softness <= 0.0) {
+            return Err("vignette softness must be positive".into());
+        }
+        if self.glare.is_some_and(|glare| !(2..=MAX_BLADES).contains(&glare.blades)) {
+            return Err(format!("glare needs between 2 and {} aperture blades", MAX_BLADES));
+        }
+        Ok(())
+    }
+    /// Whether any effect is on, without which the image is shown as rendered.
+    pub fn enabled(&self) -> bool
+    {
+        self.bloom.is_some() || self.glare.is_some() || self.chromatic_aberration.is_some()
+            || self.vignette.is_some() || self.grain.is_some()
+    }
+    /// Whether the bloom mips have to be built, which glare also traces its spikes over.
+    pub fn needs_mips(&self) -> bool
+    {
+        self.bloom.is_some() || self.glare.is_some()
+    }
+    pub fn uniform(&self) -> PostUniform
+    {
+        let glare = self.glare.unwrap_or(Glare { intensity: 0.0, blades: 2, length: 0.0 });
+        let vignette = self.vignette.unwrap_or(Vignette { strength: 0.0, radius: 1.0, softness: 1.0 });
+        PostUniform {
+            bloom_intensity: self.bloom.map_or(0.0, |bloom| bloom.intensity),
+            glare_intensity: glare.intensity,
+            glare_streaks: glare_streaks(glare.blades),
+            glare_length: glare.length,
+            chromatic_aberration: self.chromatic_aberration.map_or(0.0, |aberration| aberration.strength),
+            vignette_strength: vignette.strength,
+            vignette_radius: vignette.radius,
+            vignette_softness: vignette.softness,
+            grain_intensity: self.grain.map_or(0.0, |grain| grain.intensity),
+        }
+    }
+}
+
+/// Spikes of a polygonal aperture, whose opposite edges diffract into the same line when
+/// there is an even number of them.
+fn glare_streaks(blades: u32) -> u32
+{
+    if blades % 2 == 0 { blades } else { 2 * blades }
+}
+
+/// Texels in all bloom mips, which the buffer holds once going down and once coming back up,
+/// followed by the glare image.
+pub fn buffer_size() -> wgpu::BufferAddress
+{
+    let mips: u32 = (0..BLOOM_LEVELS).map(|level| (BLOOM_SIZE >> level).pow(2)).sum();
+    ((2 * mips + GLARE_SIZE * GLARE_SIZE) as usize * 4 * std::mem::size_of::<f32>()) as wgpu::BufferAddress
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::layout::{shader_constant, shader_workgroup_size};
+
+    #[test]
+    fn odd_apertures_double_their_spikes()
+    {
+        assert_eq!(glare_streaks(6), 6);
+        assert_eq!(glare_streaks(5), 10);
+    }
+
+    #[test]
+    fn effects_are_off_unless_given()
+    {
+        let mut post = PostDesc::default();
+        assert!(!post.enabled() && post.validate().is_ok());
+        post.grain = Some(Grain { intensity: 0.1 });
+        assert!(post.enabled() && !post.needs_mips());
+        post.bloom = Some(Bloom { intensity: 1.5 });
+        assert!(post.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_negative_effects_and_too_many_blades()
+    {
+        let valid = |post: PostDesc| post.validate().is_ok();
+        assert!(!valid(PostDesc { grain: Some(Grain { intensity: -0.1 }), ..Default::default() }));
+        assert!(!valid(PostDesc { chromatic_aberration: Some(ChromaticAberration { strength: f32::NAN }), ..Default::default() }));
+        let glare = Glare { intensity: 0.02, blades: 6, length: 0.1 };
+        assert!(valid(PostDesc { glare: Some(glare), ..Default::default() }));
+        assert!(!valid(PostDesc { glare: Some(Glare { length: -0.1, ..glare }), ..Default::default() }));
+        assert!(valid(PostDesc { glare: Some(Glare { blades: MAX_BLADES, ..glare }), ..Default::default() }));
+        assert!(!valid(PostDesc { glare: Some(Glare { blades: MAX_BLADES + 1, ..glare }), ..Default::default() }));
+        let vignette = Vignette { strength: 0.5, radius: 0.7, softness: 0.6 };
+        assert!(!valid(PostDesc { vignette: Some(Vignette { radius: -0.1, ..vignette }), ..Default::default() }));
+        assert!(!valid(PostDesc { vignette: Some(Vignette { softness: 0.0, ..vignette }), ..Default::default() }));
+    }
+
+    #[test]
+    fn shader_constants_match()
+    {
+        assert_eq!(shader_constant("BLOOM_SIZE"), BLOOM_SIZE as f64);
+        assert_eq!(shader_constant("BLOOM_LEVELS"), BLOOM_LEVELS as f64);
+        assert_eq!(shader_constant("GLARE_SIZE"), GLARE_SIZE as f64);
+        for entry_point in ["bloom_downsample", "bloom_upsample", "glare"] {
+            assert_eq!(shader_workgroup_size(entry_point), [WORKGROUP_SIZE, WORKGROUP_SIZE, 1]);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+/// Side of the square workgroups of the resampling passes.
+pub const WORKGROUP_SIZE: u32 = 8;
+
+/// Light sample kept for one accumulate buffer entry, with the surface it lights.
+///
+/// Only ever created zeroed on the host, which marks every pixel as having no surface yet.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Reservoir
+{
+    position: [f32; 3],
+    color: u32,
+    normal: [f32; 3],
+    light_idx: i32,
+    uv: [f32; 2],
+    m: f32,
+    weight: f32,
+}
+
+impl GpuLayout for Reservoir
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Reservoir { position, color, normal, light_idx, uv, m, weight })
+    }
+}
+
+/// Two reservoirs per entry: after temporal reuse, and after spatial reuse.
+/// Holds a single unused reservoir unless the path tracer resamples its lights.
+pub fn buffer_size(entries: u32, enabled: bool) -> wgpu::BufferAddress
+{
+    let reservoirs = if enabled { 2 * entries as usize } else { 1 };
+    (reservoirs * std::mem::size_of::<Reservoir>()) as wgpu::BufferAddress
+}
@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use cgmath::{InnerSpace, Vector3};
+use crate::camera::CameraPose;
+
+/// How a keyframe blends into the next one.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interpolation
+{
+    #[default]
+    Linear,
+    /// Eases out of this key and into the next with smoothstep.
+    Smooth,
+    /// Uniform Catmull-Rom spline, which also passes through the keys before and after.
+    Spline,
+}
+
+/// Value of an animated parameter at `time` seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Keyframe<T>
+{
+    pub time: f32,
+    pub value: T,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+/// Values that keyframes blend as weighted sums.
+pub trait Blend: Copy
+{
+    fn weighted_sum(values: [Self; 4], weights: [f32; 4]) -> Self;
+}
+
+impl Blend for f32
+{
+    fn weighted_sum(values: [Self; 4], weights: [f32; 4]) -> Self
+    {
+        values.iter().zip(weights).map(|(value, weight)| value * weight).sum()
+    }
+}
+
+impl Blend for [f32; 3]
+{
+    fn weighted_sum(values: [Self; 4], weights: [f32; 4]) -> Self
+    {
+        std::array::from_fn(|i| f32::weighted_sum(values.map(|value| value[i]), weights))
+    }
+}
+
+/// Value of a track at `time`, holding still before the first and after the last key.
+pub fn sample<T: Blend>(keys: &[Keyframe<T>], time: f32) -> Option<T>
+{
+    let next = keys.partition_point(|key| key.time <= time);
+    if next == 0 || next == keys.len() {
+        return keys.get(next.saturating_sub(1)).map(|key| key.value);
+    }
+    let (a, b) = (&keys[next - 1], &keys[next]);
+    let t = (time - a.time) / (b.time - a.time);
+    let weights = match a.interpolation {
+        Interpolation::Linear => [0.0, 1.0 - t, t, 0.0],
+        Interpolation::Smooth => {
+            let s = t * t * (3.0 - 2.0 * t);
+            [0.0, 1.0 - s, s, 0.0]
+        }
+        Interpolation::Spline => {
+            let (t2, t3) = (t * t, t * t * t);
+            [
+                0.5 * (-t + 2.0 * t2 - t3),
+                0.5 * (2.0 - 5.0 * t2 + 3.0 * t3),
+                0.5 * (t + 4.0 * t2 - 3.0 * t3),
+                0.5 * (t3 - t2),
+            ]
+        }
+    };
+    // the ends of the track repeat their key in place of the missing neighbour
+    let before = keys[next.saturating_sub(2)].value;
+    let after = keys.get(next + 1).map_or(b.value, |key| key.value);
+    Some(T::weighted_sum([before, a.value, b.value, after], weights))
+}
+
+fn end<T>(keys: &[Keyframe<T>]) -> f32
+{
+    keys.last().map_or(0.0, |key| key.time)
+}
+
+fn validate_track<T>(keys: &[Keyframe<T>], name: &str) -> Result<(), String>
+{
+    if keys.iter().any(|key| key.time.is_nan()) || keys.windows(2).any(|pair| pair[0].time >= pair[1].time) {
+        return Err(format!("{} keyframes must be in strictly increasing time order", name));
+    }
+    Ok(())
+}
+
+/// Keyframed changes to a scene over time.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Animation
+{
+    /// Frame rate of rendered sequences.
+    #[serde(default = "default_fps")]
+    pub fps: f32,
+    /// Length in seconds, the time of the last keyframe if left out.
+    #[serde(default)]
+    pub duration: Option<f32>,
+    #[serde(default)]
+    pub camera: CameraTrack,
+    #[serde(default)]
+    pub spheres: Vec<SphereTrack>,
+    #[serde(default)]
+    pub materials: Vec<MaterialTrack>,
+}
+
+fn default_fps() -> f32
+{
+    24.0
+}
+
+/// Camera placement; `up` is made perpendicular to `direction` after blending.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CameraTrack
+{
+    #[serde(default)]
+    pub position: Vec<Keyframe<[f32; 3]>>,
+    #[serde(default)]
+    pub direction: Vec<Keyframe<[f32; 3]>>,
+    #[serde(default)]
+    pub up: Vec<Keyframe<[f32; 3]>>,
+}
+
+/// Moves and resizes the sphere at `index` in `Scene::spheres`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SphereTrack
+{
+    pub index: usize,
+    #[serde(default)]
+    pub center: Vec<Keyframe<[f32; 3]>>,
+    #[serde(default)]
+    pub radius: Vec<Keyframe<f32>>,
+}
+
+/// Changes the material at `index` in `Scene::materials`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MaterialTrack
+{
+    pub index: usize,
+    #[serde(default)]
+    pub color: Vec<Keyframe<[f32; 3]>>,
+    #[serde(default)]
+    pub emission: Vec<Keyframe<[f32; 3]>>,
+    #[serde(default)]
+    pub clean_coat: Vec<Keyframe<f32>>,
+}
+
+impl CameraTrack
+{
+    pub fn is_animated(&self) -> bool
+    {
+        !self.position.is_empty() || !self.direction.is_empty() || !self.up.is_empty()
+    }
+    /// `pose` moved to where the track has it at `time`.
+    pub fn pose(&self, time: f32, mut pose: CameraPose) -> CameraPose
+    {
+        if let Some(position) = sample(&self.position, time) {
+            pose.position = position.into();
+        }
+        if let Some(direction) = sample(&self.direction, time) {
+            pose.direction = Vector3::from(direction).normalize();
+        }
+        if let Some(up) = sample(&self.up, time) {
+            pose.up = up.into();
+        }
+        if !self.direction.is_empty() || !self.up.is_empty() {
+            pose.up = (pose.up - pose.direction * pose.up.dot(pose.direction)).normalize();
+        }
+        pose
+    }
+}
+
+impl Animation
+{
+    pub fn duration(&self) -> f32
+    {
+        self.duration.unwrap_or_else(|| {
+            let camera = [end(&self.camera.position), end(&self.camera.direction), end(&self.camera.up)];
+            let spheres = self.spheres.iter().flat_map(|track| [end(&track.center), end(&track.radius)]);
+            let materials = self.materials.iter()
+                .flat_map(|track| [end(&track.color), end(&track.emission), end(&track.clean_coat)]);
+            camera.into_iter().chain(spheres).chain(materials).fold(0.0, f32::max)
+        })
+    }
+    /// Frames of a rendered sequence, from time zero up to and including the duration.
+    pub fn frame_count(&self) -> u32
+    {
+        (self.duration() * self.fps + 1e-3).floor() as u32 + 1
+    }
+    pub fn validate(&self, spheres: usize, materials: usize) -> Result<(), String>
+    {
+        if self.fps.is_nan() || self.fps <= 0.0 {
+            return Err("animation fps must be positive".into());
+        }
+        if self.duration.is_some_and(|duration| duration.is_nan() || duration < 0.0) {
+            return Err("animation duration must not be negative".into());
+        }
+        validate_track(&self.camera.position, "camera position")?;
+        validate_track(&self.camera.direction, "camera direction")?;
+        validate_track(&self.camera.up, "camera up")?;
+        for track in &self.spheres {
+            if track.index >= spheres {
+                return Err(format!("animation references missing sphere {}", track.index));
+            }
+            validate_track(&track.center, "sphere center")?;
+            validate_track(&track.radius, "sphere radius")?;
+        }
+        for track in &self.materials {
+            if track.index >= materials {
+                return Err(format!("animation references missing material {}", track.index));
+            }
+            validate_track(&track.color, "material color")?;
+            validate_track(&track.emission, "material emission")?;
+            validate_track(&track.clean_coat, "material clean_coat")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn key(time: f32, value: f32, interpolation: Interpolation) -> Keyframe<f32>
+    {
+        Keyframe { time, value, interpolation }
+    }
+
+    #[test]
+    fn interpolates_between_keys()
+    {
+        let linear = [key(0.0, 0.0, Interpolation::Linear), key(2.0, 4.0, Interpolation::Linear)];
+        assert_eq!(sample(&linear, -1.0), Some(0.0));
+        assert_eq!(sample(&linear, 0.5), Some(1.0));
+        assert_eq!(sample(&linear, 3.0), Some(4.0));
+        assert_eq!(sample::<f32>(&[], 1.0), None);
+        let smooth = [key(0.0, 0.0, Interpolation::Smooth), key(1.0, 1.0, Interpolation::Linear)];
+        assert!(sample(&smooth, 0.25).unwrap() < 0.25);
+        assert_eq!(sample(&smooth, 0.5), Some(0.5));
+    }
+
+    #[test]
+    fn spline_passes_through_keys()
+    {
+        let keys = [
+            key(0.0, 0.0, Interpolation::Spline),
+            key(1.0, 1.0, Interpolation::Spline),
+            key(2.0, 0.0, Interpolation::Spline),
+        ];
+        assert!((sample(&keys, 1.0).unwrap() - 1.0).abs() < 1e-6);
+        // unlike straight lines, the spline overshoots towards the peak
+        assert!(sample(&keys, 0.75).unwrap() > 0.75);
+        // on evenly spaced keys along a line, it stays on the line
+        let line = [key(0.0, 0.0, Interpolation::Spline), key(1.0, 1.0, Interpolation::Spline), key(2.0, 2.0, Interpolation::Spline), key(3.0, 3.0, Interpolation::Spline)];
+        assert!((sample(&line, 1.5).unwrap() - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn frames_cover_duration()
+    {
+        let mut animation = Animation { fps: 10.0, ..Animation::default() };
+        animation.spheres.push(SphereTrack {
+            index: 0,
+            center: vec![],
+            radius: vec![key(0.0, 1.0, Interpolation::Linear), key(2.0, 2.0, Interpolation::Linear)],
+        });
+        assert_eq!(animation.duration(), 2.0);
+        assert_eq!(animation.frame_count(), 21);
+        assert!(animation.validate(1, 1).is_ok());
+        assert!(animation.validate(0, 1).is_err());
+        animation.spheres[0].radius.reverse();
+        assert!(animation.validate(1, 1).is_err());
+    }
+}
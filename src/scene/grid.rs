@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+/// Largest 3D texture depth every adapter supports, which the stacked grids must fit into.
+pub const MAX_ATLAS_DEPTH: u32 = 2048;
+
+/// Where the voxels of a density grid come from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum GridSource
+{
+    /// Mitsuba `.vol` file with float voxels, relative to the scene file.
+    File(PathBuf),
+    /// Noisy ball of `resolution`³ voxels.
+    Cloud
+    {
+        resolution: u32,
+        #[serde(default)]
+        seed: u32,
+        /// Noise features across the ball, before octaves are added.
+        #[serde(default = "default_frequency")]
+        frequency: f32,
+    },
+}
+
+fn default_frequency() -> f32
+{
+    4.0
+}
+
+/// Density voxels with x varying fastest, then y, then z.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid
+{
+    pub size: [u32; 3],
+    pub data: Vec<f32>,
+}
+
+impl Grid
+{
+    pub fn load(source: &GridSource, directory: &Path) -> Result<Self, String>
+    {
+        match *source {
+            GridSource::File(ref path) => {
+                let path = directory.join(path);
+                let bytes = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                parse_vol(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+            }
+            GridSource::Cloud { resolution, seed, frequency } => {
+                if resolution == 0 {
+                    return Err("cloud resolution must be at least 1".into());
+                }
+                Ok(cloud(resolution, seed, frequency))
+            }
+        }
+    }
+    /// Upper bound on the density, which delta and ratio tracking step with.
+    pub fn max_density(&self) -> f32
+    {
+        self.data.iter().fold(0.0, |a, b| a.max(*b))
+    }
+}
+
+/// Mitsuba's grid volume format: `VOL`, version 3, encoding 1 for float32, the
+/// resolution, the channel count and a bounding box, followed by the voxels.
+/// Only the first channel is kept; the bounding box is replaced by the primitive's.
+fn parse_vol(bytes: &[u8]) -> Result<Grid, String>
+{
+    let header: [u8; 48] = bytes.get(..48)
+        .and_then(|header| header.try_into().ok())
+        .ok_or("file is too short for a volume header")?;
+    if &header[..3] != b"VOL" || header[3] != 3 {
+        return Err("not a version 3 VOL file".into());
+    }
+    let int = |offset: usize| i32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    if int(4) != 1 {
+        return Err("only float32 voxels are supported".into());
+    }
+    let (x, y, z, channels) = (int(8), int(12), int(16), int(20));
+    if x <= 0 || y <= 0 || z <= 0 || channels <= 0 {
+        return Err("resolution and channel count must be positive".into());
+    }
+    let count = x as usize * y as usize * z as usize;
+    let voxels = &bytes[48..];
+    if voxels.len() < count * channels as usize * 4 {
+        return Err(format!("expected {} voxels", count));
+    }
+    let data = voxels.chunks_exact(4 * channels as usize)
+        .take(count)
+        .map(|voxel| f32::from_le_bytes(voxel[..4].try_into().unwrap()))
+        .collect::<Vec<_>>();
+    if data.iter().any(|d| *d < 0.0 || !d.is_finite()) {
+        return Err("densities must be finite and non-negative".into());
+    }
+    Ok(Grid {
+        size: [x as u32, y as u32, z as u32],
+        data,
+    })
+}
+
+/// Fractal value noise carved into a ball, dense in the middle and wispy at the edge.
+fn cloud(resolution: u32, seed: u32, frequency: f32) -> Grid
+{
+    let n = resolution as usize;
+    let mut data = Vec::with_capacity(n * n * n);
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..n {
+                let p = [x, y, z].map(|i| 2.0 * (i as f32 + 0.5) / n as f32 - 1.0);
+                let r = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+                let noise = fractal_noise(p.map(|c| c * frequency), seed);
+                data.push(((1.0 - r) + 0.6 * (noise - 0.5)).clamp(0.0, 0.3) / 0.3);
+            }
+        }
+    }
+    Grid {
+        size: [resolution; 3],
+        data,
+    }
+}
+
+fn fractal_noise(p: [f32; 3], seed: u32) -> f32
+{
+    let mut sum = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0;
+    for octave in 0..4 {
+        sum += amplitude * value_noise(p.map(|c| c * frequency), seed.wrapping_add(octave));
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / (1.0 - amplitude * 2.0)
+}
+
+fn value_noise(p: [f32; 3], seed: u32) -> f32
+{
+    let cell = p.map(|c| c.floor());
+    let f = std::array::from_fn::<f32, 3, _>(|i| p[i] - cell[i]);
+    let smooth = f.map(|t| t * t * (3.0 - 2.0 * t));
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        lattice(cell[0] as i32 + dx, cell[1] as i32 + dy, cell[2] as i32 + dz, seed)
+    };
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), smooth[0]);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), smooth[0]);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), smooth[0]);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), smooth[0]);
+    lerp(lerp(x00, x10, smooth[1]), lerp(x01, x11, smooth[1]), smooth[2])
+}
+
+/// Random value in [0, 1) for a lattice point, from an integer hash.
+fn lattice(x: i32, y: i32, z: i32, seed: u32) -> f32
+{
+    let mut h = seed.wrapping_mul(0x9e3779b9)
+        ^ (x as u32).wrapping_mul(0x85ebca6b)
+        ^ (y as u32).wrapping_mul(0xc2b2ae35)
+        ^ (z as u32).wrapping_mul(0x27d4eb2f);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a2d39);
+    h ^= h >> 15;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn parses_vol_files()
+    {
+        let mut bytes = b"VOL\x03".to_vec();
+        for value in [1, 2, 1, 1, 2] {
+            bytes.extend(i32::to_le_bytes(value));
+        }
+        for value in [0.0f32, 0.0, 0.0, 1.0, 1.0, 1.0, 0.25, 9.0, 0.5, 9.0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        let grid = parse_vol(&bytes).unwrap();
+        assert_eq!(grid.size, [2, 1, 1]);
+        assert_eq!(grid.data, [0.25, 0.5]);
+        assert!(parse_vol(&bytes[..52]).is_err());
+        bytes[3] = 2;
+        assert!(parse_vol(&bytes).is_err());
+    }
+
+    #[test]
+    fn cloud_is_densest_in_the_middle()
+    {
+        let grid = cloud(16, 7, 4.0);
+        let at = |x: usize, y: usize, z: usize| grid.data[(z * 16 + y) * 16 + x];
+        assert_eq!(grid.data.len(), 16 * 16 * 16);
+        assert!(at(8, 8, 8) > 0.5);
+        assert_eq!(at(0, 0, 0), 0.0);
+        assert!(grid.max_density() <= 1.0);
+    }
+}
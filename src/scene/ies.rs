@@ -0,0 +1,118 @@
+use std::path::Path;
+
+/// Number of samples each profile is resampled to, covering 0..180 degrees.
+pub const IES_SAMPLES: usize = 64;
+
+/// Loads an IESNA LM-63 photometric file as a rotationally symmetric profile.
+///
+/// Candela values are averaged over the horizontal angles, resampled to
+/// [`IES_SAMPLES`] vertical angles and normalised to a peak of one, so the
+/// light's own emission keeps setting the brightness.
+pub fn load_profile(path: &Path) -> Result<Vec<f32>, String>
+{
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse_profile(&source).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn parse_profile(source: &str) -> Result<Vec<f32>, String>
+{
+    let mut lines = source.lines();
+    let tilt = lines.by_ref()
+        .find(|line| line.trim_start().starts_with("TILT="))
+        .ok_or("missing TILT line")?;
+    let mut numbers = lines.flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|token| !token.is_empty())
+        .map(|token| token.parse::<f32>().map_err(|_| format!("bad number {:?}", token)));
+    let mut next = move || numbers.next().ok_or_else(|| "unexpected end of file".to_string())?;
+    if tilt.trim() == "TILT=INCLUDE" {
+        next()?; // lamp to luminaire geometry
+        let count = next()? as usize;
+        for _ in 0..2 * count {
+            next()?;
+        }
+    } else if tilt.trim() != "TILT=NONE" {
+        return Err("only TILT=NONE and TILT=INCLUDE are supported".into());
+    }
+    next()?; // number of lamps
+    next()?; // lumens per lamp
+    let multiplier = next()?;
+    let vertical_count = next()? as usize;
+    let horizontal_count = next()? as usize;
+    for _ in 0..8 {
+        // photometric type, units, dimensions, ballast factor, future use, input watts
+        next()?;
+    }
+    let vertical_angles = (0..vertical_count).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+    for _ in 0..horizontal_count {
+        next()?;
+    }
+    let mut candela = vec![0.0; vertical_count];
+    for _ in 0..horizontal_count {
+        for value in candela.iter_mut() {
+            *value += next()? * multiplier / horizontal_count as f32;
+        }
+    }
+    if vertical_count == 0 || vertical_angles.windows(2).any(|w| w[1] <= w[0]) {
+        return Err("vertical angles must be increasing".into());
+    }
+    let mut profile: Vec<f32> = (0..IES_SAMPLES)
+        .map(|i| sample(&vertical_angles, &candela, 180.0 * i as f32 / (IES_SAMPLES - 1) as f32))
+        .collect();
+    let peak = profile.iter().cloned().fold(0.0, f32::max);
+    if peak <= 0.0 {
+        return Err("profile emits no light".into());
+    }
+    profile.iter_mut().for_each(|value| *value /= peak);
+    Ok(profile)
+}
+
+/// Linear interpolation, dark outside the measured range.
+fn sample(angles: &[f32], values: &[f32], angle: f32) -> f32
+{
+    let last = angles.len() - 1;
+    if angle < angles[0] || angle > angles[last] {
+        return 0.0;
+    }
+    if last == 0 {
+        return values[0];
+    }
+    let i = angles.partition_point(|a| *a <= angle).clamp(1, last);
+    let t = (angle - angles[i - 1]) / (angles[i] - angles[i - 1]);
+    values[i - 1] + t * (values[i] - values[i - 1])
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const DOWNLIGHT: &str = "IESNA:LM-63-2002
+[TEST] downlight
+TILT=NONE
+1 1000 1 5 2 1 2 0 0 0
+1 1 100
+0 30 60 90 180
+0 180
+200 150 50 0 0
+100 50 10 0 0
+";
+
+    #[test]
+    fn parses_and_normalises()
+    {
+        let profile = parse_profile(DOWNLIGHT).unwrap();
+        assert_eq!(profile.len(), IES_SAMPLES);
+        assert!((profile[0] - 1.0).abs() < 1e-6);
+        // averaged over both horizontal planes: 150 at 0 degrees, 25 at 60 degrees
+        let at_60 = profile[(60.0 / 180.0 * (IES_SAMPLES - 1) as f32).round() as usize];
+        assert!((at_60 - 25.0 / 150.0).abs() < 0.05, "{}", at_60);
+        assert_eq!(profile[IES_SAMPLES - 1], 0.0);
+    }
+
+    #[test]
+    fn rejects_truncated_file()
+    {
+        assert!(parse_profile(&DOWNLIGHT[..DOWNLIGHT.len() - 12]).is_err());
+    }
+}
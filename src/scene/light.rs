@@ -0,0 +1,409 @@
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::color::ColorDesc;
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+pub const LIGHT_RECTANGLE: u32 = 0;
+pub const LIGHT_DISK: u32 = 1;
+pub const LIGHT_POINT: u32 = 2;
+pub const LIGHT_SPOT: u32 = 3;
+pub const LIGHT_DIRECTIONAL: u32 = 4;
+
+/// Luminous efficacy of radiation at 555nm, in lumens per watt.
+pub const LUMENS_PER_WATT: f32 = 683.0;
+
+/// Emitter as laid out in the light buffer.
+///
+/// Rectangles span `edge_u` and `edge_v` from `position`; disks are centred on
+/// `position` and use the edges as their two radius vectors. Spot and
+/// directional lights point along `edge_u`. `emission` is radiance for area
+/// lights, intensity for point and spot lights and irradiance for directional
+/// lights. The cone cosines bound a spot's falloff, or the sun disk of a
+/// directional light in `cos_outer`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Light
+{
+    position: [f32; 3],
+    kind: u32,
+    edge_u: [f32; 3],
+    two_sided: u32,
+    edge_v: [f32; 3],
+    area: f32,
+    emission: [f32; 3],
+    cos_inner: f32,
+    cos_outer: f32,
+    profile_idx: i32,
+    _padding: [u32; 2],
+}
+
+impl GpuLayout for Light
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Light { position, kind, edge_u, two_sided, edge_v, area, emission, cos_inner, cos_outer, profile_idx })
+    }
+}
+
+impl Light
+{
+    pub fn in_working_space(mut self, color: &ColorDesc) -> Self
+    {
+        self.emission = color.to_working(self.emission);
+        self
+    }
+}
+
+/// How bright an emitter is, either directly or in physical units.
+///
+/// Physical units are spread over the colour channels in proportion to `color`.
+/// Area lights are Lambertian, and for directional lights `Watts` and `Lumens`
+/// give the irradiance in W/m² and lux.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Emission
+{
+    /// Radiance, intensity or irradiance per channel, depending on the light.
+    Rgb([f32; 3]),
+    /// Total radiant power in watts.
+    Watts { power: f32, color: [f32; 3] },
+    /// Total luminous flux in lumens.
+    Lumens { flux: f32, color: [f32; 3] },
+}
+
+impl Emission
+{
+    /// Resolves physical units over `extent`, the factor between the raw
+    /// quantity and the total power: π times the area for area lights, the
+    /// solid angle for point and spot lights.
+    fn resolve(&self, extent: f32) -> [f32; 3]
+    {
+        match *self {
+            Emission::Rgb(value) => value,
+            Emission::Watts { power, color } => {
+                let mean = (color[0] + color[1] + color[2]) / 3.0;
+                scale(color, power / (extent * mean))
+            }
+            Emission::Lumens { flux, color } => {
+                scale(color, flux / (LUMENS_PER_WATT * extent * luminance(color)))
+            }
+        }
+    }
+    /// Physical units need a colour to spread over, which `resolve` divides by.
+    fn validate(&self) -> Result<(), String>
+    {
+        let negative = |c: [f32; 3]| c.iter().any(|x| x.is_nan() || *x < 0.0);
+        match *self {
+            Emission::Rgb(value) if negative(value) => Err("light emission must not be negative".into()),
+            Emission::Watts { power, color } | Emission::Lumens { flux: power, color } if power.is_nan() || power <= 0.0 || negative(color) => {
+                Err("light power and flux must be positive, with a colour that is not negative".into())
+            }
+            Emission::Watts { color, .. } if color.iter().sum::<f32>() <= 0.0 => Err("light in watts needs a colour that is not black".into()),
+            Emission::Lumens { color, .. } if luminance(color) <= 0.0 => Err("light in lumens needs a colour with luminance".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Light source as written in a scene file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LightDesc
+{
+    /// Rectangle spanned by two perpendicular edges; it faces along `edge_u × edge_v`.
+    Rectangle
+    {
+        corner: [f32; 3],
+        edge_u: [f32; 3],
+        edge_v: [f32; 3],
+        emission: Emission,
+        #[serde(default)]
+        two_sided: bool,
+    },
+    Disk
+    {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+        emission: Emission,
+        #[serde(default)]
+        two_sided: bool,
+    },
+    Point
+    {
+        position: [f32; 3],
+        emission: Emission,
+    },
+    /// Cone angles are in degrees from the axis; light fades out between them.
+    Spot
+    {
+        position: [f32; 3],
+        direction: [f32; 3],
+        inner_angle: f32,
+        outer_angle: f32,
+        emission: Emission,
+        /// IES profile, relative to the scene file.
+        #[serde(default)]
+        profile: Option<PathBuf>,
+    },
+    /// Light arriving from infinitely far away, travelling along `direction`.
+    /// A non-zero `angular_radius` in degrees gives it a disk like the sun's.
+    Directional
+    {
+        direction: [f32; 3],
+        emission: Emission,
+        #[serde(default)]
+        angular_radius: f32,
+    },
+}
+
+impl LightDesc
+{
+    /// Converts to the GPU layout, with scene lengths scaled by `meters_per_unit`
+    /// when resolving physical emission units.
+    pub fn to_light(&self, meters_per_unit: f32, profile_idx: i32) -> Light
+    {
+        let mut light = Light {
+            position: [0.0; 3],
+            kind: LIGHT_RECTANGLE,
+            edge_u: [0.0; 3],
+            two_sided: 0,
+            edge_v: [0.0; 3],
+            area: self.area(),
+            emission: [0.0; 3],
+            cos_inner: 1.0,
+            cos_outer: 1.0,
+            profile_idx: -1,
+            _padding: [0; 2],
+        };
+        let sides = |two_sided: bool| if two_sided { 2.0 } else { 1.0 };
+        let area = light.area * meters_per_unit * meters_per_unit;
+        match *self {
+            LightDesc::Rectangle { corner, edge_u, edge_v, emission, two_sided } => {
+                light.position = corner;
+                light.edge_u = edge_u;
+                light.edge_v = edge_v;
+                light.two_sided = two_sided as u32;
+                light.emission = emission.resolve(PI * area * sides(two_sided));
+            }
+            LightDesc::Disk { center, normal, radius, emission, two_sided } => {
+                let normal = normalize(normal);
+                let helper = if normal[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+                // ordered so that edge_u × edge_v points along the normal
+                let edge_v = normalize(cross(normal, helper));
+                let edge_u = cross(edge_v, normal);
+                light.kind = LIGHT_DISK;
+                light.position = center;
+                light.edge_u = scale(edge_u, radius);
+                light.edge_v = scale(edge_v, radius);
+                light.two_sided = two_sided as u32;
+                light.emission = emission.resolve(PI * area * sides(two_sided));
+            }
+            LightDesc::Point { position, emission } => {
+                light.kind = LIGHT_POINT;
+                light.position = position;
+                light.emission = emission.resolve(4.0 * PI);
+            }
+            LightDesc::Spot { position, direction, inner_angle, outer_angle, emission, .. } => {
+                light.kind = LIGHT_SPOT;
+                light.position = position;
+                light.edge_u = normalize(direction);
+                light.cos_inner = inner_angle.to_radians().cos();
+                light.cos_outer = outer_angle.to_radians().cos();
+                light.profile_idx = profile_idx;
+                // solid angle of the cone halfway through the falloff
+                let cos_mid = 0.5 * (light.cos_inner + light.cos_outer);
+                light.emission = emission.resolve(2.0 * PI * (1.0 - cos_mid));
+            }
+            LightDesc::Directional { direction, emission, angular_radius } => {
+                light.kind = LIGHT_DIRECTIONAL;
+                light.edge_u = normalize(direction);
+                light.cos_outer = angular_radius.to_radians().cos();
+                light.emission = emission.resolve(1.0);
+            }
+        }
+        light
+    }
+    /// Emitting area of one side, in scene units. Zero for delta lights.
+    pub fn area(&self) -> f32
+    {
+        match *self {
+            LightDesc::Rectangle { edge_u, edge_v, .. } => length(cross(edge_u, edge_v)),
+            LightDesc::Disk { radius, .. } => PI * radius * radius,
+            LightDesc::Point { .. } | LightDesc::Spot { .. } | LightDesc::Directional { .. } => 0.0,
+        }
+    }
+    fn emission(&self) -> Emission
+    {
+        match *self {
+            LightDesc::Rectangle { emission, .. }
+            | LightDesc::Disk { emission, .. }
+            | LightDesc::Point { emission, .. }
+            | LightDesc::Spot { emission, .. }
+            | LightDesc::Directional { emission, .. } => emission,
+        }
+    }
+    pub fn validate(&self) -> Result<(), String>
+    {
+        self.emission().validate()?;
+        match *self {
+            LightDesc::Rectangle { edge_u, edge_v, .. } => {
+                // spherical rectangle sampling needs a true rectangle
+                if dot(edge_u, edge_v).abs() > 1e-3 * length(edge_u) * length(edge_v) {
+                    return Err("rectangle light edges must be perpendicular".into());
+                }
+                if self.area() <= 0.0 {
+                    return Err("light has no area".into());
+                }
+            }
+            LightDesc::Disk { normal, .. } => {
+                if self.area() <= 0.0 || length(normal) == 0.0 {
+                    return Err("disk light needs a radius and a normal".into());
+                }
+            }
+            LightDesc::Point { .. } => {}
+            LightDesc::Spot { direction, inner_angle, outer_angle, .. } => {
+                if length(direction) == 0.0 {
+                    return Err("spot light needs a direction".into());
+                }
+                // a closed cone has no solid angle to spread physical units over
+                if inner_angle.is_nan() || inner_angle < 0.0 || inner_angle > outer_angle || outer_angle <= 0.0 || outer_angle > 180.0 {
+                    return Err("spot light angles must satisfy 0 <= inner <= outer <= 180 with outer > 0".into());
+                }
+            }
+            LightDesc::Directional { direction, angular_radius, .. } => {
+                if length(direction) == 0.0 {
+                    return Err("directional light needs a direction".into());
+                }
+                if !(0.0..90.0).contains(&angular_radius) {
+                    return Err("directional light angular radius must be below 90 degrees".into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn luminance(c: [f32; 3]) -> f32
+{
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+fn scale(a: [f32; 3], s: f32) -> [f32; 3]
+{
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32
+{
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3]
+{
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn length(a: [f32; 3]) -> f32
+{
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: [f32; 3]) -> [f32; 3]
+{
+    scale(a, 1.0 / length(a))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn watts_round_trip_through_radiance()
+    {
+        let desc = LightDesc::Disk {
+            center: [0.0; 3],
+            normal: [0.0, -1.0, 0.0],
+            radius: 100.0,
+            emission: Emission::Watts { power: 60.0, color: [1.0, 1.0, 1.0] },
+            two_sided: false,
+        };
+        let light = desc.to_light(0.001, -1);
+        // Φ = π A L for a one-sided Lambertian emitter
+        let area = PI * 0.1 * 0.1;
+        assert!((PI * area * light.emission[1] - 60.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn point_light_spreads_power_over_the_sphere()
+    {
+        let desc = LightDesc::Point {
+            position: [0.0; 3],
+            emission: Emission::Watts { power: 4.0 * PI, color: [1.0, 1.0, 1.0] },
+        };
+        let light = desc.to_light(1.0, -1);
+        assert!((light.emission[0] - 1.0).abs() < 1e-5);
+        assert_eq!(light.area, 0.0);
+    }
+
+    #[test]
+    fn rejects_black_physical_emission()
+    {
+        let point = |emission| LightDesc::Point { position: [0.0; 3], emission };
+        assert!(point(Emission::Watts { power: 60.0, color: [0.0; 3] }).validate().is_err());
+        assert!(point(Emission::Lumens { flux: 800.0, color: [0.0; 3] }).validate().is_err());
+        assert!(point(Emission::Watts { power: 0.0, color: [1.0; 3] }).validate().is_err());
+        assert!(point(Emission::Rgb([0.0; 3])).validate().is_ok());
+    }
+
+    #[test]
+    fn disk_faces_along_normal()
+    {
+        let desc = LightDesc::Disk {
+            center: [0.0; 3],
+            normal: [0.0, -1.0, 0.0],
+            radius: 2.0,
+            emission: Emission::Rgb([1.0; 3]),
+            two_sided: false,
+        };
+        let light = desc.to_light(1.0, -1);
+        let normal = cross(light.edge_u, light.edge_v);
+        assert!(normal[1] < 0.0 && (length(normal) - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rejects_skewed_rectangle()
+    {
+        let desc = LightDesc::Rectangle {
+            corner: [0.0; 3],
+            edge_u: [1.0, 0.0, 0.0],
+            edge_v: [1.0, 0.0, 1.0],
+            emission: Emission::Rgb([1.0; 3]),
+            two_sided: false,
+        };
+        assert!(desc.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_inverted_spot_cone()
+    {
+        let desc = LightDesc::Spot {
+            position: [0.0; 3],
+            direction: [0.0, -1.0, 0.0],
+            inner_angle: 40.0,
+            outer_angle: 30.0,
+            emission: Emission::Rgb([1.0; 3]),
+            profile: None,
+        };
+        assert!(desc.validate().is_err());
+        let closed = LightDesc::Spot {
+            position: [0.0; 3],
+            direction: [0.0, -1.0, 0.0],
+            inner_angle: 0.0,
+            outer_angle: 0.0,
+            emission: Emission::Watts { power: 60.0, color: [1.0; 3] },
+            profile: None,
+        };
+        assert!(closed.validate().is_err());
+    }
+}
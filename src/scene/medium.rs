@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+use super::grid::{Grid, GridSource};
+
+/// Participating medium as written in a scene file.
+///
+/// Coefficients are per meter, so that a medium keeps its look across scenes
+/// with different `meters_per_unit`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MediumDesc
+{
+    #[serde(default)]
+    pub absorption: [f32; 3],
+    #[serde(default)]
+    pub scattering: [f32; 3],
+    /// Henyey–Greenstein `g`, from -1 for back scattering to 1 for forward scattering.
+    #[serde(default)]
+    pub anisotropy: f32,
+    /// Makes the medium heterogeneous, in place of `absorption` and `scattering`.
+    #[serde(default)]
+    pub density: Option<DensityDesc>,
+}
+
+/// Voxel grid stretched over the bounding box of the primitives filled with the medium.
+///
+/// Extinction per meter is `density_scale` times the voxel density, of which
+/// `albedo` is scattered and the rest absorbed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DensityDesc
+{
+    pub grid: GridSource,
+    #[serde(default = "default_density_scale")]
+    pub density_scale: f32,
+    #[serde(default = "default_albedo")]
+    pub albedo: [f32; 3],
+}
+
+fn default_density_scale() -> f32
+{
+    1.0
+}
+
+fn default_albedo() -> [f32; 3]
+{
+    [1.0; 3]
+}
+
+/// Medium coefficients per scene unit, bound in the `media` buffer.
+///
+/// Heterogeneous media scale `sigma_t` and `sigma_s` by the density found at
+/// `grid_offset` slices into the grid atlas, and track collisions against `majorant`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Medium
+{
+    sigma_t: [f32; 3],
+    g: f32,
+    sigma_s: [f32; 3],
+    /// -1 for a homogeneous medium.
+    grid_offset: i32,
+    grid_min: [f32; 3],
+    majorant: f32,
+    grid_max: [f32; 3],
+    _padding1: u32,
+    grid_size: [u32; 3],
+    _padding2: u32,
+}
+
+impl GpuLayout for Medium
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Medium { sigma_t, g, sigma_s, grid_offset, grid_min, majorant, grid_max, grid_size })
+    }
+}
+
+impl Medium
+{
+    /// Empty space, which the shader skips distance sampling for.
+    pub const VACUUM: Medium = Medium {
+        sigma_t: [0.0; 3],
+        g: 0.0,
+        sigma_s: [0.0; 3],
+        grid_offset: -1,
+        grid_min: [0.0; 3],
+        majorant: 0.0,
+        grid_max: [0.0; 3],
+        _padding1: 0,
+        grid_size: [0; 3],
+        _padding2: 0,
+    };
+    /// Places `grid` over the box from `min` to `max`, `offset` slices into the atlas.
+    pub fn with_grid(self, grid: &Grid, offset: u32, [min, max]: [[f32; 3]; 2]) -> Self
+    {
+        Self {
+            grid_offset: offset as i32,
+            grid_min: min,
+            majorant: self.sigma_t[0] * grid.max_density(),
+            grid_max: max,
+            grid_size: grid.size,
+            ..self
+        }
+    }
+}
+
+impl MediumDesc
+{
+    /// Coefficients without the grid, which [`Medium::with_grid`] adds.
+    pub fn to_medium(&self, meters_per_unit: f32) -> Medium
+    {
+        let (sigma_t, sigma_s) = match self.density {
+            Some(ref density) => {
+                let sigma_t = density.density_scale * meters_per_unit;
+                ([sigma_t; 3], density.albedo.map(|a| a * sigma_t))
+            }
+            None => (
+                std::array::from_fn(|i| (self.absorption[i] + self.scattering[i]) * meters_per_unit),
+                self.scattering.map(|s| s * meters_per_unit),
+            ),
+        };
+        Medium {
+            sigma_t,
+            g: self.anisotropy,
+            sigma_s,
+            ..Medium::VACUUM
+        }
+    }
+    pub fn validate(&self) -> Result<(), String>
+    {
+        if self.absorption.iter().chain(&self.scattering).any(|c| *c < 0.0 || !c.is_finite()) {
+            return Err(format!("medium coefficients must be finite and non-negative: {:?}", self));
+        }
+        // |g| = 1 is a delta phase function, which the sampling routine divides by zero on
+        if self.anisotropy.is_nan() || self.anisotropy.abs() >= 1.0 {
+            return Err(format!("medium anisotropy must lie strictly between -1 and 1, got {}", self.anisotropy));
+        }
+        if let Some(ref density) = self.density {
+            if density.density_scale < 0.0 || !density.density_scale.is_finite() {
+                return Err(format!("density scale must be finite and non-negative, got {}", density.density_scale));
+            }
+            if density.albedo.iter().any(|a| !(0.0..=1.0).contains(a)) {
+                return Err(format!("medium albedo must lie between 0 and 1, got {:?}", density.albedo));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn coefficients_scale_with_scene_units()
+    {
+        let fog = MediumDesc {
+            absorption: [0.5, 1.0, 1.5],
+            scattering: [2.0; 3],
+            anisotropy: 0.3,
+            density: None,
+        };
+        let medium = fog.to_medium(0.001);
+        let close = |a: [f32; 3], b: [f32; 3]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-7);
+        assert!(close(medium.sigma_t, [0.0025, 0.003, 0.0035]), "{:?}", medium.sigma_t);
+        assert!(close(medium.sigma_s, [0.002; 3]), "{:?}", medium.sigma_s);
+        assert_eq!(medium.g, 0.3);
+        assert!(fog.validate().is_ok());
+        assert!(MediumDesc { anisotropy: 1.0, ..fog.clone() }.validate().is_err());
+        assert!(MediumDesc { absorption: [-1.0; 3], ..fog }.validate().is_err());
+    }
+
+    #[test]
+    fn grid_sets_majorant()
+    {
+        let smoke = MediumDesc {
+            absorption: [0.0; 3],
+            scattering: [0.0; 3],
+            anisotropy: 0.0,
+            density: Some(DensityDesc {
+                grid: GridSource::Cloud { resolution: 1, seed: 0, frequency: 1.0 },
+                density_scale: 50.0,
+                albedo: [0.5, 0.5, 1.0],
+            }),
+        };
+        let grid = Grid {
+            size: [2, 1, 1],
+            data: vec![0.5, 2.0],
+        };
+        let medium = smoke.to_medium(0.1).with_grid(&grid, 3, [[0.0; 3], [1.0; 3]]);
+        assert_eq!(medium.sigma_t, [5.0; 3]);
+        assert_eq!(medium.sigma_s, [2.5, 2.5, 5.0]);
+        assert_eq!(medium.majorant, 10.0);
+        assert_eq!(medium.grid_offset, 3);
+    }
+}
@@ -1,22 +1,176 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::camera::{Camera, Projection, SensorFormat};
+use crate::color::ColorDesc;
+use crate::exposure::Exposure;
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+use crate::post::PostDesc;
+
+mod animation;
+mod grid;
+mod ies;
+mod light;
+mod medium;
+mod sky;
+mod texture;
+pub use animation::Animation;
+pub use grid::Grid;
+pub use light::{Emission, Light, LightDesc, LUMENS_PER_WATT};
+pub use medium::{Medium, MediumDesc};
+pub use sky::{SkyDesc, SkyUniform};
+pub use texture::{Texture, TextureDesc};
+
+/// Capacities of the lists in [`Geometry`] and [`Shading`], matching the `MAX_*` constants
+/// in the shader.
+pub const MAX_SPHERES: usize = 1024;
+pub const MAX_PLANES: usize = 64;
+pub const MAX_QUADS: usize = 1024;
+pub const MAX_BOXES: usize = 1024;
+pub const MAX_LIGHTS: usize = 64;
+pub const MAX_MATERIALS: usize = 256;
+pub const MAX_MEDIA: usize = 64;
+pub const MAX_TEXTURES: usize = 64;
+
+/// Sphere with an optional medium inside.
+///
+/// A sphere with a medium is a boundary rather than a solid: rays pass through
+/// its surface, except for the material's clean coat, which still reflects.
+///
+/// Spheres, quads and boxes move by `motion` while the shutter is open, which blurs them.
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
 pub struct Sphere
 {
     radius: f32,
     material_idx: u32,
-    _padding1: [u32; 2],
+    #[serde(skip)]
+    _padding: [u32; 2],
     center: [f32; 3],
+    /// Index into `Scene::media`, or -1 for a solid sphere.
+    #[serde(default = "no_medium")]
+    medium_idx: i32,
+    #[serde(default)]
+    motion: [f32; 3],
+    #[serde(skip)]
+    _padding2: u32,
+}
+
+/// Infinite plane of points `p` with `dot(normal, p) == offset`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
+pub struct Plane
+{
+    normal: [f32; 3],
+    offset: f32,
+    material_idx: u32,
+    #[serde(skip)]
+    _padding: [u32; 3],
+}
+
+/// Parallelogram spanned by `edge_u` and `edge_v` from `corner`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
+pub struct Quad
+{
+    corner: [f32; 3],
+    material_idx: u32,
+    edge_u: [f32; 3],
+    #[serde(skip)]
+    _padding1: u32,
+    edge_v: [f32; 3],
+    #[serde(skip)]
     _padding2: u32,
+    #[serde(default)]
+    motion: [f32; 3],
+    #[serde(skip)]
+    _padding3: u32,
 }
 
+/// Axis aligned box, optionally filled with a medium like [`Sphere`].
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
+pub struct Aabb
+{
+    min_corner: [f32; 3],
+    material_idx: u32,
+    max_corner: [f32; 3],
+    /// Index into `Scene::media`, or -1 for a solid box.
+    #[serde(default = "no_medium")]
+    medium_idx: i32,
+    #[serde(default)]
+    motion: [f32; 3],
+    #[serde(skip)]
+    _padding: u32,
+}
+
+/// Surface parameters; each `*_texture` is an index into `Scene::textures` that
+/// replaces the constant, or -1 to keep it. Clean coat textures use their red channel.
+///
+/// A positive `ior` or any Sellmeier `B` term makes the surface smooth glass tinted by
+/// `color`. Its index at a wavelength λ in µm is `ior + dispersion · (1/λ² − 1/0.5893²)`,
+/// Cauchy's equation pinned to `ior` at the sodium D line, unless the Sellmeier terms
+/// are set, in which case `n² = 1 + Σ Bᵢλ² / (λ² − Cᵢ)`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
 pub struct Material
 {
     color: [f32; 3],
-    _padding1: u32,
+    #[serde(default = "no_texture")]
+    color_texture: i32,
     emission: [f32; 3],
     clean_coat: f32,
+    #[serde(default = "no_texture")]
+    emission_texture: i32,
+    #[serde(default = "no_texture")]
+    clean_coat_texture: i32,
+    #[serde(default)]
+    ior: f32,
+    #[serde(default)]
+    dispersion: f32,
+    #[serde(default)]
+    sellmeier_b: [f32; 3],
+    #[serde(skip)]
+    _padding1: u32,
+    #[serde(default)]
+    sellmeier_c: [f32; 3],
+    #[serde(skip)]
+    _padding2: u32,
+}
+
+/// Every primitive and light of a scene with the tables they are sampled from, which the
+/// shader takes in a single storage buffer.
+///
+/// The counts say how many entries of each list are used. Empty lists still count their
+/// first, zeroed entry, which is degenerate and never intersected, so that the shader never
+/// picks from an empty list.
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Geometry
+{
+    sphere_count: u32,
+    plane_count: u32,
+    quad_count: u32,
+    box_count: u32,
+    light_count: u32,
+    _padding: [u32; 3],
+    spheres: [Sphere; MAX_SPHERES],
+    planes: [Plane; MAX_PLANES],
+    quads: [Quad; MAX_QUADS],
+    boxes: [Aabb; MAX_BOXES],
+    lights: [Light; MAX_LIGHTS],
+    /// [`ies::IES_SAMPLES`] values for every spot light with a profile, in light order.
+    ies_profiles: [f32; MAX_LIGHTS * ies::IES_SAMPLES],
+    sky_distribution: [f32; sky::DISTRIBUTION_SIZE],
+}
+
+/// Materials, media and textures of a scene in a single storage buffer like [`Geometry`].
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Shading
+{
+    materials: [Material; MAX_MATERIALS],
+    media: [Medium; MAX_MEDIA],
+    textures: [Texture; MAX_TEXTURES],
 }
 
 impl Sphere
@@ -26,11 +180,72 @@ impl Sphere
         Self {
             radius,
             material_idx,
-            _padding1: [0; 2],
+            _padding: [0; 2],
             center,
+            medium_idx: -1,
+            motion: [0.0; 3],
             _padding2: 0,
         }
     }
+    fn validate(&self) -> Result<(), String>
+    {
+        if self.radius.is_nan() || self.radius <= 0.0 {
+            return Err("sphere radius must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+impl Plane
+{
+    pub const fn new(normal: [f32; 3], offset: f32, material_idx: u32) -> Self
+    {
+        Self {
+            normal,
+            offset,
+            material_idx,
+            _padding: [0; 3],
+        }
+    }
+    fn validate(&self) -> Result<(), String>
+    {
+        let length_squared = length_squared(self.normal);
+        if length_squared.is_nan() || length_squared <= 0.0 {
+            return Err("plane normal must not be zero".into());
+        }
+        Ok(())
+    }
+}
+
+impl Quad
+{
+    fn validate(&self) -> Result<(), String>
+    {
+        let [u, v] = [self.edge_u, self.edge_v];
+        let normal = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+        let length_squared = length_squared(normal);
+        if length_squared.is_nan() || length_squared <= 0.0 {
+            return Err("quad edges must not be zero or parallel".into());
+        }
+        Ok(())
+    }
+}
+
+impl Aabb
+{
+    fn validate(&self) -> Result<(), String>
+    {
+        let (min, max) = (self.min_corner, self.max_corner);
+        if (0..3).any(|i| min[i].is_nan() || max[i].is_nan() || min[i] > max[i]) {
+            return Err("box min_corner must not exceed max_corner".into());
+        }
+        Ok(())
+    }
+}
+
+fn length_squared(v: [f32; 3]) -> f32
+{
+    v.iter().map(|x| x * x).sum()
 }
 
 impl Material
@@ -39,26 +254,692 @@ impl Material
     {
         Self {
             color,
-            _padding1: 0,
+            color_texture: -1,
             emission,
             clean_coat,
+            emission_texture: -1,
+            clean_coat_texture: -1,
+            ior: 0.0,
+            dispersion: 0.0,
+            sellmeier_b: [0.0; 3],
+            _padding1: 0,
+            sellmeier_c: [0.0; 3],
+            _padding2: 0,
         }
     }
+    /// The material with its sRGB colours converted into the working space.
+    pub fn in_working_space(mut self, color: &ColorDesc) -> Self
+    {
+        self.color = color.to_working(self.color);
+        self.emission = color.to_working(self.emission);
+        self
+    }
+    fn validate(&self) -> Result<(), String>
+    {
+        if self.ior.is_nan() || self.ior < 0.0 {
+            return Err("material ior must not be negative".into());
+        }
+        if self.sellmeier_c.iter().any(|c| c.is_nan() || *c < 0.0) {
+            return Err("Sellmeier C terms must not be negative".into());
+        }
+        Ok(())
+    }
+}
+
+impl GpuLayout for Sphere
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Sphere { radius, material_idx, center, medium_idx, motion })
+    }
+}
+
+impl GpuLayout for Plane
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Plane { normal, offset, material_idx })
+    }
+}
+
+impl GpuLayout for Quad
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Quad { corner, material_idx, edge_u, edge_v, motion })
+    }
+}
+
+impl GpuLayout for Aabb
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Aabb { min_corner, material_idx, max_corner, medium_idx, motion })
+    }
+}
+
+impl GpuLayout for Material
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Material {
+            color, color_texture, emission, clean_coat, emission_texture, clean_coat_texture,
+            ior, dispersion, sellmeier_b, sellmeier_c,
+        })
+    }
+}
+
+impl GpuLayout for Geometry
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Geometry {
+            sphere_count, plane_count, quad_count, box_count, light_count,
+            spheres, planes, quads, boxes, lights, ies_profiles, sky_distribution,
+        })
+    }
+}
+
+impl GpuLayout for Shading
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Shading { materials, media, textures })
+    }
 }
 
 pub const SPHERES: &[Sphere] = &[
-    Sphere::new(10000.0, [275.0, -10000.0, 275.0], 0), // floor
-    Sphere::new(10000.0, [275.0, 10550.0, 275.0], 0), // ceiling
-    Sphere::new(10000.0, [275.0, 275.0, 10550.0], 0), // back wall
-    Sphere::new(10000.0, [10550.0, 275.0, 275.0], 1), // left
-    Sphere::new(10000.0, [-10000.0, 275.0, 275.0], 2), // right
-    Sphere::new(5000.0, [275.0, 5549.5, 275.0], 3), // light
-    Sphere::new(100.0, [275.0, 100.0, 275.0], 4), // ball
+    Sphere::new(100.0, [275.0, 100.0, 275.0], 3), // ball
+];
+pub const PLANES: &[Plane] = &[
+    Plane::new([0.0, 1.0, 0.0], 0.0, 0), // floor
+    Plane::new([0.0, -1.0, 0.0], -550.0, 0), // ceiling
+    Plane::new([0.0, 0.0, -1.0], -550.0, 0), // back wall
+    Plane::new([-1.0, 0.0, 0.0], -550.0, 1), // left
+    Plane::new([1.0, 0.0, 0.0], 0.0, 2), // right
+];
+pub const QUADS: &[Quad] = &[];
+pub const BOXES: &[Aabb] = &[];
+// same area as the cap the old radius 5000 light sphere pushed through the ceiling
+pub const LIGHTS: &[LightDesc] = &[
+    LightDesc::Rectangle {
+        corner: [212.35, 549.5, 212.35],
+        edge_u: [125.3, 0.0, 0.0],
+        edge_v: [0.0, 0.0, 125.3],
+        emission: Emission::Rgb([31.415926; 3]),
+        two_sided: false,
+    },
 ];
 pub const MATERIALS: &[Material] = &[
     Material::new([0.725, 0.71, 0.68], [0.0; 3], 0.0), // white
     Material::new([0.63, 0.065, 0.05], [0.0; 3], 0.0), // red
     Material::new([0.08, 0.12, 0.75], [0.0; 3], 0.0), // blue
-    Material::new([0.0; 3], [10.0; 3], 0.0), // light
     Material::new([0.88, 0.55, 0.08], [0.0; 3], 0.2), // glossy yellow
-];
\ No newline at end of file
+];
+
+/// Scene description as stored in a `.ron` scene file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scene
+{
+    #[serde(default)]
+    pub spheres: Vec<Sphere>,
+    #[serde(default)]
+    pub planes: Vec<Plane>,
+    #[serde(default)]
+    pub quads: Vec<Quad>,
+    #[serde(default)]
+    pub boxes: Vec<Aabb>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    /// Daylight for rays that leave the scene; also adds the sun as a light.
+    #[serde(default)]
+    pub sky: Option<SkyDesc>,
+    pub materials: Vec<Material>,
+    /// Textures that material parameters can reference.
+    #[serde(default)]
+    pub textures: Vec<TextureDesc>,
+    /// Media that spheres can be filled with.
+    #[serde(default)]
+    pub media: Vec<MediumDesc>,
+    /// Medium filling all space outside of spheres with a medium.
+    #[serde(default)]
+    pub fog: Option<MediumDesc>,
+    /// Length of one scene unit, used to resolve physical light units;
+    /// a millimetre unless given.
+    #[serde(default = "default_meters_per_unit")]
+    pub meters_per_unit: f32,
+    #[serde(default)]
+    pub camera: CameraDesc,
+    /// Effects applied to the image before it is shown.
+    #[serde(default)]
+    pub post: PostDesc,
+    /// Working and display colour spaces.
+    #[serde(default)]
+    pub color: ColorDesc,
+    /// Keyframes for the camera, spheres and materials.
+    #[serde(default)]
+    pub animation: Option<Animation>,
+    /// Spot light IES profiles in light order, [`ies::IES_SAMPLES`] values each.
+    #[serde(skip)]
+    pub ies_profiles: Vec<f32>,
+    /// Density grids of heterogeneous media, in medium order.
+    #[serde(skip)]
+    pub grids: Vec<Grid>,
+    /// Pixels of the image textures, in texture order.
+    #[serde(skip)]
+    pub images: Vec<image::RgbaImage>,
+}
+
+fn default_meters_per_unit() -> f32
+{
+    0.001
+}
+
+/// How the scene is viewed, which the camera takes on when the scene loads.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraDesc
+{
+    #[serde(default)]
+    pub projection: Projection,
+    /// Height of the view in scene units with the orthographic projection.
+    #[serde(default = "default_orthographic_height")]
+    pub orthographic_height: f32,
+    /// Sensor size, which otherwise stays square.
+    #[serde(default)]
+    pub sensor: Option<SensorFormat>,
+    #[serde(default)]
+    pub exposure: Option<Exposure>,
+    #[serde(default)]
+    pub auto_exposure: bool,
+    #[serde(default)]
+    pub vignetting: bool,
+}
+
+impl Default for CameraDesc
+{
+    fn default() -> Self
+    {
+        Self {
+            projection: Projection::default(),
+            orthographic_height: default_orthographic_height(),
+            sensor: None,
+            exposure: None,
+            auto_exposure: false,
+            vignetting: false,
+        }
+    }
+}
+
+impl CameraDesc
+{
+    /// Width and height of the sensor in meters.
+    pub fn sensor_size(&self) -> [f32; 2]
+    {
+        match self.sensor {
+            Some(sensor) => sensor.size(),
+            None => {
+                let camera = Camera::default();
+                [camera.width, camera.height]
+            }
+        }
+    }
+    pub fn apply(&self, camera: &mut Camera)
+    {
+        camera.projection = self.projection;
+        camera.orthographic_height = self.orthographic_height;
+        [camera.width, camera.height] = self.sensor_size();
+        camera.exposure = self.exposure;
+        camera.auto_exposure = self.auto_exposure;
+        camera.vignetting = self.vignetting;
+    }
+}
+
+// frames the Cornell box
+fn default_orthographic_height() -> f32
+{
+    600.0
+}
+
+/// Copies `items` to the front of `list`, returning the count the shader uses for it.
+fn fill<T: Copy>(list: &mut [T], items: &[T]) -> u32
+{
+    list[..items.len()].copy_from_slice(items);
+    items.len().max(1) as u32
+}
+
+fn no_medium() -> i32
+{
+    -1
+}
+
+fn no_texture() -> i32
+{
+    -1
+}
+
+impl Default for Scene
+{
+    fn default() -> Self
+    {
+        Self {
+            spheres: SPHERES.to_vec(),
+            planes: PLANES.to_vec(),
+            quads: QUADS.to_vec(),
+            boxes: BOXES.to_vec(),
+            lights: LIGHTS.to_vec(),
+            sky: None,
+            materials: MATERIALS.to_vec(),
+            textures: Vec::new(),
+            media: Vec::new(),
+            fog: None,
+            meters_per_unit: 0.001,
+            camera: CameraDesc::default(),
+            post: PostDesc::default(),
+            color: ColorDesc::default(),
+            animation: None,
+            ies_profiles: Vec::new(),
+            grids: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+}
+
+impl Scene
+{
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>>
+    {
+        let source = std::fs::read_to_string(path)?;
+        let mut scene: Scene = ron::from_str(&source)?;
+        scene.validate()?;
+        let directory = path.parent().unwrap_or(Path::new("."));
+        for light in &scene.lights {
+            if let LightDesc::Spot { profile: Some(profile), .. } = light {
+                scene.ies_profiles.extend(ies::load_profile(&directory.join(profile))?);
+            }
+        }
+        for medium in &scene.media {
+            if let Some(ref density) = medium.density {
+                scene.grids.push(Grid::load(&density.grid, directory)?);
+            }
+        }
+        for texture in &scene.textures {
+            if let TextureDesc::Image { ref path, .. } = *texture {
+                scene.images.push(texture::load_image(&directory.join(path))?);
+            }
+        }
+        let depth = scene.grids.iter().map(|grid| grid.size[2]).sum::<u32>();
+        if depth > grid::MAX_ATLAS_DEPTH {
+            return Err(format!("density grids are {} voxels deep together, at most {} fit", depth, grid::MAX_ATLAS_DEPTH).into());
+        }
+        Ok(scene)
+    }
+    pub fn gpu_lights(&self) -> Vec<Light>
+    {
+        let mut profile_count = 0;
+        self.lights.iter()
+            .map(|light| {
+                let profile_idx = match light {
+                    LightDesc::Spot { profile: Some(_), .. } => {
+                        profile_count += 1;
+                        profile_count - 1
+                    }
+                    _ => -1,
+                };
+                light.to_light(self.meters_per_unit, profile_idx)
+            })
+            .chain(self.sky.iter().map(|sky| sky.sun_light().to_light(self.meters_per_unit, -1)))
+            .map(|light| light.in_working_space(&self.color))
+            .collect()
+    }
+    pub fn gpu_textures(&self) -> Vec<Texture>
+    {
+        let mut layer_count = 0;
+        self.textures.iter()
+            .map(|texture| {
+                let layer = match texture {
+                    TextureDesc::Image { .. } => {
+                        layer_count += 1;
+                        layer_count - 1
+                    }
+                    _ => -1,
+                };
+                texture.to_texture(layer).in_working_space(&self.color)
+            })
+            .collect()
+    }
+    pub fn gpu_materials(&self) -> Vec<Material>
+    {
+        self.materials.iter().map(|material| material.in_working_space(&self.color)).collect()
+    }
+    /// Image textures as layers of one size, RGBA8.
+    pub fn image_array(&self) -> ([u32; 3], Vec<u8>)
+    {
+        texture::image_array(&self.images)
+    }
+    pub fn sky_uniform(&self) -> SkyUniform
+    {
+        self.sky.as_ref().map_or_else(SkyUniform::disabled, SkyDesc::uniform)
+    }
+    pub fn sky_distribution(&self) -> Vec<f32>
+    {
+        self.sky.as_ref().map(|sky| sky.uniform().distribution()).unwrap_or_default()
+    }
+    pub fn gpu_geometry(&self) -> Box<Geometry>
+    {
+        let mut geometry: Box<Geometry> = bytemuck::zeroed_box();
+        geometry.sphere_count = fill(&mut geometry.spheres, &self.spheres);
+        geometry.plane_count = fill(&mut geometry.planes, &self.planes);
+        geometry.quad_count = fill(&mut geometry.quads, &self.quads);
+        geometry.box_count = fill(&mut geometry.boxes, &self.boxes);
+        geometry.light_count = fill(&mut geometry.lights, &self.gpu_lights());
+        fill(&mut geometry.ies_profiles, &self.ies_profiles);
+        fill(&mut geometry.sky_distribution, &self.sky_distribution());
+        geometry
+    }
+    pub fn gpu_shading(&self) -> Box<Shading>
+    {
+        let mut shading: Box<Shading> = bytemuck::zeroed_box();
+        fill(&mut shading.materials, &self.gpu_materials());
+        fill(&mut shading.media, &self.gpu_media());
+        fill(&mut shading.textures, &self.gpu_textures());
+        shading
+    }
+    /// The fog, or vacuum without one, followed by `media`, so that the shader
+    /// finds a primitive's medium at `medium_idx + 1`.
+    pub fn gpu_media(&self) -> Vec<Medium>
+    {
+        let fog = self.fog.as_ref().map_or(Medium::VACUUM, |fog| fog.to_medium(self.meters_per_unit));
+        let mut grids = self.grids.iter();
+        let mut offset = 0;
+        let media = self.media.iter().enumerate().map(|(i, desc)| {
+            let medium = desc.to_medium(self.meters_per_unit);
+            let Some(grid) = desc.density.as_ref().and_then(|_| grids.next()) else {
+                return medium;
+            };
+            offset += grid.size[2];
+            medium.with_grid(grid, offset - grid.size[2], self.medium_bounds(i as i32))
+        });
+        std::iter::once(fog).chain(media).collect()
+    }
+    /// Density grids stacked along z into one volume, the size of the largest slice.
+    pub fn grid_atlas(&self) -> ([u32; 3], Vec<f32>)
+    {
+        if self.grids.is_empty() {
+            return ([1; 3], vec![0.0]);
+        }
+        let width = self.grids.iter().map(|grid| grid.size[0]).max().unwrap_or(1);
+        let height = self.grids.iter().map(|grid| grid.size[1]).max().unwrap_or(1);
+        let depth = self.grids.iter().map(|grid| grid.size[2]).sum();
+        let mut data = Vec::with_capacity((width * height * depth) as usize);
+        for grid in &self.grids {
+            let [x, y, z] = grid.size.map(|n| n as usize);
+            for slice in grid.data.chunks_exact(x * y).take(z) {
+                for row in slice.chunks_exact(x) {
+                    data.extend(row);
+                    data.resize(data.len() + width as usize - x, 0.0);
+                }
+                data.resize(data.len() + (height as usize - y) * width as usize, 0.0);
+            }
+        }
+        ([width, height, depth], data)
+    }
+    /// Bounding box of the spheres and boxes filled with medium `medium_idx`.
+    fn medium_bounds(&self, medium_idx: i32) -> [[f32; 3]; 2]
+    {
+        let spheres = self.spheres.iter()
+            .filter(|sphere| sphere.medium_idx == medium_idx)
+            .map(|sphere| [sphere.center.map(|c| c - sphere.radius), sphere.center.map(|c| c + sphere.radius)]);
+        let boxes = self.boxes.iter()
+            .filter(|aabb| aabb.medium_idx == medium_idx)
+            .map(|aabb| [aabb.min_corner, aabb.max_corner]);
+        spheres.chain(boxes)
+            .reduce(|[min_a, max_a], [min_b, max_b]| [
+                std::array::from_fn(|i| min_a[i].min(min_b[i])),
+                std::array::from_fn(|i| max_a[i].max(max_b[i])),
+            ])
+            .unwrap_or([[0.0; 3]; 2])
+    }
+    /// Moves the camera and changes the scene to how the animation has them at `time` seconds;
+    /// whatever has no keyframes stays as it is.
+    ///
+    /// Animated spheres and the camera also get where they are when the shutter closes,
+    /// `camera.shutter` frames later, for motion blur.
+    pub fn animate(&mut self, time: f32, camera: &mut Camera)
+    {
+        let Some(animation) = &self.animation else {
+            return;
+        };
+        let close = time + camera.shutter / animation.fps;
+        let track = &animation.camera;
+        let pose = camera.pose();
+        camera.set_pose(track.pose(time, pose));
+        camera.shutter_close = track.is_animated().then(|| track.pose(close, pose));
+        for track in &animation.spheres {
+            let sphere = &mut self.spheres[track.index];
+            sphere.center = animation::sample(&track.center, time).unwrap_or(sphere.center);
+            sphere.radius = animation::sample(&track.radius, time).unwrap_or(sphere.radius);
+            if let Some(center) = animation::sample(&track.center, close) {
+                sphere.motion = std::array::from_fn(|i| center[i] - sphere.center[i]);
+            }
+        }
+        for track in &animation.materials {
+            let material = &mut self.materials[track.index];
+            material.color = animation::sample(&track.color, time).unwrap_or(material.color);
+            material.emission = animation::sample(&track.emission, time).unwrap_or(material.emission);
+            material.clean_coat = animation::sample(&track.clean_coat, time).unwrap_or(material.clean_coat);
+        }
+    }
+    fn validate(&self) -> Result<(), String>
+    {
+        if let Some(animation) = &self.animation {
+            animation.validate(self.spheres.len(), self.materials.len())?;
+        }
+        if let Some(exposure) = &self.camera.exposure {
+            exposure.validate()?;
+        }
+        self.post.validate()?;
+        self.color.validate()?;
+        let lists = [
+            ("spheres", self.spheres.len(), MAX_SPHERES),
+            ("planes", self.planes.len(), MAX_PLANES),
+            ("quads", self.quads.len(), MAX_QUADS),
+            ("boxes", self.boxes.len(), MAX_BOXES),
+            // the sky's sun takes a light, and the fog, or vacuum, the first medium
+            ("lights", self.lights.len(), MAX_LIGHTS - self.sky.is_some() as usize),
+            ("materials", self.materials.len(), MAX_MATERIALS),
+            ("media", self.media.len(), MAX_MEDIA - 1),
+            ("textures", self.textures.len(), MAX_TEXTURES),
+        ];
+        for (name, count, capacity) in lists {
+            if count > capacity {
+                return Err(format!("scene has {} {}, at most {} fit", count, name, capacity));
+            }
+        }
+        for light in &self.lights {
+            light.validate()?;
+        }
+        for medium in self.media.iter().chain(&self.fog) {
+            medium.validate()?;
+        }
+        if self.fog.as_ref().is_some_and(|fog| fog.density.is_some()) {
+            return Err("fog cannot have a density grid".into());
+        }
+        let medium_indices = self.spheres.iter().map(|s| s.medium_idx)
+            .chain(self.boxes.iter().map(|b| b.medium_idx));
+        for medium_idx in medium_indices {
+            if medium_idx < -1 || medium_idx >= self.media.len() as i32 {
+                return Err(format!("primitive references missing medium {}", medium_idx));
+            }
+        }
+        for sphere in &self.spheres {
+            sphere.validate()?;
+        }
+        for plane in &self.planes {
+            plane.validate()?;
+        }
+        for quad in &self.quads {
+            quad.validate()?;
+        }
+        for aabb in &self.boxes {
+            aabb.validate()?;
+        }
+        if self.materials.is_empty() {
+            return Err("scene needs at least one material".into());
+        }
+        for material in &self.materials {
+            material.validate()?;
+        }
+        let texture_indices = self.materials.iter()
+            .flat_map(|m| [m.color_texture, m.emission_texture, m.clean_coat_texture]);
+        for texture_idx in texture_indices {
+            if texture_idx < -1 || texture_idx >= self.textures.len() as i32 {
+                return Err(format!("material references missing texture {}", texture_idx));
+            }
+        }
+        let material_indices = self.spheres.iter().map(|s| s.material_idx)
+            .chain(self.planes.iter().map(|p| p.material_idx))
+            .chain(self.quads.iter().map(|q| q.material_idx))
+            .chain(self.boxes.iter().map(|b| b.material_idx));
+        for material_idx in material_indices {
+            if material_idx as usize >= self.materials.len() {
+                return Err(format!("primitive references missing material {}", material_idx));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn bundled_scenes_load()
+    {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some("ron".as_ref()) {
+                continue;
+            }
+            if let Err(e) = Scene::load(&path) {
+                panic!("{}: {}", path.display(), e);
+            }
+        }
+    }
+
+    #[test]
+    fn shutter_spans_motion()
+    {
+        let mut scene = Scene::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/animated.ron")).unwrap();
+        let mut camera = Camera { position: (0.0, 0.0, 0.0).into(), ..Default::default() };
+        scene.animate(0.5, &mut camera);
+        assert_eq!(scene.spheres[0].motion, [0.0; 3]);
+        assert_eq!(camera.raygen_close_matrix(), camera.raygen_matrix());
+        camera.shutter = 0.5;
+        scene.animate(0.5, &mut camera);
+        let close = camera.shutter_close.unwrap();
+        let mut closed = scene.clone();
+        closed.animate(0.5 + 0.5 / 24.0, &mut camera);
+        let motion: [f32; 3] = std::array::from_fn(|i| closed.spheres[0].center[i] - scene.spheres[0].center[i]);
+        assert_eq!(scene.spheres[0].motion, motion);
+        assert!(motion != [0.0; 3]);
+        assert_eq!(close.position, camera.position);
+    }
+
+    #[test]
+    fn rejects_degenerate_primitives()
+    {
+        let valid = |scene: &Scene| scene.validate().is_ok();
+        let mut scene = Scene::default();
+        scene.spheres.push(Sphere::new(0.0, [0.0; 3], 0));
+        assert!(!valid(&scene));
+        scene.spheres.clear();
+        scene.planes.push(Plane::new([0.0; 3], 0.0, 0));
+        assert!(!valid(&scene));
+        scene.planes.clear();
+        let quad = Quad { edge_u: [1.0, 0.0, 0.0], edge_v: [0.0, 1.0, 0.0], ..Quad::zeroed() };
+        scene.quads.push(quad);
+        assert!(valid(&scene));
+        scene.quads[0].edge_v = [2.0, 0.0, 0.0];
+        assert!(!valid(&scene));
+        scene.quads.clear();
+        scene.boxes.push(Aabb { min_corner: [0.0, 1.0, 0.0], max_corner: [1.0, 0.0, 1.0], ..Aabb::zeroed() });
+        assert!(!valid(&scene));
+    }
+
+    #[test]
+    fn rejects_more_primitives_than_fit()
+    {
+        let mut scene = Scene { spheres: vec![Sphere::new(1.0, [0.0; 3], 0); MAX_SPHERES], ..Scene::default() };
+        assert!(scene.validate().is_ok());
+        let geometry = scene.gpu_geometry();
+        assert_eq!(geometry.sphere_count, MAX_SPHERES as u32);
+        // the Cornell box has no quads, which still count their zeroed first entry
+        assert_eq!(geometry.quad_count, 1);
+        scene.spheres.push(Sphere::new(1.0, [0.0; 3], 0));
+        assert!(scene.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_missing_material()
+    {
+        let mut scene = Scene::default();
+        scene.spheres.push(Sphere::new(1.0, [0.0; 3], 99));
+        assert!(scene.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_missing_medium()
+    {
+        let mut scene = Scene::default();
+        scene.spheres[0].medium_idx = 0;
+        assert!(scene.validate().is_err());
+        scene.media.push(MediumDesc {
+            absorption: [0.0; 3],
+            scattering: [1.0; 3],
+            anisotropy: 0.0,
+            density: None,
+        });
+        assert!(scene.validate().is_ok());
+        assert_eq!(scene.gpu_media()[0], Medium::VACUUM);
+    }
+
+    #[test]
+    fn rejects_missing_texture()
+    {
+        let mut scene = Scene::default();
+        scene.materials[0].color_texture = 0;
+        assert!(scene.validate().is_err());
+        scene.textures.push(TextureDesc::Checker {
+            even: [0.0; 3],
+            odd: [1.0; 3],
+            scale: [0.01; 2],
+        });
+        assert!(scene.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_negative_ior()
+    {
+        let mut scene = Scene::default();
+        scene.materials[0].ior = -1.5;
+        assert!(scene.validate().is_err());
+        scene.materials[0].ior = 1.5;
+        scene.materials[0].sellmeier_c = [0.006, -0.02, 103.56];
+        assert!(scene.validate().is_err());
+    }
+
+    #[test]
+    fn grid_atlas_stacks_grids()
+    {
+        let mut scene = Scene::default();
+        scene.grids.push(Grid { size: [2, 2, 1], data: vec![1.0, 2.0, 3.0, 4.0] });
+        scene.grids.push(Grid { size: [1, 1, 2], data: vec![5.0, 6.0] });
+        let (size, data) = scene.grid_atlas();
+        assert_eq!(size, [2, 2, 3]);
+        assert_eq!(data, [1.0, 2.0, 3.0, 4.0, 5.0, 0.0, 0.0, 0.0, 6.0, 0.0, 0.0, 0.0]);
+    }
+}
\ No newline at end of file
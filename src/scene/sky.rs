@@ -0,0 +1,306 @@
+use std::f32::consts::PI;
+use serde::{Deserialize, Serialize};
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+use super::light::{Emission, LightDesc};
+
+/// Resolution of the equirectangular grid the sky is importance sampled from.
+pub const SKY_WIDTH: usize = 128;
+pub const SKY_HEIGHT: usize = 64;
+/// Entries of [`SkyUniform::distribution`].
+pub const DISTRIBUTION_SIZE: usize = 2 * SKY_WIDTH * SKY_HEIGHT + SKY_HEIGHT;
+
+/// Angular radius of the sun as seen from the earth, in degrees.
+const SUN_ANGULAR_RADIUS: f32 = 0.2665;
+
+/// Preetham et al., "A Practical Analytic Model for Daylight", 1999.
+///
+/// Angles are in degrees, with y up and an azimuth of zero looking along +z.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SkyDesc
+{
+    pub elevation: f32,
+    pub azimuth: f32,
+    #[serde(default = "default_turbidity")]
+    pub turbidity: f32,
+    #[serde(default = "default_ground_albedo")]
+    pub ground_albedo: [f32; 3],
+    /// Scale on sky and sun together.
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+}
+
+fn default_turbidity() -> f32
+{
+    3.0
+}
+
+fn default_ground_albedo() -> [f32; 3]
+{
+    [0.3; 3]
+}
+
+fn default_intensity() -> f32
+{
+    1.0
+}
+
+/// Precomputed model parameters, bound as the `sky` uniform.
+///
+/// `perez` holds the A to E distribution coefficients for Y, x and y, and
+/// `zenith` is already divided by the distribution at the zenith, so that the
+/// sky is `zenith * F(theta, gamma)` in Yxy.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SkyUniform
+{
+    sun_direction: [f32; 3],
+    enabled: u32,
+    perez: [[f32; 4]; 5],
+    zenith: [f32; 3],
+    cos_sun_radius: f32,
+    sun_radiance: [f32; 3],
+    _padding1: u32,
+    ground_radiance: [f32; 3],
+    _padding2: u32,
+}
+
+impl GpuLayout for SkyUniform
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(SkyUniform { sun_direction, enabled, perez, zenith, cos_sun_radius, sun_radiance, ground_radiance })
+    }
+}
+
+impl SkyDesc
+{
+    pub fn sun_direction(&self) -> [f32; 3]
+    {
+        let elevation = self.elevation.to_radians();
+        let azimuth = self.azimuth.to_radians();
+        [elevation.cos() * azimuth.sin(), elevation.sin(), elevation.cos() * azimuth.cos()]
+    }
+    /// Sun irradiance on a surface facing it, attenuated by Rayleigh and aerosol
+    /// scattering along the air mass at the current elevation.
+    pub fn sun_irradiance(&self) -> [f32; 3]
+    {
+        if self.elevation <= 0.0 {
+            return [0.0; 3];
+        }
+        // Kasten and Young
+        let zenith_angle = 90.0 - self.elevation;
+        let air_mass = 1.0 / (zenith_angle.to_radians().cos() + 0.50572 * (96.07995 - zenith_angle).powf(-1.6364));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        // extraterrestrial illuminance of 128 klx, as luminance-weighted watts
+        let outside = 128000.0 / 683.0 * self.intensity;
+        [0.68f32, 0.55, 0.44].map(|wavelength| {
+            let rayleigh = 0.008735 * wavelength.powf(-4.08);
+            let aerosol = beta * wavelength.powf(-1.3);
+            outside * (-(rayleigh + aerosol) * air_mass).exp()
+        })
+    }
+    /// The sun as a directional light, so shadow rays sample it like any other.
+    pub fn sun_light(&self) -> LightDesc
+    {
+        let [x, y, z] = self.sun_direction();
+        LightDesc::Directional {
+            direction: [-x, -y, -z],
+            emission: Emission::Rgb(self.sun_irradiance()),
+            angular_radius: SUN_ANGULAR_RADIUS,
+        }
+    }
+    pub fn uniform(&self) -> SkyUniform
+    {
+        let t = self.turbidity;
+        let theta_s = (90.0 - self.elevation.clamp(0.0, 90.0)).to_radians();
+        let perez = [
+            [0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608, 0.0],
+            [-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092, 0.0],
+            [-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102, 0.0],
+            [0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537, 0.0],
+            [-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529, 0.0],
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        // kcd/m² to luminance-weighted W/(sr m²)
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192) * 1000.0 / 683.0 * self.intensity;
+        let (t1, t2, t3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let x = t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1)
+            + t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394)
+            + (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+        let y = t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1)
+            + t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516)
+            + (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+        let zenith_distribution = perez_distribution(&perez, 1.0, theta_s);
+        let sun_irradiance = self.sun_irradiance();
+        let cos_sun_radius = SUN_ANGULAR_RADIUS.to_radians().cos();
+        let sun_solid_angle = 2.0 * PI * (1.0 - cos_sun_radius);
+        let mut uniform = SkyUniform {
+            sun_direction: self.sun_direction(),
+            enabled: 1,
+            perez,
+            zenith: [
+                luminance.max(0.0) / zenith_distribution[0],
+                x / zenith_distribution[1],
+                y / zenith_distribution[2],
+            ],
+            cos_sun_radius,
+            sun_radiance: sun_irradiance.map(|e| e / sun_solid_angle),
+            _padding1: 0,
+            ground_radiance: [0.0; 3],
+            _padding2: 0,
+        };
+        // a Lambertian ground lit by the sun and the sky dome above it
+        let sky_irradiance = uniform.upper_hemisphere_irradiance();
+        let cos_sun = self.sun_direction()[1].max(0.0);
+        uniform.ground_radiance = [0, 1, 2].map(|c| {
+            self.ground_albedo[c] * (sun_irradiance[c] * cos_sun + sky_irradiance[c]) / PI
+        });
+        uniform
+    }
+}
+
+impl SkyUniform
+{
+    pub fn disabled() -> Self
+    {
+        bytemuck::Zeroable::zeroed()
+    }
+    /// Mirrors `sky_radiance` in the shader, without the sun disk.
+    pub fn radiance(&self, direction: [f32; 3]) -> [f32; 3]
+    {
+        if direction[1] < 0.0 {
+            return self.ground_radiance;
+        }
+        let cos_theta = direction[1].max(1e-3);
+        let cos_gamma = (0..3).map(|i| direction[i] * self.sun_direction[i]).sum::<f32>();
+        let f = perez_distribution(&self.perez, cos_theta, cos_gamma.clamp(-1.0, 1.0).acos());
+        let luminance = self.zenith[0] * f[0];
+        let x = self.zenith[1] * f[1];
+        let y = self.zenith[2] * f[2];
+        xyy_to_rgb(x, y, luminance)
+    }
+    fn upper_hemisphere_irradiance(&self) -> [f32; 3]
+    {
+        let mut irradiance = [0.0; 3];
+        let (rows, columns) = (SKY_HEIGHT / 2, SKY_WIDTH);
+        for row in 0..rows {
+            let theta = (row as f32 + 0.5) / rows as f32 * 0.5 * PI;
+            let weight = theta.cos() * theta.sin() * (0.5 * PI / rows as f32) * (2.0 * PI / columns as f32);
+            for column in 0..columns {
+                let phi = (column as f32 + 0.5) / columns as f32 * 2.0 * PI;
+                let radiance = self.radiance(spherical_direction(theta, phi));
+                for c in 0..3 {
+                    irradiance[c] += radiance[c] * weight;
+                }
+            }
+        }
+        irradiance
+    }
+    /// Tabulated distribution for importance sampling the sky dome, laid out as
+    /// per-texel probabilities, per-row conditional CDFs and the marginal CDF
+    /// over rows. Rows run from the zenith down, columns around the y axis.
+    pub fn distribution(&self) -> Vec<f32>
+    {
+        let mut weights = vec![0.0f32; SKY_WIDTH * SKY_HEIGHT];
+        for row in 0..SKY_HEIGHT {
+            let theta = (row as f32 + 0.5) / SKY_HEIGHT as f32 * PI;
+            for column in 0..SKY_WIDTH {
+                let phi = (column as f32 + 0.5) / SKY_WIDTH as f32 * 2.0 * PI;
+                let [r, g, b] = self.radiance(spherical_direction(theta, phi));
+                // keep a floor so that no direction becomes impossible to sample
+                weights[row * SKY_WIDTH + column] = (0.2126 * r + 0.7152 * g + 0.0722 * b).max(1e-4) * theta.sin();
+            }
+        }
+        let total: f32 = weights.iter().sum();
+        weights.iter_mut().for_each(|w| *w /= total);
+        let mut conditional = vec![0.0f32; SKY_WIDTH * SKY_HEIGHT];
+        let mut marginal = vec![0.0f32; SKY_HEIGHT];
+        let mut row_sum = 0.0;
+        for row in 0..SKY_HEIGHT {
+            let texels = &weights[row * SKY_WIDTH..(row + 1) * SKY_WIDTH];
+            let row_weight: f32 = texels.iter().sum();
+            let mut sum = 0.0;
+            for (column, weight) in texels.iter().enumerate() {
+                sum += weight;
+                conditional[row * SKY_WIDTH + column] = sum / row_weight;
+            }
+            conditional[row * SKY_WIDTH + SKY_WIDTH - 1] = 1.0;
+            row_sum += row_weight;
+            marginal[row] = row_sum;
+        }
+        marginal[SKY_HEIGHT - 1] = 1.0;
+        [weights, conditional, marginal].concat()
+    }
+}
+
+fn perez_distribution(perez: &[[f32; 4]; 5], cos_theta: f32, gamma: f32) -> [f32; 3]
+{
+    [0, 1, 2].map(|c| {
+        let [a, b, cc, d, e] = [perez[0][c], perez[1][c], perez[2][c], perez[3][c], perez[4][c]];
+        (1.0 + a * (b / cos_theta).exp()) * (1.0 + cc * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+    })
+}
+
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> [f32; 3]
+{
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    [
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+    ]
+}
+
+fn spherical_direction(theta: f32, phi: f32) -> [f32; 3]
+{
+    [theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos()]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn afternoon() -> SkyDesc
+    {
+        SkyDesc {
+            elevation: 30.0,
+            azimuth: 45.0,
+            turbidity: 3.0,
+            ground_albedo: [0.3; 3],
+            intensity: 1.0,
+        }
+    }
+
+    #[test]
+    fn sky_is_brighter_towards_the_sun()
+    {
+        let sky = afternoon();
+        let uniform = sky.uniform();
+        let [x, _, z] = sky.sun_direction();
+        let towards = uniform.radiance([x, 0.6, z]);
+        let away = uniform.radiance([-x, 0.6, -z]);
+        assert!(towards[1] > away[1], "{:?} {:?}", towards, away);
+        assert!(away.iter().all(|c| *c > 0.0));
+    }
+
+    #[test]
+    fn distribution_is_normalised()
+    {
+        let distribution = afternoon().uniform().distribution();
+        let texels = SKY_WIDTH * SKY_HEIGHT;
+        assert_eq!(distribution.len(), 2 * texels + SKY_HEIGHT);
+        let total: f32 = distribution[..texels].iter().sum();
+        assert!((total - 1.0).abs() < 1e-3);
+        assert_eq!(distribution[2 * texels + SKY_HEIGHT - 1], 1.0);
+    }
+
+    #[test]
+    fn sun_below_horizon_is_dark()
+    {
+        let mut sky = afternoon();
+        sky.elevation = -5.0;
+        assert_eq!(sky.sun_irradiance(), [0.0; 3]);
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use crate::color::ColorDesc;
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+/// Largest side of the image array; bigger images are scaled down to fit.
+pub const MAX_IMAGE_SIZE: u32 = 2048;
+
+const TEXTURE_IMAGE: u32 = 0;
+const TEXTURE_CHECKER: u32 = 1;
+const TEXTURE_NOISE: u32 = 2;
+const TEXTURE_GRADIENT: u32 = 3;
+
+/// Texture as written in a scene file. `scale` multiplies the surface UVs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TextureDesc
+{
+    /// PNG or JPEG relative to the scene file, decoded from sRGB unless `linear`.
+    Image
+    {
+        path: PathBuf,
+        #[serde(default)]
+        linear: bool,
+        #[serde(default = "unit_scale")]
+        scale: [f32; 2],
+    },
+    /// Squares alternating between `even` and `odd`, one per unit of scaled UV.
+    Checker
+    {
+        even: [f32; 3],
+        odd: [f32; 3],
+        #[serde(default = "unit_scale")]
+        scale: [f32; 2],
+    },
+    /// Fractal value noise blending from `low` to `high`.
+    Noise
+    {
+        low: [f32; 3],
+        high: [f32; 3],
+        #[serde(default = "unit_scale")]
+        scale: [f32; 2],
+        #[serde(default)]
+        seed: u32,
+    },
+    /// Blend from `from` to `to` as `dot(uv, direction)` goes from 0 to 1.
+    Gradient
+    {
+        from: [f32; 3],
+        to: [f32; 3],
+        #[serde(default = "unit_u")]
+        direction: [f32; 2],
+    },
+}
+
+fn unit_scale() -> [f32; 2]
+{
+    [1.0; 2]
+}
+
+fn unit_u() -> [f32; 2]
+{
+    [1.0, 0.0]
+}
+
+/// Texture parameters, bound in the `textures` buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Texture
+{
+    color_a: [f32; 3],
+    kind: u32,
+    color_b: [f32; 3],
+    /// Layer in the image array, or -1 for procedural textures.
+    layer: i32,
+    scale: [f32; 2],
+    seed: u32,
+    linear: u32,
+}
+
+impl GpuLayout for Texture
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(Texture { color_a, kind, color_b, layer, scale, seed, linear })
+    }
+}
+
+impl Texture
+{
+    /// The texture with its constant colours converted into the working space; the shader
+    /// converts image texels.
+    pub fn in_working_space(mut self, color: &ColorDesc) -> Self
+    {
+        self.color_a = color.to_working(self.color_a);
+        self.color_b = color.to_working(self.color_b);
+        self
+    }
+}
+
+impl TextureDesc
+{
+    pub fn to_texture(&self, layer: i32) -> Texture
+    {
+        let mut texture = Texture {
+            color_a: [1.0; 3],
+            kind: TEXTURE_IMAGE,
+            color_b: [1.0; 3],
+            layer: -1,
+            scale: [1.0; 2],
+            seed: 0,
+            linear: 0,
+        };
+        match *self {
+            TextureDesc::Image { linear, scale, .. } => {
+                texture.layer = layer;
+                texture.scale = scale;
+                texture.linear = linear as u32;
+            }
+            TextureDesc::Checker { even, odd, scale } => {
+                texture.kind = TEXTURE_CHECKER;
+                texture.color_a = even;
+                texture.color_b = odd;
+                texture.scale = scale;
+            }
+            TextureDesc::Noise { low, high, scale, seed } => {
+                texture.kind = TEXTURE_NOISE;
+                texture.color_a = low;
+                texture.color_b = high;
+                texture.scale = scale;
+                texture.seed = seed;
+            }
+            TextureDesc::Gradient { from, to, direction } => {
+                texture.kind = TEXTURE_GRADIENT;
+                texture.color_a = from;
+                texture.color_b = to;
+                texture.scale = direction;
+            }
+        }
+        texture
+    }
+}
+
+pub fn load_image(path: &Path) -> Result<RgbaImage, String>
+{
+    let image = image::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(image.to_rgba8())
+}
+
+/// Stacks `images` into layers of one size, the largest width and height up to
+/// [`MAX_IMAGE_SIZE`], stretching the smaller ones since UVs span each image.
+pub fn image_array(images: &[RgbaImage]) -> ([u32; 3], Vec<u8>)
+{
+    if images.is_empty() {
+        return ([1; 3], vec![255; 4]);
+    }
+    let width = images.iter().map(|image| image.width()).max().unwrap_or(1).min(MAX_IMAGE_SIZE);
+    let height = images.iter().map(|image| image.height()).max().unwrap_or(1).min(MAX_IMAGE_SIZE);
+    let mut data = Vec::with_capacity((width * height * 4) as usize * images.len());
+    for image in images {
+        if image.dimensions() == (width, height) {
+            data.extend_from_slice(image.as_raw());
+        } else {
+            let resized = image::imageops::resize(image, width, height, image::imageops::FilterType::Triangle);
+            data.extend_from_slice(resized.as_raw());
+        }
+    }
+    ([width, height, images.len() as u32], data)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn images_are_stretched_to_one_size()
+    {
+        let small = RgbaImage::from_pixel(1, 1, image::Rgba([10, 20, 30, 255]));
+        let large = RgbaImage::from_pixel(4, 2, image::Rgba([0, 0, 0, 255]));
+        let ([width, height, layers], data) = image_array(&[small, large]);
+        assert_eq!([width, height, layers], [4, 2, 2]);
+        assert_eq!(data.len(), 4 * 2 * 4 * 2);
+        assert_eq!(&data[..4], &[10, 20, 30, 255]);
+    }
+}
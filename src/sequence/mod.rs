@@ -0,0 +1,91 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::scene::Animation;
+
+/// Batch rendering of a scene's animation into numbered EXR files, one per frame.
+pub struct Sequence
+{
+    directory: PathBuf,
+    /// Index of the frame being rendered.
+    pub frame: u32,
+    pub frame_count: u32,
+    fps: f32,
+    started: Instant,
+}
+
+impl Sequence
+{
+    pub fn new(directory: &Path, animation: &Animation) -> io::Result<Self>
+    {
+        std::fs::create_dir_all(directory)?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            frame: 0,
+            frame_count: animation.frame_count(),
+            fps: animation.fps,
+            started: Instant::now(),
+        })
+    }
+    /// Scene time of the current frame in seconds.
+    pub fn time(&self) -> f32
+    {
+        self.frame as f32 / self.fps
+    }
+    pub fn path(&self) -> PathBuf
+    {
+        self.directory.join(format!("frame_{:04}.exr", self.frame))
+    }
+    /// Reports progress after the current frame has been written and moves on to the next,
+    /// returning false once every frame is done.
+    pub fn advance(&mut self) -> bool
+    {
+        self.frame += 1;
+        let elapsed = self.started.elapsed();
+        log::info!(
+            "frame {}/{} done, {:.1}s per frame, {} elapsed, {} left",
+            self.frame,
+            self.frame_count,
+            elapsed.as_secs_f32() / self.frame as f32,
+            format_duration(elapsed),
+            format_duration(remaining(elapsed, self.frame, self.frame_count)),
+        );
+        self.frame < self.frame_count
+    }
+}
+
+/// Time left if the remaining frames take as long as the finished ones did on average.
+fn remaining(elapsed: Duration, done: u32, total: u32) -> Duration
+{
+    if done == 0 {
+        return Duration::ZERO;
+    }
+    elapsed / done * total.saturating_sub(done)
+}
+
+fn format_duration(duration: Duration) -> String
+{
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m{:02}s", seconds / 3600, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn estimates_time_left()
+    {
+        assert_eq!(remaining(Duration::from_secs(10), 2, 6), Duration::from_secs(20));
+        assert_eq!(remaining(Duration::from_secs(10), 0, 6), Duration::ZERO);
+        assert_eq!(remaining(Duration::from_secs(10), 6, 6), Duration::ZERO);
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m05s");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h02m05s");
+    }
+}
@@ -0,0 +1,160 @@
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+use crate::options::Options;
+
+/// Render settings that can change while running; changing one restarts accumulation.
+#[derive(Clone, Debug, Default)]
+pub struct Settings
+{
+    /// Trace hero wavelengths instead of RGB.
+    pub spectral: bool,
+    pub integrator: Integrator,
+    /// Light the path tracer's first diffuse hits by reservoir resampling instead of next event estimation.
+    pub restir: bool,
+    pub debug_view: DebugView,
+}
+
+/// Light transport algorithm, numbered as in the shader.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Integrator
+{
+    /// Unidirectional path tracing with next event estimation
+    #[default]
+    Path,
+    /// Bidirectional path tracing with light tracing splats
+    Bdpt,
+    /// Stochastic progressive photon mapping, run in compute passes
+    Sppm,
+    /// Primary sample space Metropolis light transport over the path tracer
+    Pssmlt,
+}
+
+impl Integrator
+{
+    pub fn next(self) -> Self
+    {
+        match self {
+            Integrator::Path => Integrator::Bdpt,
+            Integrator::Bdpt => Integrator::Sppm,
+            Integrator::Sppm => Integrator::Pssmlt,
+            Integrator::Pssmlt => Integrator::Path,
+        }
+    }
+}
+
+/// What to show in place of the rendered image, numbered as in the shader.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DebugView
+{
+    /// The rendered image
+    #[default]
+    Off,
+    /// Surface normals at the first hit, mapped from [-1, 1] to [0, 1]
+    Normal,
+    /// Reflectance of the first surface hit, or the colour of the light hit
+    Albedo,
+    /// Distance to the first hit, relative to the surface at the centre of the screen
+    Depth,
+    /// A false colour for every primitive
+    PrimitiveId,
+    /// A false colour for every material
+    MaterialId,
+    /// Rays traced along each path tracer path, from blue at one to red at the bounce limit
+    PathLength,
+    /// Ray-primitive intersection tests per path on the same scale
+    Intersections,
+}
+
+impl DebugView
+{
+    pub fn next(self) -> Self
+    {
+        match self {
+            DebugView::Off => DebugView::Normal,
+            DebugView::Normal => DebugView::Albedo,
+            DebugView::Albedo => DebugView::Depth,
+            DebugView::Depth => DebugView::PrimitiveId,
+            DebugView::PrimitiveId => DebugView::MaterialId,
+            DebugView::MaterialId => DebugView::PathLength,
+            DebugView::PathLength => DebugView::Intersections,
+            DebugView::Intersections => DebugView::Off,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SettingsUniform
+{
+    spectral: u32,
+    integrator: u32,
+    restir: u32,
+    debug_view: u32,
+}
+
+impl GpuLayout for SettingsUniform
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(SettingsUniform { spectral, integrator, restir, debug_view })
+    }
+}
+
+impl Settings
+{
+    pub fn new(options: &Options) -> Self
+    {
+        Self {
+            spectral: options.spectral,
+            integrator: options.integrator,
+            restir: options.restir,
+            debug_view: options.debug_view,
+        }
+    }
+    pub fn uniform(&self) -> SettingsUniform
+    {
+        SettingsUniform {
+            spectral: self.spectral as u32,
+            integrator: self.integrator as u32,
+            restir: self.restir as u32,
+            debug_view: self.debug_view as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::layout::shader_constant;
+
+    #[test]
+    fn integrators_cycle_in_shader_order()
+    {
+        let names = ["INTEGRATOR_PATH", "INTEGRATOR_BDPT", "INTEGRATOR_SPPM", "INTEGRATOR_PSSMLT"];
+        let mut integrator = Integrator::Path;
+        for name in names {
+            assert_eq!(shader_constant(name), integrator as u32 as f64, "{}", name);
+            integrator = integrator.next();
+        }
+        assert_eq!(integrator, Integrator::Path);
+    }
+
+    #[test]
+    fn debug_views_cycle_in_shader_order()
+    {
+        let mut view = DebugView::Off;
+        for i in 0..8 {
+            assert_eq!(view as u32, i);
+            view = view.next();
+        }
+        assert_eq!(view, DebugView::Off);
+        assert_eq!(shader_constant("DEBUG_INTERSECTIONS"), DebugView::Intersections as u32 as f64);
+    }
+
+    #[test]
+    fn bidirectional_paths_fit_their_vertices()
+    {
+        // camera subpaths need two vertices more than the longest path has segments
+        assert_eq!(shader_constant("BDPT_VERTICES"), shader_constant("MAX_BDPT_DEPTH") + 2.0);
+    }
+}
@@ -16,20 +16,264 @@ fn vertex_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
 struct CameraUniform
 {
     raygen: mat4x4<f32>,
+    // raygen when the shutter closes, which camera rays blend towards over the shutter interval
+    raygen_close: mat4x4<f32>,
     view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
     frame_idx: u32,
+    projection: u32,
+    resolution: vec2<u32>,
+    orthographic_height: f32,
+    stereo: u32,
+    interpupillary_distance: f32,
+    exposure: f32,
+    auto_exposure: u32,
+    vignetting: u32,
 };
+struct SkyUniform
+{
+    sun_direction: vec3<f32>,
+    enabled: u32,
+    perez: array<vec4<f32>, 5>,
+    zenith: vec3<f32>,
+    cos_sun_radius: f32,
+    sun_radiance: vec3<f32>,
+    ground_radiance: vec3<f32>,
+};
+struct SettingsUniform
+{
+    spectral: u32,
+    integrator: u32,
+    restir: u32,
+    debug_view: u32,
+};
+// conversions set up by color/mod.rs for the working space and display
+struct ColorUniform
+{
+    srgb_to_working: mat3x3<f32>,
+    working_to_srgb: mat3x3<f32>,
+    xyz_to_working: mat3x3<f32>,
+    working_to_display: mat3x3<f32>,
+    encoding: u32,
+    paper_white: f32,
+};
+// strengths of the post-processing effects, zero for those that are off
+struct PostUniform
+{
+    bloom_intensity: f32,
+    glare_intensity: f32,
+    glare_streaks: u32,
+    glare_length: f32,
+    chromatic_aberration: f32,
+    vignette_strength: f32,
+    vignette_radius: f32,
+    vignette_softness: f32,
+    grain_intensity: f32,
+};
+// motion is the displacement from shutter open to close, as on quads and boxes
 struct Sphere
 {
     radius: f32,
     material_idx: u32,
     center: vec3<f32>,
+    medium_idx: i32,
+    motion: vec3<f32>,
+};
+struct Plane
+{
+    normal: vec3<f32>,
+    offset: f32,
+    material_idx: u32,
+};
+struct Quad
+{
+    corner: vec3<f32>,
+    material_idx: u32,
+    edge_u: vec3<f32>,
+    edge_v: vec3<f32>,
+    motion: vec3<f32>,
+};
+struct Aabb
+{
+    min_corner: vec3<f32>,
+    material_idx: u32,
+    max_corner: vec3<f32>,
+    medium_idx: i32,
+    motion: vec3<f32>,
+};
+struct Light
+{
+    position: vec3<f32>,
+    kind: u32,
+    edge_u: vec3<f32>,
+    two_sided: u32,
+    edge_v: vec3<f32>,
+    area: f32,
+    emission: vec3<f32>,
+    cos_inner: f32,
+    cos_outer: f32,
+    profile_idx: i32,
+};
+struct LightSample
+{
+    direction: vec3<f32>,
+    distance: f32,
+    radiance: vec3<f32>,
+    pdf: f32,
 };
 struct Material
 {
     color: vec3<f32>,
+    color_texture: i32,
     emission: vec3<f32>,
     clean_coat: f32,
+    emission_texture: i32,
+    clean_coat_texture: i32,
+    ior: f32,
+    dispersion: f32,
+    sellmeier_b: vec3<f32>,
+    sellmeier_c: vec3<f32>,
+};
+struct Texture
+{
+    color_a: vec3<f32>,
+    kind: u32,
+    color_b: vec3<f32>,
+    layer: i32,
+    scale: vec2<f32>,
+    seed: u32,
+    linear: u32,
+};
+struct Medium
+{
+    sigma_t: vec3<f32>,
+    g: f32,
+    sigma_s: vec3<f32>,
+    grid_offset: i32,
+    grid_min: vec3<f32>,
+    majorant: f32,
+    grid_max: vec3<f32>,
+    grid_size: vec3<u32>,
+};
+// vertex of a bidirectional subpath
+struct PathVertex
+{
+    position: vec3<f32>,
+    kind: u32,
+    // faces the side the subpath arrived from on surfaces, the light's own normal on lights
+    normal: vec3<f32>,
+    // sampled a specular lobe, so connections cannot use it
+    delta: bool,
+    beta: vec3<f32>,
+    // densities per unit area of sampling the vertex from its predecessor and its successor
+    pdf_fwd: f32,
+    // diffuse reflectance f times PI, on surfaces
+    albedo: vec3<f32>,
+    pdf_rev: f32,
+    // chance of sampling the diffuse lobe on surfaces
+    diffuse: f32,
+    // Henyey-Greenstein anisotropy in media
+    g: f32,
+    // index into media of where the vertex is
+    medium: u32,
+    light_idx: i32,
+    // whether a dispersive surface has already cut the subpath down to the hero wavelength
+    dispersed: bool,
+};
+// a visible point of progressive photon mapping and the photons it has gathered
+struct SppmPixel
+{
+    position: vec3<f32>,
+    radius: f32,
+    // faces the camera side of the surface
+    normal: vec3<f32>,
+    // photons kept over all iterations, N in Hachisuka and Jensen
+    photon_count: f32,
+    // camera path throughput including the diffuse reflectance
+    beta: vec3<f32>,
+    // whether this iteration's camera path found a diffuse surface
+    valid: u32,
+    // flux gathered within the radius over all iterations
+    tau: vec3<f32>,
+    // photons gathered this iteration, M in Hachisuka and Jensen
+    photons: atomic<u32>,
+    // sum of the directly seen and directly lit radiance over all iterations
+    direct: vec3<f32>,
+    // flux gathered this iteration, as f32 bits
+    flux: array<atomic<u32>, 3>,
+};
+// primary samples of a Metropolis chain's current path, and what they gave
+struct MltChain
+{
+    samples: array<f32, MLT_DIMENSIONS>,
+    radiance: vec3<f32>,
+    luminance: f32,
+    ndc: vec2<f32>,
+};
+struct MltState
+{
+    // luminance summed over the bootstrap paths, as f32 bits
+    bootstrap_sum: atomic<u32>,
+    chains: array<MltChain>,
+};
+// a path of the Metropolis integrator, which lands anywhere on the screen
+struct MltSample
+{
+    radiance: vec3<f32>,
+    ndc: vec2<f32>,
+};
+// a pixel's light sample for direct lighting, with the surface it was picked for
+struct Reservoir
+{
+    position: vec3<f32>,
+    // reflectance as packed 8 bit RGB
+    color: u32,
+    // zero where the camera ray found no diffuse surface
+    normal: vec3<f32>,
+    light_idx: i32,
+    // point on the light, or direction of a directional light, as in light_point
+    uv: vec2<f32>,
+    // candidates the reservoir stands for
+    m: f32,
+    // unbiased contribution weight of the sample
+    weight: f32,
+};
+// compositing passes of an accumulate entry, see aov/mod.rs
+struct Aov
+{
+    direct_diffuse: vec3<f32>,
+    depth: f32,
+    indirect_diffuse: vec3<f32>,
+    object_id: u32,
+    specular: vec3<f32>,
+    frames: u32,
+    emission: vec3<f32>,
+    albedo: vec3<f32>,
+    normal: vec3<f32>,
+};
+// every primitive and light with the tables they are sampled from, see scene/mod.rs
+struct Geometry
+{
+    // entries used of each list, at least one
+    sphere_count: u32,
+    plane_count: u32,
+    quad_count: u32,
+    box_count: u32,
+    light_count: u32,
+    spheres: array<Sphere, MAX_SPHERES>,
+    planes: array<Plane, MAX_PLANES>,
+    quads: array<Quad, MAX_QUADS>,
+    boxes: array<Aabb, MAX_BOXES>,
+    lights: array<Light, MAX_LIGHTS>,
+    ies_profiles: array<f32, IES_TABLE_SIZE>,
+    sky_distribution: array<f32, SKY_DISTRIBUTION_SIZE>,
+};
+struct Shading
+{
+    materials: array<Material, MAX_MATERIALS>,
+    // the fog, or vacuum, followed by the sphere media
+    media: array<Medium, MAX_MEDIA>,
+    textures: array<Texture, MAX_TEXTURES>,
 };
 struct Ray
 {
@@ -39,23 +283,215 @@ struct Ray
 struct Hit
 {
     primitive_idx: i32,
+    // which list primitive_idx indexes, or light_idx for lights
+    primitive_kind: u32,
+    light_idx: i32,
+    medium_idx: i32,
+    material_idx: u32,
     t: f32,
+    normal: vec3<f32>,
+    uv: vec2<f32>,
 };
 @group(0) @binding(0)
 var<uniform> camera: CameraUniform;
+@group(0) @binding(1)
+var<uniform> sky: SkyUniform;
+@group(0) @binding(2)
+var<uniform> settings: SettingsUniform;
+// sigmoid polynomial coefficients by largest channel, value and ratios, see spectrum/mod.rs
+@group(0) @binding(3)
+var rgb_to_spectrum_table: texture_3d<f32>;
+@group(0) @binding(4)
+var<uniform> post: PostUniform;
+@group(0) @binding(5)
+var<uniform> color_space: ColorUniform;
 @group(1) @binding(0)
-var<storage, read> spheres: array<Sphere>;
+var<storage, read> geometry: Geometry;
 @group(2) @binding(0)
-var<storage, read> materials: array<Material>;
+var<storage, read> shading: Shading;
+// density grids of heterogeneous media, stacked along z
+@group(2) @binding(1)
+var density_grids: texture_3d<f32>;
+@group(2) @binding(2)
+var images: texture_2d_array<f32>;
+@group(2) @binding(3)
+var image_sampler: sampler;
+// the image, then the bloom mips from BLOOM_SIZE down, the same mips summed back up and the glare image
 @group(3) @binding(0)
 var<storage, read_write> accumulate: array<vec3<f32>>;
+/*
+    log luminance histogram of the image, then the metered exposure as bits, zero until the
+    first metering, then from INTEGRATOR_COUNTERS on either of, as no integrator uses both:
+    light tracing and Metropolis contributions in fixed point as i32 bits, as only integer
+    atomics exist: RGB per accumulate entry, in two halves that frames of either parity merge;
+    or the photon lookup grid: the cell size as f32 bits, then GRID_SIZE bucket heads, then for
+    every entry of sppm_pixels the next one in its bucket, as indices plus one with 0 ending a list
+*/
+@group(3) @binding(1)
+var<storage, read_write> counters: array<atomic<u32>>;
+@group(3) @binding(2)
+var<storage, read_write> sppm_pixels: array<SppmPixel>;
+@group(3) @binding(3)
+var<storage, read_write> mlt: MltState;
+// after temporal reuse for every accumulate entry, then after spatial reuse
+@group(3) @binding(4)
+var<storage, read_write> reservoirs: array<Reservoir>;
+// one entry per accumulate entry when the path tracer records compositing passes, otherwise one unused entry
+@group(3) @binding(5)
+var<storage, read_write> aovs: array<Aov>;
 var<private> seed: u32;
+// point of the shutter interval from 0 to 1 that the current path is traced at, and the camera then
+var<private> sample_time: f32;
+var<private> sample_raygen: mat4x4<f32>;
+// in spectral mode, the hero wavelength and its two companions of the current path
+var<private> wavelengths: vec3<f32>;
+var<private> camera_path: array<PathVertex, BDPT_VERTICES>;
+var<private> light_path: array<PathVertex, BDPT_VERTICES>;
+// light that camera subpaths find which no other bidirectional strategy samples
+var<private> unconnected_radiance: vec3<f32>;
+// while set, frand hands out these primary samples before falling back on the generator
+var<private> mlt_active: bool;
+var<private> mlt_samples: array<f32, MLT_DIMENSIONS>;
+var<private> mlt_sample_idx: u32;
+// accumulate entry whose reservoir lights the path tracer's first hit, or -1 for next event estimation
+var<private> restir_pixel: i32 = -1;
+// counted for the debug views: bounces of the current path and intersection tests along it
+var<private> path_length: u32;
+var<private> intersection_tests: u32;
+// while recording compositing passes, the current path's radiance split up by aov_add and its first hit
+var<private> aov_active: bool;
+var<private> aov_lighting: array<vec3<f32>, 4>;
+var<private> aov_hit: Hit;
 const PI = 3.1415926;
 const EPS = 1e-3;
 const SPP = 48;
+const BDPT_SPP = 4;
 const MAX_BOUNCE = 12;
+// longest bidirectional path in segments, which camera subpaths need two vertices more than
+const MAX_BDPT_DEPTH = 5;
+const BDPT_VERTICES = 7;
+const ACCUMULATE_SIZE = 1024u; // matches state/mod.rs
+const SPLAT_SCALE = 1024.0;
+const INTEGRATOR_PATH = 0u; // matches settings/mod.rs
+const INTEGRATOR_BDPT = 1u;
+const INTEGRATOR_SPPM = 2u;
+const PHOTONS_PER_ITERATION = 262144u; // matches sppm/mod.rs
+const GRID_SIZE = 1048576u;
+// visible points start out this many pixel footprints wide
+const INITIAL_RADIUS_PIXELS = 8.0;
+// share of new photons kept each iteration, alpha in Hachisuka and Jensen
+const SPPM_ALPHA = 0.7;
+const INTEGRATOR_PSSMLT = 3u;
+const MLT_CHAINS = 65536u; // matches mlt/mod.rs
+const MLT_DIMENSIONS = 128;
+// candidate paths per chain in the bootstrap, and mutations per chain and frame
+const MLT_BOOTSTRAP = 16u;
+const MLT_MUTATIONS = 32u;
+const MLT_LARGE_STEP = 0.3;
+const RESTIR_CANDIDATES = 32u;
+// neighbours and their distance in pixels for spatial reuse, and how many reservoirs are combined at most
+const RESTIR_NEIGHBOURS = 5u;
+const RESTIR_RADIUS = 30.0;
+const RESTIR_COMBINED = 6;
+// the previous frame counts for at most this many times the candidates of the current one
+const RESTIR_HISTORY = 20.0;
+const EXPOSURE_BINS = 128u;
+const INTEGRATOR_COUNTERS = 129u; // EXPOSURE_BINS + 1
+// the histogram spans these stops of luminance, below the first bin's black pixels
+const MIN_LOG_LUMINANCE = -16.0;
+const LOG_LUMINANCE_RANGE = 32.0;
+// the darkest and brightest pixels that metering leaves out, like shadows and lights in view
+const EXPOSURE_LOW_PERCENTILE = 0.5;
+const EXPOSURE_HIGH_PERCENTILE = 0.95;
+// share of the way to the newly metered exposure that every frame goes, in stops
+const EXPOSURE_ADAPTATION = 0.25;
+const BLOOM_SIZE = 512u; // matches post/mod.rs
+const BLOOM_LEVELS = 7u;
+const GLARE_SIZE = 256u;
+const GLARE_SAMPLES = 24;
+// radial shift of the red and blue channels at the corners per unit of chromatic aberration
+const CHROMATIC_ABERRATION_SCALE = 0.01;
+const PROJECTION_PERSPECTIVE = 0u; // matches camera/mod.rs
+const PROJECTION_ORTHOGRAPHIC = 1u;
+const PROJECTION_FISHEYE_EQUIDISTANT = 2u;
+const PROJECTION_FISHEYE_EQUISOLID = 3u;
+const PROJECTION_EQUIRECTANGULAR = 4u;
+const PROJECTION_CYLINDRICAL = 5u;
+const ENCODING_SRGB = 0u; // matches color/mod.rs
+const ENCODING_LINEAR = 1u;
+const ENCODING_SCRGB = 2u;
+// luminance of 1 in scRGB, in nits
+const SCRGB_WHITE = 80.0;
+const STEREO_OFF = 0u; // matches camera/mod.rs
+const STEREO_SIDE_BY_SIDE = 1u;
+const STEREO_TOP_BOTTOM = 2u;
+const DEBUG_OFF = 0u; // matches settings/mod.rs
+const DEBUG_NORMAL = 1u;
+const DEBUG_ALBEDO = 2u;
+const DEBUG_DEPTH = 3u;
+const DEBUG_PRIMITIVE_ID = 4u;
+const DEBUG_MATERIAL_ID = 5u;
+const DEBUG_PATH_LENGTH = 6u;
+const DEBUG_INTERSECTIONS = 7u;
+const PRIMITIVE_SPHERE = 0u;
+const PRIMITIVE_PLANE = 1u;
+const PRIMITIVE_QUAD = 2u;
+const PRIMITIVE_BOX = 3u;
+const PRIMITIVE_LIGHT = 4u;
+const AOV_DIRECT = 0;
+const AOV_INDIRECT = 1;
+const AOV_SPECULAR = 2;
+const AOV_EMISSION = 3;
+// how a path first scattered, which decides its compositing pass
+const LOBE_NONE = 0u;
+const LOBE_DIFFUSE = 1u;
+const LOBE_SPECULAR = 2u;
+const VERTEX_CAMERA = 0u;
+const VERTEX_LIGHT = 1u;
+const VERTEX_SURFACE = 2u;
+const VERTEX_MEDIUM = 3u;
+// cap on null collisions per tracking loop, to bound the work in very dense grids
+const MAX_NULL_COLLISIONS = 256;
+const LIGHT_RECTANGLE = 0u;
+const LIGHT_DISK = 1u;
+const LIGHT_POINT = 2u;
+const LIGHT_SPOT = 3u;
+const LIGHT_DIRECTIONAL = 4u;
+const IES_SAMPLES = 64; // matches scene/ies.rs
+const SKY_WIDTH = 128u; // matches scene/sky.rs
+const SKY_HEIGHT = 64u;
+const MAX_SPHERES = 1024; // matches scene/mod.rs
+const MAX_PLANES = 64;
+const MAX_QUADS = 1024;
+const MAX_BOXES = 1024;
+const MAX_LIGHTS = 64;
+const MAX_MATERIALS = 256;
+const MAX_MEDIA = 64;
+const MAX_TEXTURES = 64;
+const IES_TABLE_SIZE = 4096; // MAX_LIGHTS * IES_SAMPLES
+const SKY_DISTRIBUTION_SIZE = 16448; // 2 * SKY_WIDTH * SKY_HEIGHT + SKY_HEIGHT
+const LAMBDA_MIN = 360.0; // matches spectrum/mod.rs
+const LAMBDA_MAX = 830.0;
+const SPECTRUM_TABLE_RESOLUTION = 16;
+// integral of the CIE y matching function, and the scale giving D65 unit luminance
+const CIE_Y_INTEGRAL = 106.922074;
+const D65_SCALE = 0.0101162363;
+// CIE standard illuminant D65 from 360 to 830 nanometers in 10 nanometer steps,
+// private rather than const so that it can be indexed dynamically
+var<private> d65_table: array<f32, 48> = array<f32, 48>(
+    46.6383, 52.0891, 49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008,
+    117.812, 114.861, 115.923, 108.811, 109.354, 107.802, 104.79, 107.689, 104.405, 104.046,
+    100.0, 96.3342, 95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268,
+    80.2146, 82.2778, 78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927,
+    46.4182, 66.8054, 63.3828, 64.304, 59.4519, 51.959, 57.4406, 60.3125,
+);
 fn frand() -> f32
 {
+    if (mlt_active && mlt_sample_idx < u32(MLT_DIMENSIONS))
+    {
+        mlt_sample_idx++;
+        return mlt_samples[mlt_sample_idx - 1u];
+    }
     /*
         from Inigo Equilez's article on float and random
         https://iquilezles.org/articles/sfrand/
@@ -77,116 +513,2669 @@ fn sample_cosine_hemisphere(normal: vec3<f32>) -> vec3<f32>
     let phi = 2.0 * PI * u2.g;
     return cos_theta * normal + sin_theta * (cos(phi) * tangent + sin(phi) * binormal);
 }
+/*
+    Wavelengths sampled in proportion to the visible range, after Radziszewski et al.,
+    "An Improved Technique for Full Spectral Rendering", 2009, with the companions
+    of the hero wavelength spaced evenly through the distribution
+*/
+fn sample_wavelengths()
+{
+    let u = frand();
+    let v = fract(vec3<f32>(u, u + 1.0 / 3.0, u + 2.0 / 3.0));
+    wavelengths = 538.0 - 138.888889 * atanh(0.85691062 - 1.82750197 * v);
+}
+fn wavelength_pdf(lambda: vec3<f32>) -> vec3<f32>
+{
+    let c = cosh(0.0072 * (lambda - 538.0));
+    return 0.0039398042 / (c * c);
+}
+// Wyman et al., "Simple Analytic Approximations to the CIE XYZ Color Matching Functions", 2013
+fn piecewise_gaussian(lambda: vec3<f32>, mu: f32, sigma_low: f32, sigma_high: f32) -> vec3<f32>
+{
+    let t = (lambda - mu) / select(vec3<f32>(sigma_high), vec3<f32>(sigma_low), lambda < vec3<f32>(mu));
+    return exp(-0.5 * t * t);
+}
+// XYZ of spectral radiance at the path's wavelengths, converted to linear sRGB
+fn spectrum_to_rgb(radiance: vec3<f32>) -> vec3<f32>
+{
+    if (settings.spectral == 0u) {
+        return radiance;
+    }
+    let lambda = wavelengths;
+    let x = 1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0) + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8);
+    let weight = radiance / (wavelength_pdf(lambda) * 3.0 * CIE_Y_INTEGRAL);
+    let xyz = vec3<f32>(dot(x, weight), dot(y, weight), dot(z, weight));
+    return color_space.xyz_to_working * xyz;
+}
+fn d65(lambda: vec3<f32>) -> vec3<f32>
+{
+    let x = clamp((lambda - LAMBDA_MIN) / 10.0, vec3<f32>(0.0), vec3<f32>(47.0));
+    let i = min(vec3<u32>(x), vec3<u32>(46u));
+    let f = x - vec3<f32>(i);
+    let lo = vec3<f32>(d65_table[i.x], d65_table[i.y], d65_table[i.z]);
+    let hi = vec3<f32>(d65_table[i.x + 1u], d65_table[i.y + 1u], d65_table[i.z + 1u]);
+    return mix(lo, hi, f);
+}
+// z nodes of the table, which cluster around black and white
+fn spectrum_table_z(k: i32) -> f32
+{
+    let t = f32(k) / f32(SPECTRUM_TABLE_RESOLUTION - 1);
+    let s = t * t * (3.0 - 2.0 * t);
+    return s * s * (3.0 - 2.0 * s);
+}
+/*
+    Jakob and Hanika, "A Low-Dimensional Function Space for Efficient Spectral Upsampling", 2019:
+    trilinear lookup of the sigmoid polynomial whose reflectance has this colour
+*/
+fn spectrum_coefficients(rgb: vec3<f32>) -> vec3<f32>
+{
+    if (rgb.r == rgb.g && rgb.g == rgb.b) {
+        let r = clamp(rgb.r, 1e-4, 1.0 - 1e-4);
+        return vec3<f32>(0.0, 0.0, (r - 0.5) / sqrt(r * (1.0 - r)));
+    }
+    var l = 2;
+    if (rgb.r >= rgb.g && rgb.r >= rgb.b) {
+        l = 0;
+    } else if (rgb.g >= rgb.b) {
+        l = 1;
+    }
+    let n = SPECTRUM_TABLE_RESOLUTION;
+    let z = rgb[l];
+    let x = rgb[(l + 1) % 3] / z * f32(n - 1);
+    let y = rgb[(l + 2) % 3] / z * f32(n - 1);
+    var zi = 0;
+    for (var k = n - 2; k > 0; k--) {
+        if (spectrum_table_z(k) <= z) {
+            zi = k;
+            break;
+        }
+    }
+    let z0 = spectrum_table_z(zi);
+    let dz = clamp((z - z0) / (spectrum_table_z(zi + 1) - z0), 0.0, 1.0);
+    let xi = min(i32(x), n - 2);
+    let yi = min(i32(y), n - 2);
+    let f = vec3<f32>(x - f32(xi), y - f32(yi), dz);
+    let base = vec3<i32>(xi, yi, l * n + zi);
+    let c000 = textureLoad(rgb_to_spectrum_table, base, 0).rgb;
+    let c100 = textureLoad(rgb_to_spectrum_table, base + vec3<i32>(1, 0, 0), 0).rgb;
+    let c010 = textureLoad(rgb_to_spectrum_table, base + vec3<i32>(0, 1, 0), 0).rgb;
+    let c110 = textureLoad(rgb_to_spectrum_table, base + vec3<i32>(1, 1, 0), 0).rgb;
+    let c001 = textureLoad(rgb_to_spectrum_table, base + vec3<i32>(0, 0, 1), 0).rgb;
+    let c101 = textureLoad(rgb_to_spectrum_table, base + vec3<i32>(1, 0, 1), 0).rgb;
+    let c011 = textureLoad(rgb_to_spectrum_table, base + vec3<i32>(0, 1, 1), 0).rgb;
+    let c111 = textureLoad(rgb_to_spectrum_table, base + vec3<i32>(1, 1, 1), 0).rgb;
+    let c00 = mix(c000, c100, f.x);
+    let c10 = mix(c010, c110, f.x);
+    let c01 = mix(c001, c101, f.x);
+    let c11 = mix(c011, c111, f.x);
+    return mix(mix(c00, c10, f.y), mix(c01, c11, f.y), f.z);
+}
+fn sigmoid_polynomial(c: vec3<f32>, lambda: vec3<f32>) -> vec3<f32>
+{
+    let x = (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+    let y = (c.x * x + c.y) * x + c.z;
+    return 0.5 + 0.5 * y / sqrt(1.0 + y * y);
+}
+/*
+    An RGB parameter as the spectrum to trace: reflectances are upsampled directly,
+    other quantities are scaled into range first, and light sources are also tinted
+    by D65 so that an RGB white light stays white. RGB mode uses the colour as is.
+*/
+fn reflectance(rgb: vec3<f32>) -> vec3<f32>
+{
+    if (settings.spectral == 0u) {
+        return rgb;
+    }
+    // the coefficient table is fitted to sRGB, which clips wider working space colours
+    let srgb = color_space.working_to_srgb * rgb;
+    return sigmoid_polynomial(spectrum_coefficients(clamp(srgb, vec3<f32>(0.0), vec3<f32>(1.0))), wavelengths);
+}
+fn unbounded(rgb: vec3<f32>) -> vec3<f32>
+{
+    let scale = 2.0 * max(rgb.r, max(rgb.g, rgb.b));
+    if (settings.spectral == 0u || scale <= 0.0) {
+        return rgb;
+    }
+    return scale * sigmoid_polynomial(spectrum_coefficients(rgb / scale), wavelengths);
+}
+fn illuminant(rgb: vec3<f32>) -> vec3<f32>
+{
+    if (settings.spectral == 0u) {
+        return rgb;
+    }
+    return unbounded(max(color_space.working_to_srgb * rgb, vec3<f32>(0.0))) * d65(wavelengths) * D65_SCALE;
+}
+// -1 for the left eye and 1 for the right, or 0 without stereo
+fn stereo_eye(frag_coord: vec2<f32>) -> f32
+{
+    if (camera.stereo == STEREO_SIDE_BY_SIDE) {
+        return select(1.0, -1.0, frag_coord.x < 0.0);
+    }
+    if (camera.stereo == STEREO_TOP_BOTTOM) {
+        return select(1.0, -1.0, frag_coord.y > 0.0);
+    }
+    return 0.0;
+}
+// maps the eye's half of the screen onto all of it
+fn eye_ndc(frag_coord: vec2<f32>, eye: f32) -> vec2<f32>
+{
+    var ndc = frag_coord;
+    if (camera.stereo == STEREO_SIDE_BY_SIDE) {
+        ndc.x = 2.0 * ndc.x - eye;
+    } else if (camera.stereo == STEREO_TOP_BOTTOM) {
+        ndc.y = 2.0 * ndc.y + eye;
+    }
+    return ndc;
+}
 fn generate_ray(frag_coord: vec2<f32>) -> Ray
 {
     var ray: Ray;
     let offset = vec2<f32>(frand(), frand()) / 1024.0;
-    ray.direction = normalize((camera.raygen * vec4<f32>(frag_coord + offset, 1.0, 0.0)).rgb);
-    ray.origin = (camera.raygen * vec4<f32>(0.0, 0.0, 0.0, 1.0)).rgb;
+    sample_time = frand();
+    sample_raygen = camera.raygen * (1.0 - sample_time) + camera.raygen_close * sample_time;
+    let eye = stereo_eye(frag_coord);
+    let ndc = eye_ndc(frag_coord + offset, eye);
+    // the raygen columns span half the sensor sideways and up, and reach the sensor forwards
+    let right = sample_raygen[0].xyz;
+    let up = sample_raygen[1].xyz;
+    let forward = sample_raygen[2].xyz;
+    let sensor = ndc.x * right + ndc.y * up;
+    ray.origin = sample_raygen[3].xyz;
+    ray.direction = normalize(sensor + forward);
+    // the eyes sit on this axis, which turns with the head in panoramas for omni-directional stereo
+    var baseline = normalize(right);
+    if (camera.projection == PROJECTION_ORTHOGRAPHIC)
+    {
+        ray.origin += sensor * (0.5 * camera.orthographic_height / length(up));
+        ray.direction = normalize(forward);
+    }
+    else if (camera.projection == PROJECTION_FISHEYE_EQUIDISTANT || camera.projection == PROJECTION_FISHEYE_EQUISOLID)
+    {
+        // the radius reaches one in the corners, which look 90 degrees off the axis
+        let r = min(length(sensor) / length(right + up), 1.0);
+        var theta = 0.5 * PI * r;
+        if (camera.projection == PROJECTION_FISHEYE_EQUISOLID) {
+            theta = 2.0 * asin(r * sin(0.25 * PI));
+        }
+        ray.direction = cos(theta) * normalize(forward) + sin(theta) * sensor / max(length(sensor), 1e-12);
+    }
+    else if (camera.projection == PROJECTION_EQUIRECTANGULAR)
+    {
+        let longitude = ndc.x * PI;
+        let latitude = ndc.y * 0.5 * PI;
+        let around = sin(longitude) * normalize(right) + cos(longitude) * normalize(forward);
+        ray.direction = cos(latitude) * around + sin(latitude) * normalize(up);
+        baseline = cos(longitude) * normalize(right) - sin(longitude) * normalize(forward);
+    }
+    else if (camera.projection == PROJECTION_CYLINDRICAL)
+    {
+        let longitude = ndc.x * PI;
+        let around = sin(longitude) * normalize(right) + cos(longitude) * normalize(forward);
+        ray.direction = normalize(length(forward) * around + ndc.y * up);
+        baseline = cos(longitude) * normalize(right) - sin(longitude) * normalize(forward);
+    }
+    ray.origin += 0.5 * eye * camera.interpupillary_distance * baseline;
     return ray;
 }
+// each routine returns the nearest distance along the ray, or -1.0 on a miss
+fn intersect_sphere(ray: Ray, sphere: Sphere) -> f32
+{
+    let f = ray.origin - sphere.center;
+    let b = -dot(f, ray.direction);
+    let l = f + b * ray.direction;
+    let delta = sphere.radius * sphere.radius - dot(l, l);
+    if (delta <= 0.0) {
+        return -1.0;
+    }
+    let q = b + sign(b) * sqrt(delta);
+    let c = dot(f, f) - sphere.radius * sphere.radius;
+    let t0 = min(c / q, q);
+    let t1 = max(c / q, q);
+    return select(t1, t0, t0 > EPS);
+}
+// surface parameterisations; planes and box faces are mapped in scene units and unit squares
+fn sphere_uv(normal: vec3<f32>) -> vec2<f32>
+{
+    return vec2<f32>(atan2(normal.x, normal.z) / (2.0 * PI) + 0.5, acos(clamp(normal.y, -1.0, 1.0)) / PI);
+}
+fn plane_uv(plane: Plane, p: vec3<f32>) -> vec2<f32>
+{
+    let normal = normalize(plane.normal);
+    let tangent = normalize(cross(select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(normal.y) > 0.9), normal));
+    return vec2<f32>(dot(p, tangent), dot(p, cross(normal, tangent)));
+}
+fn quad_uv(quad: Quad, p: vec3<f32>) -> vec2<f32>
+{
+    let n = cross(quad.edge_u, quad.edge_v);
+    let w = n / dot(n, n);
+    let d = p - quad.corner;
+    return vec2<f32>(dot(w, cross(d, quad.edge_v)), dot(w, cross(quad.edge_u, d)));
+}
+fn box_uv(aabb: Aabb, p: vec3<f32>, normal: vec3<f32>) -> vec2<f32>
+{
+    let f = (p - aabb.min_corner) / (aabb.max_corner - aabb.min_corner);
+    if (normal.x != 0.0) {
+        return f.zy;
+    }
+    if (normal.y != 0.0) {
+        return f.xz;
+    }
+    return f.xy;
+}
+fn intersect_plane(ray: Ray, plane: Plane) -> f32
+{
+    let denom = dot(plane.normal, ray.direction);
+    if (abs(denom) < 1e-8) {
+        return -1.0;
+    }
+    return (plane.offset - dot(plane.normal, ray.origin)) / denom;
+}
+fn intersect_quad(ray: Ray, quad: Quad) -> f32
+{
+    let n = cross(quad.edge_u, quad.edge_v);
+    let denom = dot(n, ray.direction);
+    if (abs(denom) < 1e-8) {
+        return -1.0;
+    }
+    let t = dot(n, quad.corner - ray.origin) / denom;
+    let p = ray.origin + t * ray.direction - quad.corner;
+    let w = n / dot(n, n);
+    let a = dot(w, cross(p, quad.edge_v));
+    let b = dot(w, cross(quad.edge_u, p));
+    if (a < 0.0 || a > 1.0 || b < 0.0 || b > 1.0) {
+        return -1.0;
+    }
+    return t;
+}
+fn intersect_box(ray: Ray, aabb: Aabb) -> f32
+{
+    let inv_direction = 1.0 / ray.direction;
+    let t0 = (aabb.min_corner - ray.origin) * inv_direction;
+    let t1 = (aabb.max_corner - ray.origin) * inv_direction;
+    let t_min = min(t0, t1);
+    let t_max = max(t0, t1);
+    let t_near = max(t_min.x, max(t_min.y, t_min.z));
+    let t_far = min(t_max.x, min(t_max.y, t_max.z));
+    if (t_near >= t_far) {
+        return -1.0;
+    }
+    return select(t_far, t_near, t_near > EPS);
+}
+fn box_normal(aabb: Aabb, p: vec3<f32>) -> vec3<f32>
+{
+    let center = 0.5 * (aabb.min_corner + aabb.max_corner);
+    let d = (p - center) / (0.5 * (aabb.max_corner - aabb.min_corner));
+    let a = abs(d);
+    if (a.x > a.y && a.x > a.z) {
+        return vec3<f32>(sign(d.x), 0.0, 0.0);
+    }
+    if (a.y > a.z) {
+        return vec3<f32>(0.0, sign(d.y), 0.0);
+    }
+    return vec3<f32>(0.0, 0.0, sign(d.z));
+}
+fn intersect_light(ray: Ray, light: Light) -> f32
+{
+    // delta lights cannot be hit
+    if (light.kind > LIGHT_DISK) {
+        return -1.0;
+    }
+    let n = cross(light.edge_u, light.edge_v);
+    let denom = dot(n, ray.direction);
+    if (abs(denom) < 1e-8) {
+        return -1.0;
+    }
+    let t = dot(n, light.position - ray.origin) / denom;
+    let p = ray.origin + t * ray.direction - light.position;
+    let a = dot(p, light.edge_u) / dot(light.edge_u, light.edge_u);
+    let b = dot(p, light.edge_v) / dot(light.edge_v, light.edge_v);
+    if (light.kind == LIGHT_DISK) {
+        return select(-1.0, t, a * a + b * b <= 1.0);
+    }
+    return select(-1.0, t, a >= 0.0 && a <= 1.0 && b >= 0.0 && b <= 1.0);
+}
+// radiance leaving a light towards -direction
+fn light_emission(light: Light, direction: vec3<f32>) -> vec3<f32>
+{
+    let facing = dot(cross(light.edge_u, light.edge_v), direction) < 0.0;
+    return select(vec3<f32>(0.0), illuminant(light.emission), facing || light.two_sided != 0u);
+}
 fn intersect(ray: Ray) -> Hit
 {
     var hit: Hit;
     hit.primitive_idx = -1;
+    hit.light_idx = -1;
+    hit.medium_idx = -1;
     hit.t = 1e30;
-    for (var i = 0; i < i32(arrayLength(&spheres)); i++)
+    intersection_tests += geometry.sphere_count + geometry.plane_count + geometry.quad_count + geometry.box_count + geometry.light_count;
+    for (var i = 0; i < i32(geometry.sphere_count); i++)
     {
-        let sphere = spheres[i];
-        let f = ray.origin - sphere.center;
-        let b = -dot(f, ray.direction);
-        let l = f + b * ray.direction;
-        let delta = sphere.radius * sphere.radius - dot(l, l);
-        if (delta > 0.0) {
-            let q = b + sign(b) * sqrt(delta);
-            let c = dot(f, f) - sphere.radius * sphere.radius;
-            let t0 = c / q;
-            let t1 = q;
-            if (t0 > EPS && t0 < hit.t) {
-                hit.t = t0;
-                hit.primitive_idx = i;
-            }
-            if (t1 > EPS && t1 < hit.t) {
-                hit.t = t1;
-                hit.primitive_idx = i;
-            }
+        var sphere = geometry.spheres[i];
+        sphere.center += sample_time * sphere.motion;
+        let t = intersect_sphere(ray, sphere);
+        if (t > EPS && t < hit.t) {
+            hit.t = t;
+            hit.primitive_idx = i;
+            hit.primitive_kind = PRIMITIVE_SPHERE;
+            hit.medium_idx = sphere.medium_idx;
+            hit.material_idx = sphere.material_idx;
+            hit.normal = normalize(ray.origin + t * ray.direction - sphere.center);
+            hit.uv = sphere_uv(hit.normal);
+        }
+    }
+    for (var i = 0; i < i32(geometry.plane_count); i++)
+    {
+        let plane = geometry.planes[i];
+        let t = intersect_plane(ray, plane);
+        if (t > EPS && t < hit.t) {
+            hit.t = t;
+            hit.primitive_idx = i;
+            hit.primitive_kind = PRIMITIVE_PLANE;
+            hit.medium_idx = -1;
+            hit.material_idx = plane.material_idx;
+            hit.normal = normalize(plane.normal);
+            hit.uv = plane_uv(plane, ray.origin + t * ray.direction);
+        }
+    }
+    for (var i = 0; i < i32(geometry.quad_count); i++)
+    {
+        var quad = geometry.quads[i];
+        quad.corner += sample_time * quad.motion;
+        let t = intersect_quad(ray, quad);
+        if (t > EPS && t < hit.t) {
+            hit.t = t;
+            hit.primitive_idx = i;
+            hit.primitive_kind = PRIMITIVE_QUAD;
+            hit.medium_idx = -1;
+            hit.material_idx = quad.material_idx;
+            hit.normal = normalize(cross(quad.edge_u, quad.edge_v));
+            hit.uv = quad_uv(quad, ray.origin + t * ray.direction);
+        }
+    }
+    for (var i = 0; i < i32(geometry.box_count); i++)
+    {
+        var aabb = geometry.boxes[i];
+        aabb.min_corner += sample_time * aabb.motion;
+        aabb.max_corner += sample_time * aabb.motion;
+        let t = intersect_box(ray, aabb);
+        if (t > EPS && t < hit.t) {
+            hit.t = t;
+            hit.primitive_idx = i;
+            hit.primitive_kind = PRIMITIVE_BOX;
+            hit.medium_idx = aabb.medium_idx;
+            hit.material_idx = aabb.material_idx;
+            hit.normal = box_normal(aabb, ray.origin + t * ray.direction);
+            hit.uv = box_uv(aabb, ray.origin + t * ray.direction, hit.normal);
+        }
+    }
+    for (var i = 0; i < i32(geometry.light_count); i++)
+    {
+        let light = geometry.lights[i];
+        let t = intersect_light(ray, light);
+        if (t > EPS && t < hit.t) {
+            hit.t = t;
+            hit.primitive_idx = -1;
+            hit.primitive_kind = PRIMITIVE_LIGHT;
+            hit.light_idx = i;
+            hit.medium_idx = -1;
+            hit.normal = normalize(cross(light.edge_u, light.edge_v));
         }
     }
     return hit;
 }
-fn radiance(ray: Ray) -> vec3<f32>
+fn srgb_to_linear(x: vec3<f32>) -> vec3<f32>
 {
-    var ray = ray;
-    var acc = vec3<f32>(0.0);
-    var amp = vec3<f32>(1.0);
+    return select(pow((x + 0.055) / 1.055, vec3<f32>(2.4)), x / 12.92, x <= vec3<f32>(0.04045));
+}
+fn lattice(cell: vec2<i32>, seed: u32) -> f32
+{
+    var h = seed * 0x9e3779b9u ^ u32(cell.x) * 0x85ebca6bu ^ u32(cell.y) * 0xc2b2ae35u;
+    h ^= h >> 15u;
+    h *= 0x2c1b3c6du;
+    h ^= h >> 12u;
+    h *= 0x297a2d39u;
+    h ^= h >> 15u;
+    return f32(h >> 8u) / 16777216.0;
+}
+fn value_noise(p: vec2<f32>, seed: u32) -> f32
+{
+    let cell = vec2<i32>(floor(p));
+    let f = fract(p);
+    let s = f * f * (3.0 - 2.0 * f);
+    let x0 = mix(lattice(cell, seed), lattice(cell + vec2<i32>(1, 0), seed), s.x);
+    let x1 = mix(lattice(cell + vec2<i32>(0, 1), seed), lattice(cell + vec2<i32>(1, 1), seed), s.x);
+    return mix(x0, x1, s.y);
+}
+fn fractal_noise(p: vec2<f32>, seed: u32) -> f32
+{
+    var sum = 0.0;
+    var amplitude = 0.5;
+    var frequency = 1.0;
+    for (var octave = 0u; octave < 4u; octave++)
+    {
+        sum += amplitude * value_noise(p * frequency, seed + octave);
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    return sum / (1.0 - 2.0 * amplitude);
+}
+// a material parameter: the texture at uv when the material has one, else the constant
+fn texture_value(texture_idx: i32, uv: vec2<f32>, constant: vec3<f32>) -> vec3<f32>
+{
+    if (texture_idx < 0) {
+        return constant;
+    }
+    let texture = shading.textures[texture_idx];
+    let st = uv * texture.scale;
+    // kinds as in scene/texture.rs: image, checker, noise, gradient
+    switch (texture.kind) {
+        case 0u: {
+            // explicit level, as sampling happens in non-uniform control flow
+            let texel = textureSampleLevel(images, image_sampler, st, texture.layer, 0.0).rgb;
+            return color_space.srgb_to_working * select(srgb_to_linear(texel), texel, texture.linear != 0u);
+        }
+        case 1u: {
+            let cell = vec2<i32>(floor(st));
+            return select(texture.color_a, texture.color_b, ((cell.x + cell.y) & 1) != 0);
+        }
+        case 2u: {
+            return mix(texture.color_a, texture.color_b, fractal_noise(st, texture.seed));
+        }
+        default: {
+            return mix(texture.color_a, texture.color_b, clamp(dot(uv, texture.scale), 0.0, 1.0));
+        }
+    }
+}
+fn surface_clean_coat(material: Material, uv: vec2<f32>) -> f32
+{
+    return texture_value(material.clean_coat_texture, uv, vec3<f32>(material.clean_coat)).r;
+}
+// extinction, and the fraction of it that scatters, at the traced wavelengths or channels
+fn medium_sigma_t(medium: Medium) -> vec3<f32>
+{
+    return unbounded(medium.sigma_t);
+}
+fn medium_albedo(medium: Medium) -> vec3<f32>
+{
+    return reflectance(select(vec3<f32>(0.0), medium.sigma_s / medium.sigma_t, medium.sigma_t > vec3<f32>(0.0)));
+}
+// trilinear interpolation by hand, as 32 bit float textures are not filterable
+fn grid_density(medium: Medium, p: vec3<f32>) -> f32
+{
+    let uvw = (p - medium.grid_min) / (medium.grid_max - medium.grid_min);
+    if (any(uvw < vec3<f32>(0.0)) || any(uvw > vec3<f32>(1.0))) {
+        return 0.0;
+    }
+    let x = uvw * vec3<f32>(medium.grid_size) - 0.5;
+    let base = floor(x);
+    let f = x - base;
+    let last = vec3<i32>(medium.grid_size) - 1;
+    let lo = clamp(vec3<i32>(base), vec3<i32>(0), last);
+    let hi = clamp(vec3<i32>(base) + 1, vec3<i32>(0), last);
+    let offset = medium.grid_offset;
+    let d000 = textureLoad(density_grids, vec3<i32>(lo.x, lo.y, lo.z + offset), 0).r;
+    let d100 = textureLoad(density_grids, vec3<i32>(hi.x, lo.y, lo.z + offset), 0).r;
+    let d010 = textureLoad(density_grids, vec3<i32>(lo.x, hi.y, lo.z + offset), 0).r;
+    let d110 = textureLoad(density_grids, vec3<i32>(hi.x, hi.y, lo.z + offset), 0).r;
+    let d001 = textureLoad(density_grids, vec3<i32>(lo.x, lo.y, hi.z + offset), 0).r;
+    let d101 = textureLoad(density_grids, vec3<i32>(hi.x, lo.y, hi.z + offset), 0).r;
+    let d011 = textureLoad(density_grids, vec3<i32>(lo.x, hi.y, hi.z + offset), 0).r;
+    let d111 = textureLoad(density_grids, vec3<i32>(hi.x, hi.y, hi.z + offset), 0).r;
+    let d00 = mix(d000, d100, f.x);
+    let d10 = mix(d010, d110, f.x);
+    let d01 = mix(d001, d101, f.x);
+    let d11 = mix(d011, d111, f.x);
+    return mix(mix(d00, d10, f.y), mix(d01, d11, f.y), f.z);
+}
+// part of the ray inside the grid, outside of which the density is zero
+fn grid_span(medium: Medium, ray: Ray, distance: f32) -> vec2<f32>
+{
+    let inv_direction = 1.0 / ray.direction;
+    let t0 = (medium.grid_min - ray.origin) * inv_direction;
+    let t1 = (medium.grid_max - ray.origin) * inv_direction;
+    let t_min = min(t0, t1);
+    let t_max = max(t0, t1);
+    return vec2<f32>(max(max(t_min.x, max(t_min.y, t_min.z)), 0.0), min(min(t_max.x, min(t_max.y, t_max.z)), distance));
+}
+/*
+    delta tracking against the majorant: returns the distance to the first real
+    collision, or a negative value when the ray gets through to `distance`
+*/
+fn delta_tracking(medium: Medium, ray: Ray, distance: f32) -> f32
+{
+    let span = grid_span(medium, ray, distance);
+    if (medium.majorant <= 0.0 || span.x >= span.y) {
+        return -1.0;
+    }
+    var t = span.x;
+    for (var i = 0; i < MAX_NULL_COLLISIONS; i++)
+    {
+        t -= log(1.0 - frand()) / medium.majorant;
+        if (t >= span.y) {
+            break;
+        }
+        let sigma_t = medium.sigma_t.r * grid_density(medium, ray.origin + t * ray.direction);
+        if (frand() * medium.majorant < sigma_t) {
+            return t;
+        }
+    }
+    return -1.0;
+}
+// closed form for homogeneous media, ratio tracking through density grids
+fn medium_transmittance(medium: Medium, ray: Ray, distance: f32) -> vec3<f32>
+{
+    if (medium.grid_offset < 0) {
+        return exp(-medium_sigma_t(medium) * distance);
+    }
+    let span = grid_span(medium, ray, distance);
+    if (medium.majorant <= 0.0 || span.x >= span.y) {
+        return vec3<f32>(1.0);
+    }
+    var result = 1.0;
+    var t = span.x;
+    for (var i = 0; i < MAX_NULL_COLLISIONS; i++)
+    {
+        t -= log(1.0 - frand()) / medium.majorant;
+        if (t >= span.y) {
+            break;
+        }
+        result *= 1.0 - medium.sigma_t.r * grid_density(medium, ray.origin + t * ray.direction) / medium.majorant;
+    }
+    return vec3<f32>(result);
+}
+/*
+    samples a scattering distance in a medium before `distance`, or returns a negative
+    value when the ray gets through, weighting `beta` by what the choice leaves out
+*/
+fn sample_free_flight(medium: Medium, ray: Ray, distance: f32, beta: ptr<function, vec3<f32>>) -> f32
+{
+    if (medium.grid_offset >= 0)
+    {
+        let t = delta_tracking(medium, ray, distance);
+        if (t >= 0.0) {
+            // a real collision scatters with the albedo's probability, folded into the weight
+            *beta *= medium_albedo(medium);
+        }
+        return t;
+    }
+    if (all(medium.sigma_t <= vec3<f32>(0.0))) {
+        return -1.0;
+    }
+    /*
+        free flight in one channel picked uniformly, weighted by the pdf averaged
+        over all channels, so that chromatic media stay unbiased in every channel
+    */
+    let sigma_t = medium_sigma_t(medium);
+    let channel = min(u32(frand() * 3.0), 2u);
+    let sigma = sigma_t[channel];
+    let t = select(1e30, -log(1.0 - frand()) / sigma, sigma > 0.0);
+    if (t < distance)
+    {
+        let tr = exp(-sigma_t * t);
+        *beta *= sigma_t * medium_albedo(medium) * tr / dot(sigma_t * tr, vec3<f32>(1.0 / 3.0));
+        return t;
+    }
+    let tr = exp(-sigma_t * distance);
+    *beta *= tr / max(dot(tr, vec3<f32>(1.0 / 3.0)), 1e-30);
+    return -1.0;
+}
+// shadow ray that passes through medium boundaries, attenuated by the media along the way
+fn transmittance(origin: vec3<f32>, direction: vec3<f32>, distance: f32, medium: u32) -> vec3<f32>
+{
+    var ray: Ray;
+    ray.origin = origin;
+    ray.direction = direction;
+    var medium = medium;
+    var remaining = distance;
+    var result = vec3<f32>(1.0);
     for (var i = 0; i < MAX_BOUNCE; i++)
     {
         let hit = intersect(ray);
-        if (hit.primitive_idx < 0)
-        {
-            break;
+        if (hit.t >= remaining * (1.0 - 1e-3)) {
+            return result * medium_transmittance(shading.media[medium], ray, remaining);
         }
-        let sphere = spheres[hit.primitive_idx];
-        ray.origin += hit.t * ray.direction;
-        var normal = normalize(ray.origin - sphere.center);
-        normal *= select(1.0, -1.0, dot(ray.direction, normal) > 0.0);
-        ray.origin += normal * EPS;
-        let material = materials[sphere.material_idx];
-        acc += material.emission * amp * PI;
-        var u = frand();
-        if (u < material.clean_coat)
-        {
-            ray.direction = reflect(ray.direction, normal);
-            u /= material.clean_coat;
+        if (hit.medium_idx < 0 || is_dielectric(shading.materials[hit.material_idx])) {
+            return vec3<f32>(0.0);
         }
-        else
-        {
-            amp *= material.color;
-            ray.direction = sample_cosine_hemisphere(normal);
-            u = (u - material.clean_coat) / (1.0 - material.clean_coat);
+        // the clean coat reflects, only the rest of the light crosses the boundary
+        result *= medium_transmittance(shading.media[medium], ray, hit.t) * (1.0 - surface_clean_coat(shading.materials[hit.material_idx], hit.uv));
+        medium = select(0u, u32(hit.medium_idx) + 1u, dot(direction, hit.normal) < 0.0);
+        ray.origin += hit.t * direction + sign(dot(direction, hit.normal)) * hit.normal * EPS;
+        remaining -= hit.t;
+    }
+    return vec3<f32>(0.0);
+}
+/*
+    Solid angle sampling of a spherical rectangle, after Urena et al.,
+    "An Area-Preserving Parametrization for Spherical Rectangles", 2013
+*/
+fn sample_rectangle_light(light: Light, origin: vec3<f32>) -> LightSample
+{
+    var sample: LightSample;
+    sample.pdf = 0.0;
+    let extent_u = length(light.edge_u);
+    let extent_v = length(light.edge_v);
+    let x = light.edge_u / extent_u;
+    let y = light.edge_v / extent_v;
+    var z = cross(x, y);
+    let d = light.position - origin;
+    let x0 = dot(d, x);
+    let y0 = dot(d, y);
+    var z0 = dot(d, z);
+    if (z0 > 0.0) {
+        z0 = -z0;
+        z = -z;
+    }
+    let x1 = x0 + extent_u;
+    let y1 = y0 + extent_v;
+    let v00 = vec3<f32>(x0, y0, z0);
+    let v01 = vec3<f32>(x0, y1, z0);
+    let v10 = vec3<f32>(x1, y0, z0);
+    let v11 = vec3<f32>(x1, y1, z0);
+    let n0 = normalize(cross(v00, v10));
+    let n1 = normalize(cross(v10, v11));
+    let n2 = normalize(cross(v11, v01));
+    let n3 = normalize(cross(v01, v00));
+    let g0 = acos(clamp(-dot(n0, n1), -1.0, 1.0));
+    let g1 = acos(clamp(-dot(n1, n2), -1.0, 1.0));
+    let g2 = acos(clamp(-dot(n2, n3), -1.0, 1.0));
+    let g3 = acos(clamp(-dot(n3, n0), -1.0, 1.0));
+    let k = 2.0 * PI - g2 - g3;
+    let solid_angle = g0 + g1 - k;
+    if (solid_angle < 1e-6) {
+        return sample;
+    }
+    let u = frand2();
+    let au = u.x * solid_angle + k;
+    let fu = (cos(au) * n0.z - n2.z) / sin(au);
+    let cu = clamp(select(-1.0, 1.0, fu > 0.0) / sqrt(fu * fu + n0.z * n0.z), -1.0, 1.0);
+    let xu = clamp(-(cu * z0) / max(sqrt(1.0 - cu * cu), 1e-7), x0, x1);
+    let dist = sqrt(xu * xu + z0 * z0);
+    let h0 = y0 / sqrt(dist * dist + y0 * y0);
+    let h1 = y1 / sqrt(dist * dist + y1 * y1);
+    let hv = h0 + u.y * (h1 - h0);
+    let yv = select(y1, (hv * dist) / sqrt(1.0 - hv * hv), hv * hv < 1.0 - 1e-6);
+    let offset = xu * x + yv * y + z0 * z;
+    sample.distance = length(offset);
+    sample.direction = offset / sample.distance;
+    sample.radiance = light_emission(light, sample.direction);
+    sample.pdf = 1.0 / solid_angle;
+    return sample;
+}
+fn sample_disk_light(light: Light, origin: vec3<f32>) -> LightSample
+{
+    var sample: LightSample;
+    sample.pdf = 0.0;
+    let u = frand2();
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let offset = light.position + r * (cos(phi) * light.edge_u + sin(phi) * light.edge_v) - origin;
+    sample.distance = length(offset);
+    sample.direction = offset / sample.distance;
+    let cos_light = abs(dot(normalize(cross(light.edge_u, light.edge_v)), sample.direction));
+    if (cos_light < 1e-6) {
+        return sample;
+    }
+    sample.radiance = light_emission(light, sample.direction);
+    sample.pdf = sample.distance * sample.distance / (light.area * cos_light);
+    return sample;
+}
+fn ies_profile(profile_idx: i32, cos_theta: f32) -> f32
+{
+    if (profile_idx < 0) {
+        return 1.0;
+    }
+    let x = acos(clamp(cos_theta, -1.0, 1.0)) / PI * f32(IES_SAMPLES - 1);
+    let i = min(i32(x), IES_SAMPLES - 2);
+    let base = profile_idx * IES_SAMPLES + i;
+    return mix(geometry.ies_profiles[base], geometry.ies_profiles[base + 1], x - f32(i));
+}
+// share of a point or spot light's intensity going in a direction away from it
+fn spot_falloff(light: Light, direction: vec3<f32>) -> f32
+{
+    if (light.kind != LIGHT_SPOT) {
+        return 1.0;
+    }
+    let cos_theta = dot(light.edge_u, direction);
+    let falloff = smoothstep(0.0, 1.0, (cos_theta - light.cos_outer) / max(light.cos_inner - light.cos_outer, 1e-6));
+    return falloff * ies_profile(light.profile_idx, cos_theta);
+}
+// intensity of a point or spot light in a direction away from it
+fn light_intensity(light: Light, direction: vec3<f32>) -> vec3<f32>
+{
+    return illuminant(light.emission) * spot_falloff(light, direction);
+}
+// uniform direction within a cone, the whole sphere when cos_max is -1
+fn sample_cone(axis: vec3<f32>, cos_max: f32) -> vec3<f32>
+{
+    return cone_direction(axis, cos_max, frand2());
+}
+fn cone_direction(axis: vec3<f32>, cos_max: f32, u: vec2<f32>) -> vec3<f32>
+{
+    let cos_theta = 1.0 - u.x * (1.0 - cos_max);
+    let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    let phi = 2.0 * PI * u.y;
+    let tangent = normalize(cross(select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), abs(axis.x) > 0.9), axis));
+    let binormal = cross(axis, tangent);
+    return cos_theta * axis + sin_theta * (cos(phi) * tangent + sin(phi) * binormal);
+}
+// delta lights are sampled with pdf 1 and report irradiance at the origin as radiance
+fn sample_point_light(light: Light, origin: vec3<f32>) -> LightSample
+{
+    var sample: LightSample;
+    let offset = light.position - origin;
+    sample.distance = length(offset);
+    sample.direction = offset / sample.distance;
+    sample.radiance = light_intensity(light, -sample.direction) / (sample.distance * sample.distance);
+    sample.pdf = 1.0;
+    return sample;
+}
+fn sample_directional_light(light: Light) -> LightSample
+{
+    var sample: LightSample;
+    // uniform direction within the cone of the light's disk
+    sample.direction = sample_cone(-light.edge_u, light.cos_outer);
+    sample.distance = 1e30;
+    sample.radiance = illuminant(light.emission);
+    sample.pdf = 1.0;
+    return sample;
+}
+fn sample_light(light: Light, origin: vec3<f32>) -> LightSample
+{
+    switch (light.kind) {
+        case 2u, 3u: {
+            return sample_point_light(light, origin);
         }
-        let p1 = max(amp.r, max(amp.g, amp.b));
-        if (p1 < 1e-2) {
-            break;
+        case 4u: {
+            return sample_directional_light(light);
         }
-        let p2 = min(amp.r, max(amp.g, amp.b));
-        if (i > 6)
-        {
-            if (u < p2)
-            {
-                amp *= 1.0 / p2;
+        default: {
+            if (light.area <= 0.0) {
+                var sample: LightSample;
+                sample.pdf = 0.0;
+                return sample;
             }
-            else
-            {
-                break;
+            if (light.kind == LIGHT_DISK) {
+                return sample_disk_light(light, origin);
             }
+            return sample_rectangle_light(light, origin);
         }
     }
-    return acc;
 }
-fn aces_tone_mapping(x: vec3<f32>) -> vec3<f32>
+/*
+    Preetham et al., "A Practical Analytic Model for Daylight", 1999,
+    with the coefficients precomputed on the host
+*/
+fn sky_radiance(direction: vec3<f32>) -> vec3<f32>
 {
-    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), vec3<f32>(0.0), vec3<f32>(1.0));
+    if (sky.enabled == 0u) {
+        return vec3<f32>(0.0);
+    }
+    if (direction.y < 0.0) {
+        return color_space.srgb_to_working * sky.ground_radiance;
+    }
+    let cos_theta = max(direction.y, 1e-3);
+    let gamma = acos(clamp(dot(direction, sky.sun_direction), -1.0, 1.0));
+    let f = (1.0 + sky.perez[0].xyz * exp(sky.perez[1].xyz / cos_theta))
+        * (1.0 + sky.perez[2].xyz * exp(sky.perez[3].xyz * gamma) + sky.perez[4].xyz * cos(gamma) * cos(gamma));
+    let yxy = sky.zenith * f;
+    let xyz = vec3<f32>(yxy.y / yxy.z * yxy.x, yxy.x, (1.0 - yxy.y - yxy.z) / yxy.z * yxy.x);
+    return max(color_space.xyz_to_working * xyz, vec3<f32>(0.0));
 }
-fn linear_to_srgb(x: vec3<f32>) -> vec3<f32>
+// what a camera or mirror ray sees when it leaves the scene
+fn environment_radiance(direction: vec3<f32>) -> vec3<f32>
 {
-    return clamp(select(1.055 * pow(x, vec3<f32>(1.0 / 2.4)) - 0.055, 12.92 * x, x <= 0.00031308), vec3<f32>(0.0), vec3<f32>(1.0));
+    let in_sun = sky.enabled != 0u && dot(direction, sky.sun_direction) >= sky.cos_sun_radius;
+    let sun = color_space.srgb_to_working * sky.sun_radiance;
+    return illuminant(sky_radiance(direction) + select(vec3<f32>(0.0), sun, in_sun));
+}
+// index of the first entry in a cumulative table that reaches u
+fn search_cdf(offset: u32, count: u32, u: f32) -> u32
+{
+    var lo = 0u;
+    var hi = count - 1u;
+    while (lo < hi) {
+        let mid = (lo + hi) / 2u;
+        if (geometry.sky_distribution[offset + mid] < u) {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+fn sample_sky() -> LightSample
+{
+    let texels = SKY_WIDTH * SKY_HEIGHT;
+    let u = frand2();
+    let v = frand2();
+    let row = search_cdf(2u * texels, SKY_HEIGHT, u.x);
+    let column = search_cdf(texels + row * SKY_WIDTH, SKY_WIDTH, u.y);
+    let theta = (f32(row) + v.x) / f32(SKY_HEIGHT) * PI;
+    let phi = (f32(column) + v.y) / f32(SKY_WIDTH) * 2.0 * PI;
+    var sample: LightSample;
+    sample.direction = vec3<f32>(sin(theta) * sin(phi), cos(theta), sin(theta) * cos(phi));
+    sample.distance = 1e30;
+    sample.radiance = illuminant(sky_radiance(sample.direction));
+    // texel probability spread uniformly over its theta and phi range
+    let texel_pdf = geometry.sky_distribution[row * SKY_WIDTH + column];
+    sample.pdf = texel_pdf * f32(texels) / (2.0 * PI * PI * max(sin(theta), 1e-6));
+    return sample;
+}
+// one light picked uniformly, with the pick folded into the pdf
+fn sample_lights(origin: vec3<f32>) -> LightSample
+{
+    let light_count = geometry.light_count;
+    let light_idx = min(u32(frand() * f32(light_count)), light_count - 1u);
+    var sample = sample_light(geometry.lights[light_idx], origin);
+    sample.pdf /= f32(light_count);
+    return sample;
+}
+// radiance arriving along a light sample through the media, divided by its pdf
+fn incident_radiance(origin: vec3<f32>, sample: LightSample, medium: u32) -> vec3<f32>
+{
+    if (sample.pdf <= 0.0 || all(sample.radiance == vec3<f32>(0.0))) {
+        return vec3<f32>(0.0);
+    }
+    return sample.radiance * transmittance(origin, sample.direction, sample.distance, medium) / sample.pdf;
+}
+fn sky_lighting(origin: vec3<f32>, normal: vec3<f32>, medium: u32) -> vec3<f32>
+{
+    if (sky.enabled == 0u) {
+        return vec3<f32>(0.0);
+    }
+    let sample = sample_sky();
+    let cos_theta = dot(normal, sample.direction);
+    if (cos_theta <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+    return incident_radiance(origin, sample, medium) * cos_theta / PI;
+}
+// next event estimation for a Lambertian vertex
+fn direct_lighting(origin: vec3<f32>, normal: vec3<f32>, medium: u32) -> vec3<f32>
+{
+    let sample = sample_lights(origin);
+    let cos_theta = dot(normal, sample.direction);
+    if (cos_theta <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+    return incident_radiance(origin, sample, medium) * cos_theta / PI;
+}
+// cos_theta is between the directions of travel before and after scattering
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32
+{
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * denom * sqrt(denom));
+}
+fn sample_henyey_greenstein(direction: vec3<f32>, g: f32) -> vec3<f32>
+{
+    let u2 = frand2();
+    var cos_theta = 1.0 - 2.0 * u2.r;
+    if (abs(g) > 1e-3) {
+        let sq = (1.0 - g * g) / (1.0 - g + 2.0 * g * u2.r);
+        cos_theta = (1.0 + g * g - sq * sq) / (2.0 * g);
+    }
+    let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    let phi = 2.0 * PI * u2.g;
+    let tangent = normalize(cross(select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 1.0), abs(direction.r) < 1e-3), direction));
+    let binormal = cross(direction, tangent);
+    return cos_theta * direction + sin_theta * (cos(phi) * tangent + sin(phi) * binormal);
+}
+// next event estimation for a scattering event in a medium, lights and sky alike
+fn medium_lighting(origin: vec3<f32>, direction: vec3<f32>, medium: u32) -> vec3<f32>
+{
+    let g = shading.media[medium].g;
+    let sample = sample_lights(origin);
+    var result = incident_radiance(origin, sample, medium) * henyey_greenstein(dot(direction, sample.direction), g);
+    if (sky.enabled != 0u) {
+        let sky_sample = sample_sky();
+        result += incident_radiance(origin, sky_sample, medium) * henyey_greenstein(dot(direction, sky_sample.direction), g);
+    }
+    return result;
+}
+fn is_dielectric(material: Material) -> bool
+{
+    return material.ior > 0.0 || any(material.sellmeier_b != vec3<f32>(0.0));
+}
+fn is_dispersive(material: Material) -> bool
+{
+    return material.dispersion != 0.0 || any(material.sellmeier_b != vec3<f32>(0.0));
+}
+// index of refraction at a wavelength in nanometers, by Sellmeier's equation or else Cauchy's
+fn material_ior(material: Material, lambda: f32) -> f32
+{
+    let l2 = lambda * lambda * 1e-6;
+    if (any(material.sellmeier_b != vec3<f32>(0.0))) {
+        let n2 = 1.0 + dot(material.sellmeier_b * l2 / (l2 - material.sellmeier_c), vec3<f32>(1.0));
+        return sqrt(max(n2, 1.0));
+    }
+    return material.ior + material.dispersion * (1.0 / l2 - 1.0 / (0.5893 * 0.5893));
+}
+// unpolarized reflectance of a smooth dielectric, eta being the incident over the transmitted index
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32
+{
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if (sin2_t >= 1.0) {
+        return 1.0;
+    }
+    let cos_t = sqrt(1.0 - sin2_t);
+    let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return 0.5 * (r_s * r_s + r_p * r_p);
+}
+// russian roulette after a few bounces, reweighting the paths that survive
+fn survives_roulette(amp: ptr<function, vec3<f32>>, bounce: i32, u: f32) -> bool
+{
+    let p1 = max((*amp).r, max((*amp).g, (*amp).b));
+    if (p1 < 1e-2) {
+        return false;
+    }
+    let p2 = min((*amp).r, max((*amp).g, (*amp).b));
+    if (bounce > 6)
+    {
+        if (u < p2)
+        {
+            *amp *= 1.0 / p2;
+        }
+        else
+        {
+            return false;
+        }
+    }
+    return true;
+}
+fn radiance(ray: Ray) -> vec3<f32>
+{
+    var ray = ray;
+    var acc = vec3<f32>(0.0);
+    var amp = vec3<f32>(1.0);
+    // camera rays and mirror bounces see lights directly, diffuse bounces sample them
+    var specular = true;
+    // index into media; the camera is assumed to be outside of medium spheres
+    var medium = 0u;
+    // set once a dispersive surface has split the path onto the hero wavelength alone
+    var dispersed = false;
+    // the first scattering event and the bounce it happened at, for the compositing passes
+    var first_lobe = LOBE_NONE;
+    var first_bounce = 0;
+    aov_lighting = array<vec3<f32>, 4>();
+    for (var i = 0; i < MAX_BOUNCE; i++)
+    {
+        path_length = u32(i + 1);
+        let hit = intersect(ray);
+        if (i == 0) {
+            aov_hit = hit;
+        }
+        let t = sample_free_flight(shading.media[medium], ray, hit.t, &amp);
+        if (t >= 0.0)
+        {
+            ray.origin += t * ray.direction;
+            if (first_lobe == LOBE_NONE)
+            {
+                first_lobe = LOBE_DIFFUSE;
+                first_bounce = i;
+            }
+            let lighting = amp * medium_lighting(ray.origin, ray.direction, medium);
+            acc += lighting;
+            aov_add(lighting, first_lobe, i == first_bounce);
+            ray.direction = sample_henyey_greenstein(ray.direction, shading.media[medium].g);
+            specular = false;
+            if (!survives_roulette(&amp, i, frand())) {
+                break;
+            }
+            continue;
+        }
+        if (hit.light_idx >= 0)
+        {
+            if (specular)
+            {
+                let emitted = amp * light_emission(geometry.lights[hit.light_idx], ray.direction);
+                acc += emitted;
+                aov_add(emitted, first_lobe, i == first_bounce + 1);
+            }
+            break;
+        }
+        if (hit.primitive_idx < 0)
+        {
+            if (specular)
+            {
+                let emitted = amp * environment_radiance(ray.direction);
+                acc += emitted;
+                aov_add(emitted, first_lobe, i == first_bounce + 1);
+            }
+            break;
+        }
+        ray.origin += hit.t * ray.direction;
+        var normal = hit.normal;
+        let entering = dot(ray.direction, normal) < 0.0;
+        normal *= select(1.0, -1.0, dot(ray.direction, normal) > 0.0);
+        ray.origin += normal * EPS;
+        let material = shading.materials[hit.material_idx];
+        let emission = illuminant(texture_value(material.emission_texture, hit.uv, material.emission)) * amp * PI;
+        acc += emission;
+        aov_add(emission, first_lobe, i == first_bounce + 1);
+        if (is_dielectric(material))
+        {
+            if (first_lobe == LOBE_NONE) {
+                first_lobe = LOBE_SPECULAR;
+            }
+            /*
+                the companion wavelengths would refract elsewhere, so they end here
+                and the hero wavelength carries the estimate for all three
+            */
+            if (settings.spectral != 0u && is_dispersive(material) && !dispersed)
+            {
+                amp *= vec3<f32>(3.0, 0.0, 0.0);
+                dispersed = true;
+            }
+            let ior = material_ior(material, select(589.3, wavelengths.x, settings.spectral != 0u));
+            let eta = select(ior, 1.0 / ior, entering);
+            if (frand() < fresnel_dielectric(-dot(ray.direction, normal), eta))
+            {
+                ray.direction = reflect(ray.direction, normal);
+            }
+            else
+            {
+                ray.direction = refract(ray.direction, normal, eta);
+                ray.origin -= 2.0 * normal * EPS;
+                amp *= reflectance(texture_value(material.color_texture, hit.uv, material.color));
+                if (hit.medium_idx >= 0) {
+                    medium = select(0u, u32(hit.medium_idx) + 1u, entering);
+                }
+            }
+            specular = true;
+            if (!survives_roulette(&amp, i, frand())) {
+                break;
+            }
+            continue;
+        }
+        let clean_coat = surface_clean_coat(material, hit.uv);
+        var u = frand();
+        if (hit.medium_idx >= 0 && u >= clean_coat)
+        {
+            // medium boundary: carry on in the same direction on the other side
+            ray.origin -= 2.0 * normal * EPS;
+            medium = select(0u, u32(hit.medium_idx) + 1u, entering);
+            continue;
+        }
+        if (u < clean_coat)
+        {
+            if (first_lobe == LOBE_NONE) {
+                first_lobe = LOBE_SPECULAR;
+            }
+            ray.direction = reflect(ray.direction, normal);
+            specular = true;
+            u /= clean_coat;
+        }
+        else
+        {
+            amp *= reflectance(texture_value(material.color_texture, hit.uv, material.color));
+            if (first_lobe == LOBE_NONE)
+            {
+                first_lobe = LOBE_DIFFUSE;
+                first_bounce = i;
+            }
+            var direct: vec3<f32>;
+            if (i == 0 && restir_pixel >= 0) {
+                direct = restir_lighting(ray.origin, normal, medium);
+            } else {
+                direct = direct_lighting(ray.origin, normal, medium);
+            }
+            let lighting = amp * (direct + sky_lighting(ray.origin, normal, medium));
+            acc += lighting;
+            aov_add(lighting, first_lobe, i == first_bounce);
+            ray.direction = sample_cosine_hemisphere(normal);
+            specular = false;
+            u = (u - clean_coat) / (1.0 - clean_coat);
+        }
+        if (!survives_roulette(&amp, i, u)) {
+            break;
+        }
+    }
+    return acc;
+}
+// entry of the accumulate buffer for a pixel, in integers so that every pass agrees on it
+/*
+    files light reaching the camera under a compositing pass by how its path first scattered;
+    direct is set for the lighting of the first diffuse hit and for emission seen right after it
+*/
+fn aov_add(value: vec3<f32>, first_lobe: u32, direct: bool)
+{
+    if (!aov_active) {
+        return;
+    }
+    if (first_lobe == LOBE_NONE) {
+        aov_lighting[AOV_EMISSION] += value;
+    } else if (first_lobe == LOBE_SPECULAR) {
+        aov_lighting[AOV_SPECULAR] += value;
+    } else if (direct) {
+        aov_lighting[AOV_DIRECT] += value;
+    } else {
+        aov_lighting[AOV_INDIRECT] += value;
+    }
+}
+// adds the passes of the path radiance just traced to a frame's sum
+fn add_aov_sample(sum: Aov) -> Aov
+{
+    var sum = sum;
+    sum.direct_diffuse += spectrum_to_rgb(aov_lighting[AOV_DIRECT]);
+    sum.indirect_diffuse += spectrum_to_rgb(aov_lighting[AOV_INDIRECT]);
+    sum.specular += spectrum_to_rgb(aov_lighting[AOV_SPECULAR]);
+    sum.emission += spectrum_to_rgb(aov_lighting[AOV_EMISSION]);
+    let hit = aov_hit;
+    sum.depth = hit.t;
+    sum.object_id = 0u;
+    if (hit.light_idx >= 0)
+    {
+        sum.normal += hit.normal;
+        sum.object_id = (PRIMITIVE_LIGHT << 16u | u32(hit.light_idx)) + 1u;
+    }
+    else if (hit.primitive_idx >= 0)
+    {
+        let material = shading.materials[hit.material_idx];
+        sum.albedo += texture_value(material.color_texture, hit.uv, material.color);
+        sum.normal += hit.normal;
+        sum.object_id = (hit.primitive_kind << 16u | u32(hit.primitive_idx)) + 1u;
+    }
+    return sum;
+}
+// folds a frame's sum over `samples` paths into the running average
+fn store_aovs(idx: u32, sum: Aov, samples: f32)
+{
+    var aov = aovs[idx];
+    if (camera.frame_idx == 0u) {
+        aov.frames = 0u;
+    }
+    let weight = 1.0 / f32(aov.frames + 1u);
+    aov.direct_diffuse = mix(aov.direct_diffuse, sum.direct_diffuse / samples, weight);
+    aov.indirect_diffuse = mix(aov.indirect_diffuse, sum.indirect_diffuse / samples, weight);
+    aov.specular = mix(aov.specular, sum.specular / samples, weight);
+    aov.emission = mix(aov.emission, sum.emission / samples, weight);
+    aov.albedo = mix(aov.albedo, sum.albedo / samples, weight);
+    aov.normal = mix(aov.normal, sum.normal / samples, weight);
+    aov.depth = sum.depth;
+    aov.object_id = sum.object_id;
+    aov.frames += 1u;
+    aovs[idx] = aov;
+}
+fn pixel_index(pixel: vec2<u32>) -> u32
+{
+    let cell = pixel * ACCUMULATE_SIZE / camera.resolution;
+    return cell.g | cell.r << 10u;
+}
+fn accumulate_index(ndc: vec2<f32>) -> u32
+{
+    let uv = clamp(ndc * 0.5 + 0.5, vec2<f32>(0.0), vec2<f32>(1.0));
+    return pixel_index(min(vec2<u32>(uv * vec2<f32>(camera.resolution)), camera.resolution - 1u));
+}
+// where a direction from the camera lands on the screen, outside of [-1, 1] when it misses
+fn camera_ndc(direction: vec3<f32>) -> vec2<f32>
+{
+    let right = sample_raygen[0].xyz;
+    let up = sample_raygen[1].xyz;
+    let forward = sample_raygen[2].xyz;
+    let z = dot(direction, forward) / dot(forward, forward);
+    if (z <= 0.0) {
+        return vec2<f32>(2.0);
+    }
+    return vec2<f32>(dot(direction, right) / dot(right, right), dot(direction, up) / dot(up, up)) / z;
+}
+// solid angle density of camera rays spread over the whole screen; importance is this over cos theta
+fn camera_pdf(direction: vec3<f32>) -> f32
+{
+    // light tracing only connects to the pinhole camera with a single view
+    if (camera.projection != PROJECTION_PERSPECTIVE || camera.stereo != STEREO_OFF || any(abs(camera_ndc(direction)) > vec2<f32>(1.0))) {
+        return 0.0;
+    }
+    let forward = sample_raygen[2].xyz;
+    let cos_theta = dot(direction, normalize(forward));
+    let area = 4.0 * length(sample_raygen[0].xyz) * length(sample_raygen[1].xyz) / dot(forward, forward);
+    return 1.0 / (area * cos_theta * cos_theta * cos_theta);
+}
+// radiance of an area light or intensity of a point light, leaving in a direction
+fn light_radiance(light: Light, direction: vec3<f32>) -> vec3<f32>
+{
+    if (light.kind <= LIGHT_DISK) {
+        return light_emission(light, -direction);
+    }
+    return light_intensity(light, direction);
+}
+// solid angle density of light subpaths leaving a light in a direction
+fn light_direction_pdf(light: Light, direction: vec3<f32>) -> f32
+{
+    if (light.kind <= LIGHT_DISK) {
+        let cos_theta = dot(normalize(cross(light.edge_u, light.edge_v)), direction);
+        return select(max(cos_theta, 0.0) / PI, abs(cos_theta) / (2.0 * PI), light.two_sided != 0u);
+    }
+    let cos_max = select(-1.0, light.cos_outer, light.kind == LIGHT_SPOT);
+    return select(0.0, 1.0 / (2.0 * PI * (1.0 - cos_max)), light.kind != LIGHT_SPOT || dot(light.edge_u, direction) >= cos_max);
+}
+fn on_surface(v: PathVertex) -> bool
+{
+    return v.kind == VERTEX_SURFACE || (v.kind == VERTEX_LIGHT && geometry.lights[v.light_idx].kind <= LIGHT_DISK);
+}
+// solid angle density at `start` turned into area density at `to`
+fn convert_density(pdf: f32, start: vec3<f32>, to: PathVertex) -> f32
+{
+    let offset = to.position - start;
+    let distance2 = dot(offset, offset);
+    if (distance2 == 0.0) {
+        return 0.0;
+    }
+    var result = pdf / distance2;
+    if (on_surface(to)) {
+        result *= abs(dot(to.normal, offset)) / sqrt(distance2);
+    }
+    return result;
+}
+// scattering from `prev` through v to `next`: the BSDF, phase function or emission, without cosines
+fn vertex_f(v: PathVertex, prev: vec3<f32>, next: vec3<f32>) -> vec3<f32>
+{
+    let wi = normalize(next - v.position);
+    // kinds as in VERTEX_*
+    switch (v.kind) {
+        case 1u: {
+            return light_radiance(geometry.lights[v.light_idx], wi);
+        }
+        case 2u: {
+            let wo = normalize(prev - v.position);
+            if (v.delta || dot(wo, v.normal) <= 0.0 || dot(wi, v.normal) <= 0.0) {
+                return vec3<f32>(0.0);
+            }
+            return v.albedo / PI;
+        }
+        case 3u: {
+            return vec3<f32>(henyey_greenstein(dot(normalize(v.position - prev), wi), v.g));
+        }
+        default: {
+            return vec3<f32>(0.0);
+        }
+    }
+}
+// area density at `next` of v sampling it, having been reached from `prev`
+fn vertex_pdf(v: PathVertex, prev: vec3<f32>, next: PathVertex) -> f32
+{
+    let wi = normalize(next.position - v.position);
+    var pdf = 0.0;
+    switch (v.kind) {
+        case 0u: {
+            pdf = camera_pdf(wi);
+        }
+        case 1u: {
+            pdf = light_direction_pdf(geometry.lights[v.light_idx], wi);
+        }
+        case 2u: {
+            let wo = normalize(prev - v.position);
+            if (dot(wo, v.normal) > 0.0 && dot(wi, v.normal) > 0.0) {
+                pdf = v.diffuse * dot(wi, v.normal) / PI;
+            }
+        }
+        default: {
+            pdf = henyey_greenstein(dot(normalize(v.position - prev), wi), v.g);
+        }
+    }
+    return convert_density(pdf, v.position, next);
+}
+// a point just off a surface vertex on the side facing `toward`
+fn vertex_origin(v: PathVertex, toward: vec3<f32>) -> vec3<f32>
+{
+    if (!on_surface(v)) {
+        return v.position;
+    }
+    return v.position + v.normal * EPS * sign(dot(v.normal, toward - v.position));
+}
+// shadow ray from a camera subpath vertex to a light subpath vertex, with the cosines and squared distance
+fn geometry_term(a: PathVertex, b: PathVertex) -> vec3<f32>
+{
+    let offset = b.position - a.position;
+    let direction = normalize(offset);
+    var g = 1.0 / dot(offset, offset);
+    if (on_surface(a)) {
+        g *= abs(dot(a.normal, direction));
+    }
+    if (on_surface(b)) {
+        g *= abs(dot(b.normal, direction));
+    }
+    let origin = vertex_origin(a, b.position);
+    let end = vertex_origin(b, a.position);
+    let distance = length(end - origin);
+    return g * transmittance(origin, (end - origin) / distance, distance, a.medium);
+}
+/*
+    extends a subpath of `count` vertices along `ray`, `beta` being the throughput so far
+    and `pdf` the solid angle density of the ray, and returns the new vertex count
+*/
+fn random_walk(path: ptr<private, array<PathVertex, BDPT_VERTICES>>, count: u32, ray: Ray, beta: vec3<f32>, pdf: f32, max_count: u32, camera_subpath: bool) -> u32
+{
+    var ray = ray;
+    var beta = beta;
+    var pdf_fwd = pdf;
+    var medium = 0u;
+    var dispersed = false;
+    var n = count;
+    for (var i = 0; i < MAX_BOUNCE && n < max_count; i++)
+    {
+        let hit = intersect(ray);
+        let t = sample_free_flight(shading.media[medium], ray, hit.t, &beta);
+        let prev = (*path)[n - 1u];
+        var v: PathVertex;
+        v.beta = beta;
+        v.medium = medium;
+        v.light_idx = -1;
+        v.dispersed = dispersed;
+        if (t >= 0.0)
+        {
+            v.kind = VERTEX_MEDIUM;
+            v.position = ray.origin + t * ray.direction;
+            v.g = shading.media[medium].g;
+            v.pdf_fwd = convert_density(pdf_fwd, prev.position, v);
+            (*path)[n] = v;
+            n++;
+            let direction = sample_henyey_greenstein(ray.direction, v.g);
+            // the phase function is symmetric, so the reverse density is the same
+            pdf_fwd = henyey_greenstein(dot(ray.direction, direction), v.g);
+            (*path)[n - 2u].pdf_rev = convert_density(pdf_fwd, v.position, prev);
+            ray.origin = v.position;
+            ray.direction = direction;
+            continue;
+        }
+        if (hit.light_idx >= 0)
+        {
+            if (camera_subpath)
+            {
+                let light = geometry.lights[hit.light_idx];
+                v.kind = VERTEX_LIGHT;
+                v.position = ray.origin + hit.t * ray.direction;
+                v.normal = normalize(cross(light.edge_u, light.edge_v));
+                v.light_idx = hit.light_idx;
+                v.pdf_fwd = convert_density(pdf_fwd, prev.position, v);
+                (*path)[n] = v;
+                n++;
+            }
+            break;
+        }
+        if (hit.primitive_idx < 0)
+        {
+            // elsewhere the sky is sampled from camera vertices, as in the path tracer
+            if (camera_subpath && (prev.delta || prev.kind == VERTEX_CAMERA)) {
+                unconnected_radiance += beta * environment_radiance(ray.direction);
+            }
+            break;
+        }
+        let position = ray.origin + hit.t * ray.direction;
+        let entering = dot(ray.direction, hit.normal) < 0.0;
+        let normal = select(-hit.normal, hit.normal, entering);
+        let material = shading.materials[hit.material_idx];
+        let clean_coat = surface_clean_coat(material, hit.uv);
+        let dielectric = is_dielectric(material);
+        // emissive materials are not lights, so only camera subpaths find them
+        if (camera_subpath) {
+            unconnected_radiance += beta * illuminant(texture_value(material.emission_texture, hit.uv, material.emission)) * PI;
+        }
+        let u = frand();
+        if (hit.medium_idx >= 0 && !dielectric && u >= clean_coat)
+        {
+            // medium boundary: no vertex, the subpath carries on behind it
+            ray.origin = position - normal * EPS;
+            medium = select(0u, u32(hit.medium_idx) + 1u, entering);
+            continue;
+        }
+        v.kind = VERTEX_SURFACE;
+        v.position = position;
+        v.normal = normal;
+        v.pdf_fwd = convert_density(pdf_fwd, prev.position, v);
+        let wo = -ray.direction;
+        let color = reflectance(texture_value(material.color_texture, hit.uv, material.color));
+        var pdf_rev = 0.0;
+        ray.origin = position + normal * EPS;
+        if (dielectric)
+        {
+            v.delta = true;
+            if (settings.spectral != 0u && is_dispersive(material) && !dispersed)
+            {
+                beta *= vec3<f32>(3.0, 0.0, 0.0);
+                dispersed = true;
+            }
+            let ior = material_ior(material, select(589.3, wavelengths.x, settings.spectral != 0u));
+            let eta = select(ior, 1.0 / ior, entering);
+            if (frand() < fresnel_dielectric(dot(wo, normal), eta))
+            {
+                ray.direction = reflect(ray.direction, normal);
+            }
+            else
+            {
+                ray.direction = refract(ray.direction, normal, eta);
+                ray.origin = position - normal * EPS;
+                beta *= color;
+                if (hit.medium_idx >= 0) {
+                    medium = select(0u, u32(hit.medium_idx) + 1u, entering);
+                }
+            }
+            pdf_fwd = 0.0;
+        }
+        else if (u < clean_coat)
+        {
+            v.delta = true;
+            ray.direction = reflect(ray.direction, normal);
+            pdf_fwd = 0.0;
+        }
+        else
+        {
+            v.diffuse = 1.0 - clean_coat;
+            v.albedo = v.diffuse * color;
+            ray.direction = sample_cosine_hemisphere(normal);
+            beta *= color;
+            pdf_fwd = v.diffuse * dot(ray.direction, normal) / PI;
+            pdf_rev = v.diffuse * dot(wo, normal) / PI;
+        }
+        (*path)[n] = v;
+        (*path)[n - 1u].pdf_rev = convert_density(pdf_rev, position, prev);
+        n++;
+        if (all(beta == vec3<f32>(0.0))) {
+            break;
+        }
+    }
+    return n;
+}
+// a light picked uniformly and a subpath leaving it, returning the vertex count
+fn generate_light_subpath() -> u32
+{
+    let light_count = geometry.light_count;
+    let light_idx = min(u32(frand() * f32(light_count)), light_count - 1u);
+    let light = geometry.lights[light_idx];
+    var v: PathVertex;
+    v.kind = VERTEX_LIGHT;
+    v.light_idx = i32(light_idx);
+    v.pdf_fwd = 1.0 / f32(light_count);
+    var ray: Ray;
+    var cos_theta = 1.0;
+    if (light.kind == LIGHT_DIRECTIONAL || (light.kind <= LIGHT_DISK && light.area <= 0.0)) {
+        // directional lights are sampled from camera vertices along with the sky
+        return 0u;
+    }
+    if (light.kind <= LIGHT_DISK)
+    {
+        let u = frand2();
+        if (light.kind == LIGHT_DISK)
+        {
+            let r = sqrt(u.x);
+            let phi = 2.0 * PI * u.y;
+            v.position = light.position + r * (cos(phi) * light.edge_u + sin(phi) * light.edge_v);
+        }
+        else
+        {
+            v.position = light.position + u.x * light.edge_u + u.y * light.edge_v;
+        }
+        v.normal = normalize(cross(light.edge_u, light.edge_v));
+        v.pdf_fwd /= light.area;
+        let side = select(1.0, -1.0, light.two_sided != 0u && frand() < 0.5);
+        ray.direction = sample_cosine_hemisphere(side * v.normal);
+        ray.origin = v.position + side * v.normal * EPS;
+        cos_theta = abs(dot(v.normal, ray.direction));
+    }
+    else
+    {
+        v.position = light.position;
+        ray.direction = sample_cone(light.edge_u, select(-1.0, light.cos_outer, light.kind == LIGHT_SPOT));
+        ray.origin = v.position;
+    }
+    // the light's emission is its f, so that connections to it work like any other
+    v.beta = vec3<f32>(1.0 / v.pdf_fwd);
+    light_path[0] = v;
+    let pdf_dir = light_direction_pdf(light, ray.direction);
+    let beta = v.beta * light_radiance(light, ray.direction) * cos_theta / pdf_dir;
+    return random_walk(&light_path, 1u, ray, beta, pdf_dir, u32(MAX_BDPT_DEPTH + 1), false);
+}
+fn remap0(pdf: f32) -> f32
+{
+    return select(pdf, 1.0, pdf == 0.0);
+}
+/*
+    balance heuristic over the strategies that could have made the path, from the ratios
+    of neighbouring strategies' densities; the four at the connection replace the stored ones
+*/
+fn mis_weight(s: u32, t: u32, pt_rev: f32, pt_minus_rev: f32, qs_rev: f32, qs_minus_rev: f32) -> f32
+{
+    if (s + t == 2u) {
+        return 1.0;
+    }
+    var sum = 0.0;
+    var ri = 1.0;
+    for (var i = i32(t) - 1; i > 0; i--)
+    {
+        var pdf_rev = camera_path[i].pdf_rev;
+        if (i == i32(t) - 1) {
+            pdf_rev = pt_rev;
+        } else if (i == i32(t) - 2) {
+            pdf_rev = pt_minus_rev;
+        }
+        ri *= remap0(pdf_rev) / remap0(camera_path[i].pdf_fwd);
+        if (!camera_path[i].delta && !camera_path[i - 1].delta) {
+            sum += ri;
+        }
+    }
+    ri = 1.0;
+    for (var i = i32(s) - 1; i >= 0; i--)
+    {
+        var pdf_rev = light_path[i].pdf_rev;
+        if (i == i32(s) - 1) {
+            pdf_rev = qs_rev;
+        } else if (i == i32(s) - 2) {
+            pdf_rev = qs_minus_rev;
+        }
+        ri *= remap0(pdf_rev) / remap0(light_path[i].pdf_fwd);
+        // point and spot lights cannot be hit, so nothing replaces their position
+        var delta_before = geometry.lights[light_path[0].light_idx].kind > LIGHT_DISK;
+        if (i > 0) {
+            delta_before = light_path[i - 1].delta;
+        }
+        if (!light_path[i].delta && !delta_before) {
+            sum += ri;
+        }
+    }
+    return 1.0 / (1.0 + sum);
+}
+// adds to the half of the splats that frames of the given parity merge
+fn splat(ndc: vec2<f32>, rgb: vec3<f32>, parity: u32)
+{
+    let half = parity * ACCUMULATE_SIZE * ACCUMULATE_SIZE;
+    let base = INTEGRATOR_COUNTERS + 3u * (half + accumulate_index(ndc));
+    for (var c = 0u; c < 3u; c++) {
+        // stochastic rounding keeps the fixed point sum unbiased, and adding the bits of a
+        // negative i32 wraps around like adding it would
+        atomicAdd(&counters[base + c], bitcast<u32>(i32(floor(rgb[c] * SPLAT_SCALE + frand()))));
+    }
+}
+/*
+    contribution of the path through the first s light and t camera subpath vertices;
+    with t = 1 the light subpath is seen by the camera elsewhere and splatted instead
+*/
+fn connect(s: u32, t: u32) -> vec3<f32>
+{
+    let pt = camera_path[t - 1u];
+    if (s == 0u)
+    {
+        if (pt.kind != VERTEX_LIGHT) {
+            return vec3<f32>(0.0);
+        }
+        let pt_minus = camera_path[t - 2u];
+        let light = geometry.lights[pt.light_idx];
+        let direction = normalize(pt_minus.position - pt.position);
+        let radiance = pt.beta * light_radiance(light, direction);
+        if (all(radiance == vec3<f32>(0.0))) {
+            return vec3<f32>(0.0);
+        }
+        let pt_rev = 1.0 / (f32(geometry.light_count) * light.area);
+        let pt_minus_rev = convert_density(light_direction_pdf(light, direction), pt.position, pt_minus);
+        return radiance * mis_weight(0u, t, pt_rev, pt_minus_rev, 0.0, 0.0);
+    }
+    let qs = light_path[s - 1u];
+    var qs_minus = qs;
+    if (s > 1u) {
+        qs_minus = light_path[s - 2u];
+    }
+    if (qs.delta) {
+        return vec3<f32>(0.0);
+    }
+    if (t == 1u)
+    {
+        let direction = normalize(pt.position - qs.position);
+        let pdf = camera_pdf(-direction);
+        if (pdf == 0.0) {
+            return vec3<f32>(0.0);
+        }
+        let importance = pdf / dot(-direction, normalize(sample_raygen[2].xyz));
+        let contribution = qs.beta * vertex_f(qs, qs_minus.position, pt.position) * importance;
+        if (all(contribution == vec3<f32>(0.0))) {
+            return vec3<f32>(0.0);
+        }
+        var qs_minus_rev = 0.0;
+        if (s > 1u) {
+            qs_minus_rev = vertex_pdf(qs, pt.position, qs_minus);
+        }
+        let weight = mis_weight(s, 1u, 0.0, 0.0, convert_density(pdf, pt.position, qs), qs_minus_rev);
+        // merged by the next frame, once every invocation of this one is done
+        splat(camera_ndc(-direction), spectrum_to_rgb(contribution * geometry_term(pt, qs) * weight), (camera.frame_idx + 1u) & 1u);
+        return vec3<f32>(0.0);
+    }
+    // a camera subpath ending on a light only counts when it hits it
+    if (pt.delta || pt.kind == VERTEX_LIGHT) {
+        return vec3<f32>(0.0);
+    }
+    let pt_minus = camera_path[t - 2u];
+    var contribution = qs.beta * vertex_f(qs, qs_minus.position, pt.position) * vertex_f(pt, pt_minus.position, qs.position) * pt.beta;
+    if (all(contribution == vec3<f32>(0.0))) {
+        return vec3<f32>(0.0);
+    }
+    contribution *= geometry_term(pt, qs);
+    // the hero wavelength was tripled on both sides, where it should be once
+    if (qs.dispersed && pt.dispersed) {
+        contribution /= 3.0;
+    }
+    let pt_rev = vertex_pdf(qs, qs_minus.position, pt);
+    let pt_minus_rev = vertex_pdf(pt, qs.position, pt_minus);
+    let qs_rev = vertex_pdf(pt, pt_minus.position, qs);
+    var qs_minus_rev = 0.0;
+    if (s > 1u) {
+        qs_minus_rev = vertex_pdf(qs, pt.position, qs_minus);
+    }
+    return contribution * mis_weight(s, t, pt_rev, pt_minus_rev, qs_rev, qs_minus_rev);
+}
+// the sky and directional lights, which only camera vertices sample, as the path tracer does
+fn infinite_lighting(v: PathVertex, prev: vec3<f32>) -> vec3<f32>
+{
+    var result = vec3<f32>(0.0);
+    let origin = select(v.position, v.position + v.normal * EPS, v.kind == VERTEX_SURFACE);
+    if (sky.enabled != 0u)
+    {
+        let sample = sample_sky();
+        let cos_theta = select(1.0, dot(v.normal, sample.direction), v.kind == VERTEX_SURFACE);
+        result += vertex_f(v, prev, v.position + sample.direction) * cos_theta * incident_radiance(origin, sample, v.medium);
+    }
+    for (var i = 0u; i < geometry.light_count; i++)
+    {
+        if (geometry.lights[i].kind != LIGHT_DIRECTIONAL) {
+            continue;
+        }
+        let sample = sample_directional_light(geometry.lights[i]);
+        let cos_theta = select(1.0, dot(v.normal, sample.direction), v.kind == VERTEX_SURFACE);
+        result += vertex_f(v, prev, v.position + sample.direction) * cos_theta * incident_radiance(origin, sample, v.medium);
+    }
+    return v.beta * result;
+}
+/*
+    bidirectional path tracing after Veach, "Robust Monte Carlo Methods for Light Transport
+    Simulation", 1997: a camera and a light subpath, joined in every way that makes a path
+*/
+fn bidirectional(ray: Ray) -> vec3<f32>
+{
+    unconnected_radiance = vec3<f32>(0.0);
+    var camera_vertex: PathVertex;
+    camera_vertex.kind = VERTEX_CAMERA;
+    camera_vertex.position = ray.origin;
+    camera_vertex.normal = normalize(sample_raygen[2].xyz);
+    // other projections and stereo views cannot be connected to, so MIS leaves out light tracing
+    camera_vertex.delta = camera.projection != PROJECTION_PERSPECTIVE || camera.stereo != STEREO_OFF;
+    camera_vertex.beta = vec3<f32>(1.0);
+    camera_vertex.light_idx = -1;
+    camera_path[0] = camera_vertex;
+    let camera_count = random_walk(&camera_path, 1u, ray, vec3<f32>(1.0), camera_pdf(ray.direction), u32(BDPT_VERTICES), true);
+    let light_count = generate_light_subpath();
+    var result = unconnected_radiance;
+    for (var t = 1u; t <= camera_count; t++)
+    {
+        let pt = camera_path[t - 1u];
+        if (t > 1u && !pt.delta && (pt.kind == VERTEX_SURFACE || pt.kind == VERTEX_MEDIUM)) {
+            result += infinite_lighting(pt, camera_path[t - 2u].position);
+        }
+        for (var s = 0u; s <= light_count; s++)
+        {
+            let depth = i32(s + t) - 2;
+            if ((s == 1u && t == 1u) || depth < 0 || depth > MAX_BDPT_DEPTH) {
+                continue;
+            }
+            result += connect(s, t);
+        }
+    }
+    return result;
+}
+/*
+    stochastic progressive photon mapping after Hachisuka and Jensen, "Stochastic Progressive
+    Photon Mapping", 2009: every frame is an iteration of the three passes below, and the
+    fragment shader shrinks the radii and turns the gathered flux into radiance
+*/
+// one set of wavelengths per iteration, shared by camera paths and photons so that they meet
+fn sppm_wavelengths()
+{
+    seed = camera.frame_idx * 0x000343fdu + 0x00269ec3u;
+    sample_wavelengths();
+}
+fn grid_cell(position: vec3<f32>, cell_size: f32) -> vec3<i32>
+{
+    return vec3<i32>(floor(position / cell_size));
+}
+fn grid_bucket(cell: vec3<i32>) -> u32
+{
+    let c = bitcast<vec3<u32>>(cell);
+    return ((c.x * 73856093u) ^ (c.y * 19349663u) ^ (c.z * 83492791u)) % GRID_SIZE;
+}
+// follows specular bounces from the camera to the first diffuse surface, lighting it directly
+@compute @workgroup_size(8, 8)
+fn sppm_camera(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    if (any(id.xy >= camera.resolution)) {
+        return;
+    }
+    let idx = pixel_index(id.xy);
+    sppm_wavelengths();
+    seed = (idx | camera.frame_idx << 20u) * 0x000343fdu + 0x00269ec3u;
+    var ray = generate_ray((vec2<f32>(id.xy) + 0.5) / vec2<f32>(camera.resolution) * 2.0 - 1.0);
+    var beta = vec3<f32>(1.0);
+    var direct = vec3<f32>(0.0);
+    var medium = 0u;
+    var dispersed = false;
+    var path_length = 0.0;
+    var valid = false;
+    var normal: vec3<f32>;
+    for (var i = 0; i < MAX_BOUNCE; i++)
+    {
+        let hit = intersect(ray);
+        let t = sample_free_flight(shading.media[medium], ray, hit.t, &beta);
+        if (t >= 0.0)
+        {
+            // visible points only lie on surfaces, so media are lit by single scattering
+            direct += beta * medium_lighting(ray.origin + t * ray.direction, ray.direction, medium);
+            break;
+        }
+        if (hit.light_idx >= 0)
+        {
+            direct += beta * light_emission(geometry.lights[hit.light_idx], ray.direction);
+            break;
+        }
+        if (hit.primitive_idx < 0)
+        {
+            direct += beta * environment_radiance(ray.direction);
+            break;
+        }
+        path_length += hit.t;
+        ray.origin += hit.t * ray.direction;
+        let entering = dot(ray.direction, hit.normal) < 0.0;
+        normal = select(-hit.normal, hit.normal, entering);
+        let material = shading.materials[hit.material_idx];
+        direct += beta * illuminant(texture_value(material.emission_texture, hit.uv, material.emission)) * PI;
+        if (is_dielectric(material))
+        {
+            if (settings.spectral != 0u && is_dispersive(material) && !dispersed)
+            {
+                beta *= vec3<f32>(3.0, 0.0, 0.0);
+                dispersed = true;
+            }
+            let ior = material_ior(material, select(589.3, wavelengths.x, settings.spectral != 0u));
+            let eta = select(ior, 1.0 / ior, entering);
+            if (frand() < fresnel_dielectric(-dot(ray.direction, normal), eta))
+            {
+                ray.direction = reflect(ray.direction, normal);
+                ray.origin += normal * EPS;
+            }
+            else
+            {
+                ray.direction = refract(ray.direction, normal, eta);
+                ray.origin -= normal * EPS;
+                beta *= reflectance(texture_value(material.color_texture, hit.uv, material.color));
+                if (hit.medium_idx >= 0) {
+                    medium = select(0u, u32(hit.medium_idx) + 1u, entering);
+                }
+            }
+            continue;
+        }
+        let clean_coat = surface_clean_coat(material, hit.uv);
+        let u = frand();
+        if (hit.medium_idx >= 0 && u >= clean_coat)
+        {
+            ray.origin -= normal * EPS;
+            medium = select(0u, u32(hit.medium_idx) + 1u, entering);
+            continue;
+        }
+        if (u < clean_coat)
+        {
+            ray.direction = reflect(ray.direction, normal);
+            ray.origin += normal * EPS;
+            continue;
+        }
+        beta *= reflectance(texture_value(material.color_texture, hit.uv, material.color));
+        let origin = ray.origin + normal * EPS;
+        direct += beta * (direct_lighting(origin, normal, medium) + sky_lighting(origin, normal, medium));
+        valid = true;
+        break;
+    }
+    sppm_pixels[idx].direct += spectrum_to_rgb(direct);
+    sppm_pixels[idx].valid = u32(valid);
+    if (!valid) {
+        return;
+    }
+    sppm_pixels[idx].position = ray.origin;
+    sppm_pixels[idx].normal = normal;
+    sppm_pixels[idx].beta = beta;
+    if (sppm_pixels[idx].radius == 0.0)
+    {
+        let footprint = 2.0 * length(sample_raygen[0].xyz) / (length(sample_raygen[2].xyz) * f32(camera.resolution.x));
+        sppm_pixels[idx].radius = INITIAL_RADIUS_PIXELS * footprint * path_length;
+    }
+    // radii are positive, so their bits order like the floats; the largest sizes the grid cells
+    atomicMax(&counters[INTEGRATOR_COUNTERS], bitcast<u32>(sppm_pixels[idx].radius));
+}
+// files every visible point under its grid cell, which photons search along with the neighbours
+@compute @workgroup_size(64)
+fn sppm_grid(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    let idx = id.x;
+    if (sppm_pixels[idx].valid == 0u) {
+        return;
+    }
+    let cell_size = bitcast<f32>(atomicLoad(&counters[INTEGRATOR_COUNTERS]));
+    let bucket = grid_bucket(grid_cell(sppm_pixels[idx].position, cell_size));
+    let next = atomicExchange(&counters[INTEGRATOR_COUNTERS + 1u + bucket], idx + 1u);
+    atomicStore(&counters[INTEGRATOR_COUNTERS + 1u + GRID_SIZE + idx], next);
+}
+// float atomics do not exist, so this retries until no other photon got in between
+fn add_flux(idx: u32, channel: u32, value: f32)
+{
+    var old = atomicLoad(&sppm_pixels[idx].flux[channel]);
+    loop {
+        let result = atomicCompareExchangeWeak(&sppm_pixels[idx].flux[channel], old, bitcast<u32>(bitcast<f32>(old) + value));
+        if (result.exchanged) {
+            break;
+        }
+        old = result.old_value;
+    }
+}
+fn deposit_photon(photon: PathVertex, cell_size: f32)
+{
+    let cell = grid_cell(photon.position, cell_size);
+    for (var i = 0; i < 27; i++)
+    {
+        let neighbour = cell + vec3<i32>(i % 3, i / 3 % 3, i / 9) - 1;
+        var next = atomicLoad(&counters[INTEGRATOR_COUNTERS + 1u + grid_bucket(neighbour)]);
+        while (next != 0u)
+        {
+            let idx = next - 1u;
+            next = atomicLoad(&counters[INTEGRATOR_COUNTERS + 1u + GRID_SIZE + idx]);
+            let offset = sppm_pixels[idx].position - photon.position;
+            let radius = sppm_pixels[idx].radius;
+            // buckets are shared between cells, which must not find the same point twice
+            if (any(grid_cell(sppm_pixels[idx].position, cell_size) != neighbour)
+                || dot(offset, offset) > radius * radius
+                || dot(sppm_pixels[idx].normal, photon.normal) <= 0.0) {
+                continue;
+            }
+            let flux = sppm_pixels[idx].beta * photon.beta / (PI * f32(PHOTONS_PER_ITERATION));
+            for (var c = 0u; c < 3u; c++) {
+                add_flux(idx, c, flux[c]);
+            }
+            atomicAdd(&sppm_pixels[idx].photons, 1u);
+        }
+    }
+}
+@compute @workgroup_size(64)
+fn sppm_photons(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    let cell_size = bitcast<f32>(atomicLoad(&counters[INTEGRATOR_COUNTERS]));
+    if (cell_size <= 0.0) {
+        return;
+    }
+    sppm_wavelengths();
+    seed = (id.x | camera.frame_idx << 20u) * 0x000343fdu + 0x0019660du;
+    // photons land wherever the moving primitives are at their own point of the shutter interval
+    sample_time = frand();
+    let count = generate_light_subpath();
+    // the first hit is lit directly, which the camera pass has sampled already
+    for (var i = 2u; i < count; i++)
+    {
+        if (light_path[i].kind == VERTEX_SURFACE) {
+            deposit_photon(light_path[i], cell_size);
+        }
+    }
+}
+// progressive radiance estimate of a pixel, folding in this iteration's photons
+fn sppm_radiance(idx: u32) -> vec3<f32>
+{
+    let photons = f32(atomicExchange(&sppm_pixels[idx].photons, 0u));
+    if (photons > 0.0)
+    {
+        let flux = vec3<f32>(
+            bitcast<f32>(atomicExchange(&sppm_pixels[idx].flux[0], 0u)),
+            bitcast<f32>(atomicExchange(&sppm_pixels[idx].flux[1], 0u)),
+            bitcast<f32>(atomicExchange(&sppm_pixels[idx].flux[2], 0u)),
+        );
+        let count = sppm_pixels[idx].photon_count;
+        let new_count = count + SPPM_ALPHA * photons;
+        let shrink = sqrt(new_count / (count + photons));
+        sppm_pixels[idx].tau = (sppm_pixels[idx].tau + spectrum_to_rgb(flux)) * shrink * shrink;
+        sppm_pixels[idx].radius *= shrink;
+        sppm_pixels[idx].photon_count = new_count;
+    }
+    let iterations = f32(camera.frame_idx + 1u);
+    let radius = sppm_pixels[idx].radius;
+    var result = sppm_pixels[idx].direct / iterations;
+    if (radius > 0.0) {
+        result += sppm_pixels[idx].tau / (iterations * PI * radius * radius);
+    }
+    return result;
+}
+fn luminance(rgb: vec3<f32>) -> f32
+{
+    return max(dot(rgb, vec3<f32>(0.2126, 0.7152, 0.0722)), 0.0);
+}
+/*
+    primary sample space Metropolis light transport after Kelemen et al., "A Simple and Robust
+    Mutation Strategy for the Metropolis Light Transport Algorithm", 2002, with the path tracer
+    turning the samples in mlt_samples into a path through any point of the screen
+*/
+fn mlt_evaluate() -> MltSample
+{
+    mlt_active = true;
+    mlt_sample_idx = 0u;
+    sample_wavelengths();
+    var sample: MltSample;
+    sample.ndc = frand2() * 2.0 - 1.0;
+    sample.radiance = spectrum_to_rgb(radiance(generate_ray(sample.ndc)));
+    mlt_active = false;
+    return sample;
+}
+// small step: an exponentially distributed offset between 1/1024 and 1/64, wrapping around
+fn mutate_sample(x: f32) -> f32
+{
+    let offset = exp(-log(16.0) * frand()) / 64.0;
+    return fract(x + select(-offset, offset, frand() < 0.5));
+}
+fn store_chain(chain: u32, sample: MltSample, luminance: f32)
+{
+    for (var d = 0; d < MLT_DIMENSIONS; d++) {
+        mlt.chains[chain].samples[d] = mlt_samples[d];
+    }
+    mlt.chains[chain].radiance = sample.radiance;
+    mlt.chains[chain].luminance = luminance;
+    mlt.chains[chain].ndc = sample.ndc;
+}
+fn add_bootstrap_sum(value: f32)
+{
+    var old = atomicLoad(&mlt.bootstrap_sum);
+    loop {
+        let result = atomicCompareExchangeWeak(&mlt.bootstrap_sum, old, bitcast<u32>(bitcast<f32>(old) + value));
+        if (result.exchanged) {
+            break;
+        }
+        old = result.old_value;
+    }
+}
+// estimates the normalisation from independent paths and starts each chain on one in proportion to its luminance
+@compute @workgroup_size(64)
+fn mlt_bootstrap(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    let chain = id.x;
+    seed = chain * 0x000343fdu + 0x00269ec3u;
+    var sum = 0.0;
+    for (var i = 0u; i < MLT_BOOTSTRAP; i++)
+    {
+        for (var d = 0; d < MLT_DIMENSIONS; d++) {
+            mlt_samples[d] = frand();
+        }
+        let sample = mlt_evaluate();
+        let weight = luminance(sample.radiance);
+        sum += weight;
+        if (weight > 0.0 && frand() * sum < weight) {
+            store_chain(chain, sample, weight);
+        }
+    }
+    add_bootstrap_sum(sum);
+}
+@compute @workgroup_size(64)
+fn mlt_mutate(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    let chain = id.x;
+    seed = (chain | camera.frame_idx << 20u) * 0x000343fdu + 0x0019660du;
+    let normalization = bitcast<f32>(atomicLoad(&mlt.bootstrap_sum)) / f32(MLT_CHAINS * MLT_BOOTSTRAP);
+    if (normalization <= 0.0) {
+        return;
+    }
+    // every mutation is one of this frame's samples of the whole screen
+    let scale = normalization * f32(camera.resolution.x * camera.resolution.y) / f32(MLT_CHAINS * MLT_MUTATIONS);
+    let parity = camera.frame_idx & 1u;
+    var current: MltSample;
+    current.radiance = mlt.chains[chain].radiance;
+    current.ndc = mlt.chains[chain].ndc;
+    var current_luminance = mlt.chains[chain].luminance;
+    for (var m = 0u; m < MLT_MUTATIONS; m++)
+    {
+        let large_step = frand() < MLT_LARGE_STEP;
+        for (var d = 0; d < MLT_DIMENSIONS; d++) {
+            mlt_samples[d] = select(mutate_sample(mlt.chains[chain].samples[d]), frand(), large_step);
+        }
+        let proposal = mlt_evaluate();
+        let proposal_luminance = luminance(proposal.radiance);
+        var accept = 1.0;
+        if (current_luminance > 0.0) {
+            accept = min(1.0, proposal_luminance / current_luminance);
+        }
+        // both outcomes weighted by their chance, which wastes none of the rejected paths
+        if (current_luminance > 0.0) {
+            splat(current.ndc, current.radiance * (1.0 - accept) * scale / current_luminance, parity);
+        }
+        if (proposal_luminance > 0.0) {
+            splat(proposal.ndc, proposal.radiance * accept * scale / proposal_luminance, parity);
+        }
+        if (frand() < accept)
+        {
+            current = proposal;
+            current_luminance = proposal_luminance;
+            store_chain(chain, current, current_luminance);
+        }
+    }
+}
+// the splats for this frame's parity, leaving their half empty for the next frame to share it
+fn merge_splats(idx: u32) -> vec3<f32>
+{
+    let base = INTEGRATOR_COUNTERS + 3u * ((camera.frame_idx & 1u) * ACCUMULATE_SIZE * ACCUMULATE_SIZE + idx);
+    let splatted = vec3<f32>(
+        f32(bitcast<i32>(atomicExchange(&counters[base], 0u))),
+        f32(bitcast<i32>(atomicExchange(&counters[base + 1u], 0u))),
+        f32(bitcast<i32>(atomicExchange(&counters[base + 2u], 0u))),
+    );
+    return splatted / SPLAT_SCALE;
+}
+/*
+    ReSTIR direct lighting after Bitterli et al., "Spatiotemporal Reservoir Resampling for
+    Real-Time Ray Tracing with Dynamic Direct Lighting", 2020: every pixel resamples light
+    candidates, then its reservoir from the previous frame, then its neighbours' reservoirs
+*/
+// a point on a light chosen by uv, with `radiance` holding its falloff and geometry towards origin but not its emission
+fn light_point(light: Light, uv: vec2<f32>, origin: vec3<f32>) -> LightSample
+{
+    var sample: LightSample;
+    sample.pdf = 1.0;
+    if (light.kind == LIGHT_DIRECTIONAL)
+    {
+        sample.direction = cone_direction(-light.edge_u, light.cos_outer, uv);
+        sample.distance = 1e30;
+        sample.radiance = vec3<f32>(1.0);
+        return sample;
+    }
+    var position = light.position;
+    if (light.kind == LIGHT_RECTANGLE) {
+        position += uv.x * light.edge_u + uv.y * light.edge_v;
+    } else if (light.kind == LIGHT_DISK) {
+        let r = sqrt(uv.x);
+        let phi = 2.0 * PI * uv.y;
+        position += r * (cos(phi) * light.edge_u + sin(phi) * light.edge_v);
+    }
+    let offset = position - origin;
+    sample.distance = length(offset);
+    sample.direction = offset / sample.distance;
+    var geometry = 1.0 / (sample.distance * sample.distance);
+    if (light.kind <= LIGHT_DISK)
+    {
+        let cos_light = dot(normalize(cross(light.edge_u, light.edge_v)), sample.direction);
+        geometry *= select(0.0, abs(cos_light), cos_light < 0.0 || light.two_sided != 0u);
+    }
+    else
+    {
+        geometry *= spot_falloff(light, -sample.direction);
+    }
+    sample.radiance = vec3<f32>(geometry);
+    return sample;
+}
+// density of light_point with uniform uv, per area on area lights and per solid angle on directional ones
+fn light_point_pdf(light: Light) -> f32
+{
+    if (light.kind <= LIGHT_DISK) {
+        return select(0.0, 1.0 / light.area, light.area > 0.0);
+    }
+    if (light.kind == LIGHT_DIRECTIONAL && light.cos_outer < 1.0) {
+        return 1.0 / (2.0 * PI * (1.0 - light.cos_outer));
+    }
+    return 1.0;
+}
+/*
+    target function of the resampling: the luminance of the unshadowed contribution, in RGB so
+    that it is the same whichever wavelengths the path tracer samples
+*/
+fn restir_target(surface: Reservoir, light_idx: i32, uv: vec2<f32>) -> f32
+{
+    if (light_idx < 0 || u32(light_idx) >= geometry.light_count) {
+        return 0.0;
+    }
+    let light = geometry.lights[light_idx];
+    let sample = light_point(light, uv, surface.position);
+    let cos_theta = dot(surface.normal, sample.direction);
+    if (cos_theta <= 0.0) {
+        return 0.0;
+    }
+    let color = unpack4x8unorm(surface.color).rgb;
+    return luminance(light.emission) * luminance(color) / PI * cos_theta * sample.radiance.x;
+}
+fn restir_visible(surface: Reservoir, light_idx: i32, uv: vec2<f32>) -> bool
+{
+    let sample = light_point(geometry.lights[light_idx], uv, surface.position);
+    let origin = surface.position + surface.normal * EPS;
+    return any(transmittance(origin, sample.direction, sample.distance, 0u) > vec3<f32>(0.0));
+}
+fn similar_surfaces(a: Reservoir, b: Reservoir) -> bool
+{
+    let offset = b.position - a.position;
+    let depth = length(a.position - camera.raygen[3].xyz);
+    return any(b.normal != vec3<f32>(0.0)) && dot(a.normal, b.normal) > 0.9 && dot(offset, offset) < 0.01 * depth * depth;
+}
+/*
+    resamples the reservoirs at `indices` into one for `surface`, normalising by the candidates of
+    only those reservoirs whose surfaces see the pick, which keeps reuse across pixels unbiased;
+    each reservoir counts for at most `max_m` candidates
+*/
+fn combine_reservoirs(surface: Reservoir, indices: ptr<function, array<u32, RESTIR_COMBINED>>, count: u32, max_m: f32) -> Reservoir
+{
+    var result = surface;
+    result.light_idx = -1;
+    result.m = 0.0;
+    result.weight = 0.0;
+    var weight_sum = 0.0;
+    var picked_target = 0.0;
+    for (var i = 0u; i < count; i++)
+    {
+        let candidate = reservoirs[(*indices)[i]];
+        let m = min(candidate.m, max_m);
+        let candidate_target = restir_target(surface, candidate.light_idx, candidate.uv);
+        let weight = candidate_target * candidate.weight * m;
+        weight_sum += weight;
+        result.m += m;
+        if (weight > 0.0 && frand() * weight_sum < weight)
+        {
+            result.light_idx = candidate.light_idx;
+            result.uv = candidate.uv;
+            picked_target = candidate_target;
+        }
+    }
+    if (picked_target <= 0.0) {
+        return result;
+    }
+    var normalization = 0.0;
+    for (var i = 0u; i < count; i++)
+    {
+        let candidate = reservoirs[(*indices)[i]];
+        if (restir_target(candidate, result.light_idx, result.uv) > 0.0 && restir_visible(candidate, result.light_idx, result.uv)) {
+            normalization += min(candidate.m, max_m);
+        }
+    }
+    if (normalization > 0.0) {
+        result.weight = weight_sum / (normalization * picked_target);
+    }
+    return result;
+}
+// fresh candidates for the pixel's first hit, combined with its reservoir from the previous frame
+@compute @workgroup_size(8, 8)
+fn restir_temporal(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    if (any(id.xy >= camera.resolution)) {
+        return;
+    }
+    let idx = pixel_index(id.xy);
+    seed = (idx | camera.frame_idx << 20u) * 0x000343fdu + 0x3c6ef35fu;
+    var surface: Reservoir;
+    surface.light_idx = -1;
+    let ray = generate_ray((vec2<f32>(id.xy) + 0.5) / vec2<f32>(camera.resolution) * 2.0 - 1.0);
+    let hit = intersect(ray);
+    // only diffuse surfaces seen directly, where the path tracer looks the reservoir up
+    if (hit.light_idx >= 0 || hit.primitive_idx < 0 || hit.medium_idx >= 0 || is_dielectric(shading.materials[hit.material_idx]))
+    {
+        reservoirs[idx] = surface;
+        return;
+    }
+    let material = shading.materials[hit.material_idx];
+    surface.position = ray.origin + hit.t * ray.direction;
+    surface.normal = select(-hit.normal, hit.normal, dot(ray.direction, hit.normal) < 0.0);
+    surface.color = pack4x8unorm(vec4<f32>(texture_value(material.color_texture, hit.uv, material.color), 0.0));
+    var initial = surface;
+    let light_count = geometry.light_count;
+    var weight_sum = 0.0;
+    var picked_target = 0.0;
+    for (var i = 0u; i < RESTIR_CANDIDATES; i++)
+    {
+        let light_idx = i32(min(u32(frand() * f32(light_count)), light_count - 1u));
+        let uv = frand2();
+        let pdf = light_point_pdf(geometry.lights[light_idx]) / f32(light_count);
+        if (pdf <= 0.0) {
+            continue;
+        }
+        let candidate_target = restir_target(surface, light_idx, uv);
+        let weight = candidate_target / pdf;
+        weight_sum += weight;
+        if (weight > 0.0 && frand() * weight_sum < weight)
+        {
+            initial.light_idx = light_idx;
+            initial.uv = uv;
+            picked_target = candidate_target;
+        }
+    }
+    initial.m = f32(RESTIR_CANDIDATES);
+    // an occluded pick is dropped straight away, so that it is never passed on
+    if (picked_target > 0.0 && restir_visible(surface, initial.light_idx, initial.uv)) {
+        initial.weight = weight_sum / (initial.m * picked_target);
+    }
+    reservoirs[idx] = initial;
+    // prev_view_proj only reprojects a single perspective view
+    if (camera.projection != PROJECTION_PERSPECTIVE || camera.stereo != STEREO_OFF) {
+        return;
+    }
+    let clip = camera.prev_view_proj * vec4<f32>(surface.position, 1.0);
+    let prev_ndc = clip.xy / clip.w;
+    if (clip.w <= 0.0 || any(abs(prev_ndc) > vec2<f32>(1.0))) {
+        return;
+    }
+    let prev_idx = ACCUMULATE_SIZE * ACCUMULATE_SIZE + accumulate_index(prev_ndc);
+    let prev = reservoirs[prev_idx];
+    if (!similar_surfaces(surface, prev)) {
+        return;
+    }
+    var indices: array<u32, RESTIR_COMBINED>;
+    indices[0] = idx;
+    indices[1] = prev_idx;
+    reservoirs[idx] = combine_reservoirs(surface, &indices, 2u, RESTIR_HISTORY * initial.m);
+}
+@compute @workgroup_size(8, 8)
+fn restir_spatial(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    if (any(id.xy >= camera.resolution)) {
+        return;
+    }
+    let idx = pixel_index(id.xy);
+    let center = reservoirs[idx];
+    let final_idx = ACCUMULATE_SIZE * ACCUMULATE_SIZE + idx;
+    if (all(center.normal == vec3<f32>(0.0)))
+    {
+        reservoirs[final_idx] = center;
+        return;
+    }
+    seed = (idx | camera.frame_idx << 20u) * 0x000343fdu + 0x1b873593u;
+    var indices: array<u32, RESTIR_COMBINED>;
+    indices[0] = idx;
+    var count = 1u;
+    for (var i = 0u; i < RESTIR_NEIGHBOURS; i++)
+    {
+        let u = frand2();
+        let offset = RESTIR_RADIUS * sqrt(u.x) * vec2<f32>(cos(2.0 * PI * u.y), sin(2.0 * PI * u.y));
+        let pixel = clamp(vec2<i32>(id.xy) + vec2<i32>(offset), vec2<i32>(0), vec2<i32>(camera.resolution) - 1);
+        let neighbour_idx = pixel_index(vec2<u32>(pixel));
+        if (neighbour_idx != idx && similar_surfaces(center, reservoirs[neighbour_idx]))
+        {
+            indices[count] = neighbour_idx;
+            count++;
+        }
+    }
+    // the temporal pass has already bounded the history of every reservoir here
+    reservoirs[final_idx] = combine_reservoirs(center, &indices, count, f32(RESTIR_CANDIDATES) * (RESTIR_HISTORY + 1.0));
+}
+// the pixel's resampled light, at the path tracer's first hit next to the reservoir's surface
+fn restir_lighting(origin: vec3<f32>, normal: vec3<f32>, medium: u32) -> vec3<f32>
+{
+    let reservoir = reservoirs[ACCUMULATE_SIZE * ACCUMULATE_SIZE + u32(restir_pixel)];
+    // the pixel's centre saw no diffuse surface, but this sample's jittered ray did
+    if (all(reservoir.normal == vec3<f32>(0.0))) {
+        return direct_lighting(origin, normal, medium);
+    }
+    if (reservoir.weight <= 0.0 || u32(reservoir.light_idx) >= geometry.light_count) {
+        return vec3<f32>(0.0);
+    }
+    let light = geometry.lights[reservoir.light_idx];
+    var sample = light_point(light, reservoir.uv, origin);
+    let cos_theta = dot(normal, sample.direction);
+    if (cos_theta <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+    sample.radiance *= illuminant(light.emission) * reservoir.weight;
+    return incident_radiance(origin, sample, medium) * cos_theta / PI;
+}
+// blue through cyan, green and yellow to red as x goes from 0 to 1
+fn heatmap(x: f32) -> vec3<f32>
+{
+    let x = 4.0 * clamp(x, 0.0, 1.0);
+    return clamp(vec3<f32>(x - 2.0, 2.0 - abs(x - 2.0), 2.0 - x), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+// an arbitrary but stable colour for every id
+fn id_color(id: u32) -> vec3<f32>
+{
+    var h = (id + 1u) * 0x9e3779b9u;
+    h = (h ^ (h >> 16u)) * 0x85ebca6bu;
+    h ^= h >> 13u;
+    return vec3<f32>(vec3<u32>(h, h >> 8u, h >> 16u) & vec3<u32>(255u)) / 255.0;
+}
+/*
+    what the debug view shows for one camera ray, averaged over samples and frames like radiance;
+    the path length and intersection views keep their count in x and map it to colour afterwards
+*/
+fn debug_sample(ray: Ray) -> vec3<f32>
+{
+    if (settings.debug_view == DEBUG_PATH_LENGTH || settings.debug_view == DEBUG_INTERSECTIONS)
+    {
+        sample_wavelengths();
+        intersection_tests = 0u;
+        _ = radiance(ray);
+        return vec3<f32>(select(f32(path_length), f32(intersection_tests), settings.debug_view == DEBUG_INTERSECTIONS));
+    }
+    let hit = intersect(ray);
+    if (hit.primitive_idx < 0 && hit.light_idx < 0) {
+        return vec3<f32>(0.0);
+    }
+    if (settings.debug_view == DEBUG_NORMAL) {
+        return hit.normal * 0.5 + 0.5;
+    }
+    if (settings.debug_view == DEBUG_ALBEDO)
+    {
+        if (hit.light_idx >= 0)
+        {
+            let emission = geometry.lights[hit.light_idx].emission;
+            return emission / max(max(emission.r, emission.g), max(emission.b, 1e-6));
+        }
+        let material = shading.materials[hit.material_idx];
+        return texture_value(material.color_texture, hit.uv, material.color);
+    }
+    if (settings.debug_view == DEBUG_DEPTH)
+    {
+        // near surfaces are bright and the surface at the centre of the screen mid grey
+        let center = intersect(generate_ray(vec2<f32>(0.0)));
+        return vec3<f32>(center.t / (hit.t + center.t));
+    }
+    if (settings.debug_view == DEBUG_PRIMITIVE_ID)
+    {
+        let idx = select(u32(hit.primitive_idx), u32(hit.light_idx), hit.light_idx >= 0);
+        return id_color(hit.primitive_kind << 16u | idx);
+    }
+    return select(id_color(hit.material_idx), vec3<f32>(0.0), hit.light_idx >= 0);
+}
+// display colour of an averaged debug_sample
+fn debug_color(value: vec3<f32>) -> vec3<f32>
+{
+    if (settings.debug_view == DEBUG_PATH_LENGTH) {
+        return heatmap((value.x - 1.0) / f32(MAX_BOUNCE - 1));
+    }
+    if (settings.debug_view == DEBUG_INTERSECTIONS)
+    {
+        // logarithmic, up to every primitive tested by three rays for each of MAX_BOUNCE bounces
+        let primitives = geometry.sphere_count + geometry.plane_count + geometry.quad_count + geometry.box_count + geometry.light_count;
+        return heatmap(log2(1.0 + value.x) / log2(1.0 + 3.0 * f32(MAX_BOUNCE * i32(primitives))));
+    }
+    return value;
+}
+// scales radiance by the camera's exposure, darkened towards the edges with vignetting
+fn expose(color: vec3<f32>, frag_coord: vec2<f32>) -> vec3<f32>
+{
+    var scale = camera.exposure;
+    let metered = bitcast<f32>(atomicLoad(&counters[EXPOSURE_BINS]));
+    if (camera.auto_exposure != 0u && metered > 0.0) {
+        scale = metered;
+    }
+    if (camera.vignetting != 0u && camera.projection == PROJECTION_PERSPECTIVE)
+    {
+        let ndc = eye_ndc(frag_coord, stereo_eye(frag_coord));
+        let forward = camera.raygen[2].xyz;
+        let cos_theta = dot(normalize(forward), normalize(ndc.x * camera.raygen[0].xyz + ndc.y * camera.raygen[1].xyz + forward));
+        scale *= cos_theta * cos_theta * cos_theta * cos_theta;
+    }
+    return color * scale;
+}
+fn log_luminance_bin(bin: u32) -> f32
+{
+    return MIN_LOG_LUMINANCE + (f32(bin) - 0.5) / f32(EXPOSURE_BINS - 1u) * LOG_LUMINANCE_RANGE;
+}
+@compute @workgroup_size(8, 8)
+fn exposure_histogram(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    if (any(id.xy >= camera.resolution)) {
+        return;
+    }
+    let y = luminance(accumulate[pixel_index(id.xy)]);
+    // the first bin holds the black pixels, which do not count
+    var bin = 0u;
+    if (y > 0.0) {
+        let t = clamp((log2(y) - MIN_LOG_LUMINANCE) / LOG_LUMINANCE_RANGE, 0.0, 1.0);
+        bin = 1u + min(u32(t * f32(EXPOSURE_BINS - 1u)), EXPOSURE_BINS - 2u);
+    }
+    atomicAdd(&counters[bin], 1u);
+}
+var<workgroup> exposure_counts: array<f32, EXPOSURE_BINS>;
+/*
+    meters the average log luminance between the percentiles, which saturation-based exposure
+    puts at 18% grey, and clears the histogram for the next frame
+*/
+@compute @workgroup_size(128)
+fn exposure_average(@builtin(local_invocation_index) i: u32)
+{
+    exposure_counts[i] = f32(atomicExchange(&counters[i], 0u));
+    workgroupBarrier();
+    if (i != 0u) {
+        return;
+    }
+    var total = 0.0;
+    for (var bin = 1u; bin < EXPOSURE_BINS; bin++) {
+        total += exposure_counts[bin];
+    }
+    let low = EXPOSURE_LOW_PERCENTILE * total;
+    let high = EXPOSURE_HIGH_PERCENTILE * total;
+    var seen = 0.0;
+    var sum = 0.0;
+    var weight = 0.0;
+    for (var bin = 1u; bin < EXPOSURE_BINS; bin++)
+    {
+        let counted = clamp(seen + exposure_counts[bin], low, high) - clamp(seen, low, high);
+        sum += counted * log_luminance_bin(bin);
+        weight += counted;
+        seen += exposure_counts[bin];
+    }
+    if (weight == 0.0) {
+        return;
+    }
+    // 1 / (1.2 · 2^EV100) with EV100 = log2(L · 100 / 12.5)
+    var log_scale = -log2(9.6) - sum / weight;
+    let previous = bitcast<f32>(atomicLoad(&counters[EXPOSURE_BINS]));
+    if (previous > 0.0) {
+        log_scale = mix(log2(previous), log_scale, EXPOSURE_ADAPTATION);
+    }
+    atomicStore(&counters[EXPOSURE_BINS], bitcast<u32>(exp2(log_scale)));
+}
+// first texel of a bloom mip in accumulate, going down or coming back up
+fn bloom_offset(level: u32, up: bool) -> u32
+{
+    var offset = 0u;
+    var total = 0u;
+    for (var l = 0u; l < BLOOM_LEVELS; l++)
+    {
+        let size = BLOOM_SIZE >> l;
+        if (l < level) {
+            offset += size * size;
+        }
+        total += size * size;
+    }
+    return ACCUMULATE_SIZE * ACCUMULATE_SIZE + select(offset, total + offset, up);
+}
+fn glare_offset() -> u32
+{
+    return bloom_offset(BLOOM_LEVELS, true);
+}
+// bilinear lookup in a square image of the bloom mips, clamped at the edges
+fn sample_image(base: u32, size: u32, uv: vec2<f32>) -> vec3<f32>
+{
+    let p = clamp(uv * f32(size) - 0.5, vec2<f32>(0.0), vec2<f32>(f32(size - 1u)));
+    let p0 = vec2<u32>(p);
+    let p1 = min(p0 + 1u, vec2<u32>(size - 1u));
+    let t = p - vec2<f32>(p0);
+    let bottom = mix(accumulate[base + p0.y * size + p0.x], accumulate[base + p0.y * size + p1.x], t.x);
+    let top = mix(accumulate[base + p1.y * size + p0.x], accumulate[base + p1.y * size + p1.x], t.x);
+    return mix(bottom, top, t.y);
+}
+fn image_at(uv: vec2<f32>) -> vec3<f32>
+{
+    return accumulate[accumulate_index(uv * 2.0 - 1.0)];
+}
+// the mip a bloom pass works on, told apart by the size of its dispatch
+fn bloom_level(num_workgroups: vec3<u32>) -> u32
+{
+    return firstTrailingBit(BLOOM_SIZE / (num_workgroups.x * 8u));
+}
+// averages 2 by 2 texels of the image or the mip above into each texel of the next mip
+@compute @workgroup_size(8, 8)
+fn bloom_downsample(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>)
+{
+    let level = bloom_level(num_workgroups);
+    let size = BLOOM_SIZE >> level;
+    if (any(id.xy >= vec2<u32>(size))) {
+        return;
+    }
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < 4u; i++)
+    {
+        let child = 2u * id.xy + vec2<u32>(i & 1u, i >> 1u);
+        if (level == 0u) {
+            color += image_at((vec2<f32>(child) + 0.5) / f32(2u * size));
+        } else {
+            color += accumulate[bloom_offset(level - 1u, false) + child.y * 2u * size + child.x];
+        }
+    }
+    accumulate[bloom_offset(level, false) + id.y * size + id.x] = 0.25 * color;
+}
+// adds the upsampled sum of the smaller mips to each mip, from the smallest up
+@compute @workgroup_size(8, 8)
+fn bloom_upsample(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>)
+{
+    let level = bloom_level(num_workgroups);
+    let size = BLOOM_SIZE >> level;
+    if (any(id.xy >= vec2<u32>(size))) {
+        return;
+    }
+    let uv = (vec2<f32>(id.xy) + 0.5) / f32(size);
+    let below = bloom_offset(level + 1u, level + 2u < BLOOM_LEVELS);
+    let texel = id.y * size + id.x;
+    accumulate[bloom_offset(level, true) + texel] = accumulate[bloom_offset(level, false) + texel] + sample_image(below, size / 2u, uv);
+}
+// traces the diffraction spikes over the second bloom mip, spreading red further than blue
+@compute @workgroup_size(8, 8)
+fn glare(@builtin(global_invocation_id) id: vec3<u32>)
+{
+    let uv = (vec2<f32>(id.xy) + 0.5) / f32(GLARE_SIZE);
+    let source = bloom_offset(1u, false);
+    let spread = vec3<f32>(650.0, 550.0, 450.0) / 550.0;
+    var color = vec3<f32>(0.0);
+    var weights = 0.0;
+    for (var streak = 0u; streak < post.glare_streaks; streak++)
+    {
+        let angle = (f32(streak) + 0.5) * 2.0 * PI / f32(post.glare_streaks);
+        let direction = vec2<f32>(cos(angle), sin(angle));
+        for (var i = 0; i < GLARE_SAMPLES; i++)
+        {
+            let t = f32(i) / f32(GLARE_SAMPLES);
+            let weight = exp(-4.0 * t);
+            for (var c = 0; c < 3; c++) {
+                color[c] += weight * sample_image(source, GLARE_SIZE, uv + direction * t * post.glare_length * spread[c])[c];
+            }
+            weights += weight;
+        }
+    }
+    accumulate[glare_offset() + id.y * GLARE_SIZE + id.x] = color / weights;
+}
+fn aces_tone_mapping(x: vec3<f32>) -> vec3<f32>
+{
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+fn linear_to_srgb(x: vec3<f32>) -> vec3<f32>
+{
+    return clamp(select(1.055 * pow(x, vec3<f32>(1.0 / 2.4)) - 0.055, 12.92 * x, x <= 0.00031308), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+// converts exposed working space colour to what the window shows, with 1 as paper white in HDR
+fn display(color: vec3<f32>) -> vec3<f32>
+{
+    let rgb = color_space.working_to_display * color;
+    if (color_space.encoding == ENCODING_SCRGB) {
+        // colours outside of Rec.709 keep their negative components
+        return rgb * color_space.paper_white / SCRGB_WHITE;
+    }
+    let sdr = clamp(rgb, vec3<f32>(0.0), vec3<f32>(1.0));
+    if (color_space.encoding == ENCODING_LINEAR) {
+        return sdr;
+    }
+    return linear_to_srgb(sdr);
 }
 @fragment
 fn fragment_main(@location(0) frag_coord: vec2<f32>) -> @location(0) vec4<f32> {
-    let uv = clamp(frag_coord * 0.5 + 0.5, vec2<f32>(0.0), vec2<f32>(1.0));
-    var ifrag_coord = vec2<u32>(uv * 1024.0);
-    let frag_idx = ifrag_coord.g | ifrag_coord.r << 10u;
+    let frag_idx = accumulate_index(frag_coord);
     seed = (frag_idx | camera.frame_idx << 20u) * 0x000343fdu + 0x00269ec3u;
+    if (settings.debug_view != DEBUG_OFF)
+    {
+        var value = vec3<f32>(0.0);
+        for (var i = 0; i < SPP; i++) {
+            value += debug_sample(generate_ray(frag_coord));
+        }
+        value = (value / f32(SPP) + f32(camera.frame_idx) * accumulate[frag_idx]) / f32(1u + camera.frame_idx);
+        accumulate[frag_idx] = value;
+        return vec4<f32>(debug_color(value), 1.0);
+    }
+    if (settings.integrator == INTEGRATOR_SPPM)
+    {
+        sppm_wavelengths();
+        let color = sppm_radiance(frag_idx);
+        accumulate[frag_idx] = color;
+        return vec4<f32>(display(expose(color, frag_coord)), 1.0);
+    }
+    let bdpt = settings.integrator == INTEGRATOR_BDPT;
     var color = vec3<f32>(0.0);
-    for (var i = 0; i < SPP; i++)
+    if (settings.integrator == INTEGRATOR_PSSMLT)
+    {
+        // the chains have splatted a whole frame's worth before this pass
+        color = merge_splats(frag_idx);
+    }
+    else
     {
-        let ray = generate_ray(frag_coord);
-        color += radiance(ray);
+        let spp = select(SPP, BDPT_SPP, bdpt);
+        if (settings.restir != 0u && settings.integrator == INTEGRATOR_PATH) {
+            restir_pixel = i32(frag_idx);
+        }
+        aov_active = settings.integrator == INTEGRATOR_PATH && arrayLength(&aovs) == ACCUMULATE_SIZE * ACCUMULATE_SIZE;
+        var aov_sum: Aov;
+        for (var i = 0; i < spp; i++)
+        {
+            sample_wavelengths();
+            let ray = generate_ray(frag_coord);
+            if (bdpt) {
+                color += spectrum_to_rgb(bidirectional(ray));
+            } else {
+                color += spectrum_to_rgb(radiance(ray));
+            }
+            if (aov_active) {
+                aov_sum = add_aov_sample(aov_sum);
+            }
+        }
+        if (aov_active) {
+            store_aovs(frag_idx, aov_sum, f32(spp));
+        }
+        if (bdpt) {
+            color += merge_splats(frag_idx);
+        }
+        color *= 1.0 / f32(spp);
     }
-    color *= 1.0 / f32(SPP);
     let accumulated_color = accumulate[frag_idx];
     color = (color + f32(camera.frame_idx) * accumulated_color) / f32(1u + camera.frame_idx);
     accumulate[frag_idx] = color;
-    return vec4<f32>(linear_to_srgb(color), 1.0);
+    return vec4<f32>(display(expose(color, frag_coord)), 1.0);
+}
+
+// draws the accumulated image again with the effects applied in linear light before exposure
+@fragment
+fn post_main(@location(0) frag_coord: vec2<f32>) -> @location(0) vec4<f32> {
+    let frag_idx = accumulate_index(frag_coord);
+    seed = (frag_idx | camera.frame_idx << 20u) * 0x000343fdu + 0x61c88647u;
+    let eye = stereo_eye(frag_coord);
+    let ndc = eye_ndc(frag_coord, eye);
+    let uv = frag_coord * 0.5 + 0.5;
+    var color = accumulate[frag_idx];
+    if (post.chromatic_aberration > 0.0)
+    {
+        // radial in the eye's view, squeezed back into its half of the screen
+        let squeeze = eye_ndc(vec2<f32>(1.0), eye) - eye_ndc(vec2<f32>(0.0), eye);
+        let shift = ndc * post.chromatic_aberration * CHROMATIC_ABERRATION_SCALE * dot(ndc, ndc) / squeeze;
+        color.r = accumulate[accumulate_index(frag_coord - shift)].r;
+        color.b = accumulate[accumulate_index(frag_coord + shift)].b;
+    }
+    if (post.bloom_intensity > 0.0) {
+        color = mix(color, sample_image(bloom_offset(0u, true), BLOOM_SIZE, uv) / f32(BLOOM_LEVELS), post.bloom_intensity);
+    }
+    if (post.glare_intensity > 0.0) {
+        color = mix(color, sample_image(glare_offset(), GLARE_SIZE, uv), post.glare_intensity);
+    }
+    let radius = length(ndc) / sqrt(2.0);
+    color *= 1.0 - post.vignette_strength * smoothstep(post.vignette_radius, post.vignette_radius + post.vignette_softness, radius);
+    color = expose(color, frag_coord);
+    if (post.grain_intensity > 0.0)
+    {
+        let y = clamp(luminance(color), 0.0, 1.0);
+        color += (frand() + frand() - 1.0) * post.grain_intensity * 4.0 * y * (1.0 - y);
+    }
+    return vec4<f32>(display(color), 1.0);
 }
\ No newline at end of file
@@ -0,0 +1,339 @@
+/// Lowest and highest wavelengths, in nanometers, that spectral mode samples.
+pub const LAMBDA_MIN: f64 = 360.0;
+pub const LAMBDA_MAX: f64 = 830.0;
+
+/// Entries along each axis of the RGB to spectrum table.
+pub const TABLE_RESOLUTION: usize = 16;
+
+/// CIE standard illuminant D65 from 360 to 830 nanometers in 10 nanometer steps.
+const D65: [f64; 48] = [
+    46.6383, 52.0891, 49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008,
+    117.812, 114.861, 115.923, 108.811, 109.354, 107.802, 104.79, 107.689, 104.405, 104.046,
+    100.0, 96.3342, 95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268,
+    80.2146, 82.2778, 78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927,
+    46.4182, 66.8054, 63.3828, 64.304, 59.4519, 51.959, 57.4406, 60.3125,
+];
+
+const XYZ_TO_SRGB: [[f64; 3]; 3] = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.9692660, 1.8760108, 0.0415560],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.1191920, 0.9503041],
+];
+
+/// Wavelength samples the fit integrates over, 5 nanometers apart.
+const FIT_SAMPLES: usize = 95;
+
+/// Piecewise Gaussian fit of the CIE 1931 colour matching functions, after Wyman
+/// et al., "Simple Analytic Approximations to the CIE XYZ Color Matching Functions", 2013.
+/// The shader evaluates the same fit.
+pub fn cie_xyz(lambda: f64) -> [f64; 3]
+{
+    let g = |mu: f64, sigma_low: f64, sigma_high: f64| {
+        let t = (lambda - mu) / if lambda < mu { sigma_low } else { sigma_high };
+        (-0.5 * t * t).exp()
+    };
+    [
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    ]
+}
+
+pub fn d65(lambda: f64) -> f64
+{
+    let x = ((lambda - LAMBDA_MIN) / 10.0).clamp(0.0, (D65.len() - 1) as f64);
+    let i = (x as usize).min(D65.len() - 2);
+    let f = x - i as f64;
+    D65[i] * (1.0 - f) + D65[i + 1] * f
+}
+
+#[cfg(test)]
+/// Integral of the luminance matching function, which spectral estimates are divided by.
+pub fn cie_y_integral() -> f64
+{
+    integrate(|lambda| cie_xyz(lambda)[1])
+}
+
+#[cfg(test)]
+/// Scale that gives D65 a luminance of one, so that RGB white lights stay white.
+pub fn d65_scale() -> f64
+{
+    cie_y_integral() / integrate(|lambda| cie_xyz(lambda)[1] * d65(lambda))
+}
+
+#[cfg(test)]
+fn integrate(f: impl Fn(f64) -> f64) -> f64
+{
+    let steps = 4700;
+    let h = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+    (0..steps).map(|i| f(LAMBDA_MIN + (i as f64 + 0.5) * h)).sum::<f64>() * h
+}
+
+#[cfg(test)]
+/// The reflectance spectrum `sigmoid(c0 x² + c1 x + c2)` of Jakob and Hanika,
+/// "A Low-Dimensional Function Space for Efficient Spectral Upsampling", 2019,
+/// with `x` the wavelength mapped from 360..830 nanometers to 0..1.
+pub fn sigmoid_polynomial(coefficients: [f32; 3], x: f32) -> f32
+{
+    let y = (coefficients[0] * x + coefficients[1]) * x + coefficients[2];
+    0.5 + 0.5 * y / (1.0 + y * y).sqrt()
+}
+
+fn smoothstep(t: f64) -> f64
+{
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Coefficients of the sigmoid polynomial over a grid of RGB colours.
+///
+/// A colour is looked up by its largest channel `l`, the value `z` of that channel
+/// and the ratios `x` and `y` of the following two channels to it. Entry
+/// `(l, k, j, i)` holds the fit for `z = z_k`, `y = j / (n - 1)` and `x = i / (n - 1)`,
+/// where the `z_k` cluster around black and white.
+pub struct Rgb2SpecTable
+{
+    pub resolution: usize,
+    /// Three coefficients and a zero per entry, x varying fastest, then y, z and l.
+    pub data: Vec<f32>,
+}
+
+impl Rgb2SpecTable
+{
+    /// Fits every entry with Gauss–Newton iterations on the CIELAB error under D65,
+    /// warm starting each fit from its neighbour along `z`.
+    pub fn fit(resolution: usize) -> Self
+    {
+        let fitter = Fitter::new();
+        let n = resolution;
+        let mut data = vec![0.0; 3 * n * n * n * 4];
+        for l in 0..3 {
+            for j in 0..n {
+                let y = j as f64 / (n - 1) as f64;
+                for i in 0..n {
+                    let x = i as f64 / (n - 1) as f64;
+                    let start = n / 5;
+                    let upwards = (start..n).collect::<Vec<_>>();
+                    let downwards = (0..start).rev().collect::<Vec<_>>();
+                    for ks in [upwards, downwards] {
+                        let mut coefficients = [0.0; 3];
+                        for k in ks {
+                            let z = Self::z_node(k, n);
+                            let mut rgb = [0.0; 3];
+                            rgb[l] = z;
+                            rgb[(l + 1) % 3] = x * z;
+                            rgb[(l + 2) % 3] = y * z;
+                            // a warm start can stall where a cold one converges, so retry those
+                            let error = fitter.gauss_newton(rgb, &mut coefficients);
+                            if error > 1e-6 {
+                                let mut cold = [0.0; 3];
+                                if fitter.gauss_newton(rgb, &mut cold) < error {
+                                    coefficients = cold;
+                                }
+                            }
+                            let index = (((l * n + k) * n + j) * n + i) * 4;
+                            for c in 0..3 {
+                                data[index + c] = coefficients[c] as f32;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Self { resolution, data }
+    }
+    fn z_node(k: usize, resolution: usize) -> f64
+    {
+        smoothstep(smoothstep(k as f64 / (resolution - 1) as f64))
+    }
+    #[cfg(test)]
+    /// Trilinear lookup, mirroring `spectrum_coefficients` in the shader.
+    pub fn coefficients(&self, rgb: [f32; 3]) -> [f32; 3]
+    {
+        if rgb[0] == rgb[1] && rgb[1] == rgb[2] {
+            let r = rgb[0].clamp(1e-4, 1.0 - 1e-4);
+            return [0.0, 0.0, (r - 0.5) / (r * (1.0 - r)).sqrt()];
+        }
+        let l = if rgb[0] >= rgb[1] && rgb[0] >= rgb[2] { 0 } else if rgb[1] >= rgb[2] { 1 } else { 2 };
+        let n = self.resolution;
+        let z = rgb[l] as f64;
+        let x = rgb[(l + 1) % 3] as f64 / z * (n - 1) as f64;
+        let y = rgb[(l + 2) % 3] as f64 / z * (n - 1) as f64;
+        let zi = (0..n - 1).rev().find(|k| Self::z_node(*k, n) <= z).unwrap_or(0);
+        let dz = ((z - Self::z_node(zi, n)) / (Self::z_node(zi + 1, n) - Self::z_node(zi, n))).clamp(0.0, 1.0);
+        let xi = (x as usize).min(n - 2);
+        let yi = (y as usize).min(n - 2);
+        let (dx, dy) = (x - xi as f64, y - yi as f64);
+        let mut result = [0.0; 3];
+        for (corner, weight) in (0..8).map(|c| {
+            let (a, b, k) = (c & 1, (c >> 1) & 1, (c >> 2) & 1);
+            let weight = if a == 1 { dx } else { 1.0 - dx }
+                * if b == 1 { dy } else { 1.0 - dy }
+                * if k == 1 { dz } else { 1.0 - dz };
+            ((((l * n + zi + k) * n + yi + b) * n + xi + a) * 4, weight)
+        }) {
+            for (sum, value) in result.iter_mut().zip(&self.data[corner..corner + 3]) {
+                *sum += *value as f64 * weight;
+            }
+        }
+        result.map(|c| c as f32)
+    }
+}
+
+/// Tabulated colour matching functions under D65 for the fit.
+struct Fitter
+{
+    /// sRGB response to each wavelength sample, including the quadrature weight.
+    rgb_response: [[f64; FIT_SAMPLES]; 3],
+    white: [f64; 3],
+}
+
+impl Fitter
+{
+    fn new() -> Self
+    {
+        let h = (LAMBDA_MAX - LAMBDA_MIN) / FIT_SAMPLES as f64;
+        let lambdas = std::array::from_fn::<f64, FIT_SAMPLES, _>(|i| LAMBDA_MIN + (i as f64 + 0.5) * h);
+        let normalization = lambdas.iter().map(|l| cie_xyz(*l)[1] * d65(*l)).sum::<f64>();
+        let mut rgb_response = [[0.0; FIT_SAMPLES]; 3];
+        for (i, lambda) in lambdas.iter().enumerate() {
+            let xyz = cie_xyz(*lambda).map(|c| c * d65(*lambda) / normalization);
+            for (c, row) in XYZ_TO_SRGB.iter().enumerate() {
+                rgb_response[c][i] = row[0] * xyz[0] + row[1] * xyz[1] + row[2] * xyz[2];
+            }
+        }
+        Self {
+            rgb_response,
+            white: multiply(&SRGB_TO_XYZ, [1.0; 3]),
+        }
+    }
+    fn lab(&self, rgb: [f64; 3]) -> [f64; 3]
+    {
+        let xyz = multiply(&SRGB_TO_XYZ, rgb);
+        let f = |t: f64| {
+            const DELTA: f64 = 6.0 / 29.0;
+            if t > DELTA * DELTA * DELTA { t.cbrt() } else { t / (3.0 * DELTA * DELTA) + 4.0 / 29.0 }
+        };
+        let [x, y, z] = std::array::from_fn(|c| f(xyz[c] / self.white[c]));
+        [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+    }
+    fn residual(&self, coefficients: [f64; 3], target: [f64; 3]) -> [f64; 3]
+    {
+        let mut rgb = [0.0; 3];
+        for i in 0..FIT_SAMPLES {
+            let x = (i as f64 + 0.5) / FIT_SAMPLES as f64;
+            let y = (coefficients[0] * x + coefficients[1]) * x + coefficients[2];
+            let s = 0.5 + 0.5 * y / (1.0 + y * y).sqrt();
+            for (sum, response) in rgb.iter_mut().zip(&self.rgb_response) {
+                *sum += response[i] * s;
+            }
+        }
+        let (a, b) = (self.lab(target), self.lab(rgb));
+        std::array::from_fn(|c| a[c] - b[c])
+    }
+    /// Returns the remaining squared CIELAB error.
+    fn gauss_newton(&self, target: [f64; 3], coefficients: &mut [f64; 3]) -> f64
+    {
+        for _ in 0..15 {
+            let r = self.residual(*coefficients, target);
+            let mut jacobian = [[0.0; 3]; 3];
+            for i in 0..3 {
+                const EPSILON: f64 = 1e-5;
+                let mut forward = *coefficients;
+                let mut backward = *coefficients;
+                forward[i] += EPSILON;
+                backward[i] -= EPSILON;
+                let (rf, rb) = (self.residual(forward, target), self.residual(backward, target));
+                for j in 0..3 {
+                    jacobian[j][i] = (rf[j] - rb[j]) / (2.0 * EPSILON);
+                }
+            }
+            let Some(step) = solve(jacobian, r) else {
+                break;
+            };
+            for i in 0..3 {
+                coefficients[i] -= step[i];
+            }
+            // keeps the sigmoid from saturating into a step the iteration cannot leave
+            let largest = coefficients.iter().fold(0.0f64, |a, c| a.max(c.abs()));
+            if largest > 200.0 {
+                coefficients.iter_mut().for_each(|c| *c *= 200.0 / largest);
+            }
+            if r.iter().map(|c| c * c).sum::<f64>() < 1e-12 {
+                break;
+            }
+        }
+        self.residual(*coefficients, target).iter().map(|c| c * c).sum()
+    }
+}
+
+fn multiply(m: &[[f64; 3]; 3], v: [f64; 3]) -> [f64; 3]
+{
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+/// Solves `a x = b` by Cramer's rule, or `None` when `a` is singular.
+fn solve(a: [[f64; 3]; 3], b: [f64; 3]) -> Option<[f64; 3]>
+{
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(a);
+    if d.abs() < 1e-15 {
+        return None;
+    }
+    Some(std::array::from_fn(|column| {
+        let mut m = a;
+        for row in 0..3 {
+            m[row][column] = b[row];
+        }
+        det(m) / d
+    }))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::layout::shader_constant;
+
+    /// Integrates an upsampled reflectance back to sRGB under D65.
+    fn reflectance_to_rgb(coefficients: [f32; 3]) -> [f64; 3]
+    {
+        let fitter = Fitter::new();
+        let mut rgb = [0.0; 3];
+        for i in 0..FIT_SAMPLES {
+            let s = sigmoid_polynomial(coefficients, (i as f32 + 0.5) / FIT_SAMPLES as f32) as f64;
+            for (sum, response) in rgb.iter_mut().zip(&fitter.rgb_response) {
+                *sum += response[i] * s;
+            }
+        }
+        rgb
+    }
+
+    #[test]
+    fn upsampled_colours_round_trip()
+    {
+        let table = Rgb2SpecTable::fit(TABLE_RESOLUTION);
+        for rgb in [[0.8, 0.3, 0.1], [0.1, 0.5, 0.2], [0.2, 0.3, 0.9], [0.5, 0.5, 0.5], [0.95, 0.9, 0.85]] {
+            let result = reflectance_to_rgb(table.coefficients(rgb));
+            for c in 0..3 {
+                assert!((result[c] - rgb[c] as f64).abs() < 0.02, "{:?} came back as {:?}", rgb, result);
+            }
+        }
+    }
+
+    #[test]
+    fn shader_constants_match()
+    {
+        assert!((shader_constant("CIE_Y_INTEGRAL") / cie_y_integral() - 1.0).abs() < 1e-5);
+        assert!((shader_constant("D65_SCALE") / d65_scale() - 1.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::layout::{GpuLayout, StructLayout, struct_layout};
+
+/// Photons traced per iteration, matching `PHOTONS_PER_ITERATION` in the shader.
+pub const PHOTONS_PER_ITERATION: u32 = 1 << 18;
+/// Buckets of the photon lookup grid, matching `GRID_SIZE` in the shader.
+pub const GRID_SIZE: u32 = 1 << 20;
+/// Threads per workgroup of the one dimensional passes.
+pub const WORKGROUP_SIZE: u32 = 64;
+/// Side of the square workgroups of the camera pass.
+pub const CAMERA_WORKGROUP_SIZE: u32 = 8;
+
+/// Progressive photon mapping state of one accumulate buffer entry.
+///
+/// Only ever cleared from the host; the shader owns its contents.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SppmPixel
+{
+    position: [f32; 3],
+    radius: f32,
+    normal: [f32; 3],
+    photon_count: f32,
+    beta: [f32; 3],
+    valid: u32,
+    tau: [f32; 3],
+    photons: u32,
+    direct: [f32; 3],
+    flux: [u32; 3],
+    _padding: [u32; 2],
+}
+
+impl GpuLayout for SppmPixel
+{
+    fn layout() -> StructLayout
+    {
+        struct_layout!(SppmPixel { position, radius, normal, photon_count, beta, valid, tau, photons, direct, flux })
+    }
+}
+
+/// Size of the per pixel state buffer, which holds a single unused entry unless photon mapping is used.
+pub fn pixel_buffer_size(pixels: u32, enabled: bool) -> wgpu::BufferAddress
+{
+    let pixels = if enabled { pixels as usize } else { 1 };
+    (pixels * std::mem::size_of::<SppmPixel>()) as wgpu::BufferAddress
+}
+
+/// Size of the lookup grid buffer: the cell size, the bucket heads and a link per pixel,
+/// or only the cell size unless photon mapping is used.
+pub fn grid_buffer_size(pixels: u32, enabled: bool) -> wgpu::BufferAddress
+{
+    let entries = if enabled { 1 + GRID_SIZE as wgpu::BufferAddress + pixels as wgpu::BufferAddress } else { 1 };
+    entries * std::mem::size_of::<u32>() as wgpu::BufferAddress
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::layout::{shader_constant, shader_workgroup_size};
+
+    #[test]
+    fn shader_constants_match()
+    {
+        assert_eq!(shader_constant("PHOTONS_PER_ITERATION"), PHOTONS_PER_ITERATION as f64);
+        assert_eq!(shader_constant("GRID_SIZE"), GRID_SIZE as f64);
+        assert_eq!(shader_workgroup_size("sppm_camera"), [CAMERA_WORKGROUP_SIZE, CAMERA_WORKGROUP_SIZE, 1]);
+        assert_eq!(shader_workgroup_size("sppm_grid"), [WORKGROUP_SIZE, 1, 1]);
+        assert_eq!(shader_workgroup_size("sppm_photons"), [WORKGROUP_SIZE, 1, 1]);
+    }
+
+    #[test]
+    fn grid_holds_cell_size_buckets_and_links()
+    {
+        assert_eq!(grid_buffer_size(0, true), 4 * (1 + GRID_SIZE as wgpu::BufferAddress));
+        assert_eq!(grid_buffer_size(16, true) - grid_buffer_size(0, true), 4 * 16);
+        assert_eq!(grid_buffer_size(16, false), 4);
+        // every pass dispatches whole workgroups
+        assert_eq!(PHOTONS_PER_ITERATION % WORKGROUP_SIZE, 0);
+    }
+}
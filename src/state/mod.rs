@@ -1,18 +1,64 @@
 use crate::camera::{
     Camera,
     CameraUniform,
-    CameraController
-};
-use crate::scene::{
-    SPHERES,
-    MATERIALS,
+    CameraController,
+    Stereo,
 };
+use crate::aov::{self, Aov};
+use crate::checkpoint::{self, Checkpoint};
+use crate::color::{self, DisplayEncoding};
+use crate::exposure;
+use crate::layout;
+use crate::mlt;
+use crate::options::Options;
+use crate::post;
+use crate::restir;
+use crate::scene::Scene;
+use crate::settings::{DebugView, Integrator, Settings};
+use crate::spectrum::{self, Rgb2SpecTable};
+use crate::sppm;
+use crate::watcher::{FileWatcher, canonicalize};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use winit::window::Window;
 use winit::dpi::PhysicalSize;
 use winit::event::*;
 use wgpu::include_wgsl;
 use wgpu::util::DeviceExt;
 
+/// The accumulate buffer covers a fixed 1024x1024 grid regardless of the window size.
+const ACCUMULATE_SIZE: u32 = 1024;
+/// Storage buffers in the geometry, material and accumulate bind groups, all of which every
+/// shader stage sees.
+pub const GEOMETRY_STORAGE_BUFFERS: u32 = 1;
+pub const MATERIAL_STORAGE_BUFFERS: u32 = 1;
+pub const ACCUMULATE_STORAGE_BUFFERS: u32 = 6;
+
+/// Everything built from `shader.wgsl`, replaced together when it is reloaded.
+pub struct Pipelines {
+    pub render: wgpu::RenderPipeline,
+    /// Draws the post-processed image over the rendered one.
+    pub post: wgpu::RenderPipeline,
+    /// Progressive photon mapping passes, run in this order before the render pass.
+    pub sppm_camera: wgpu::ComputePipeline,
+    pub sppm_grid: wgpu::ComputePipeline,
+    pub sppm_photons: wgpu::ComputePipeline,
+    /// Metropolis passes: the bootstrap after every restart, then mutations every frame.
+    pub mlt_bootstrap: wgpu::ComputePipeline,
+    pub mlt_mutate: wgpu::ComputePipeline,
+    /// ReSTIR passes: candidates with temporal reuse, then spatial reuse.
+    pub restir_temporal: wgpu::ComputePipeline,
+    pub restir_spatial: wgpu::ComputePipeline,
+    /// Auto exposure passes: a luminance histogram of the image, then its average.
+    pub exposure_histogram: wgpu::ComputePipeline,
+    pub exposure_average: wgpu::ComputePipeline,
+    /// Post-processing passes: the bloom mips down and back up, then glare over them.
+    pub bloom_downsample: wgpu::ComputePipeline,
+    pub bloom_upsample: wgpu::ComputePipeline,
+    pub glare: wgpu::ComputePipeline,
+}
+
 pub struct State {
     pub surface: wgpu::Surface,
     pub device: wgpu::Device,
@@ -20,20 +66,67 @@ pub struct State {
     pub config: wgpu::SurfaceConfiguration,
     pub size: PhysicalSize<u32>,
     pub window: Window,
-    pub render_pipeline: wgpu::RenderPipeline,
+    pub pipelines: Pipelines,
+    pub pipeline_layout: wgpu::PipelineLayout,
     pub camera: Camera,
     pub camera_controller: CameraController,
     pub camera_uniform: CameraUniform,
     pub camera_buffer: wgpu::Buffer,
     pub camera_bind_group: wgpu::BindGroup,
+    /// Set when the image has to start over, applied in `update` after the camera has advanced.
+    pub restart: bool,
+    pub sky_buffer: wgpu::Buffer,
+    pub settings: Settings,
+    pub settings_buffer: wgpu::Buffer,
+    pub post_buffer: wgpu::Buffer,
+    pub color_buffer: wgpu::Buffer,
+    pub display_encoding: DisplayEncoding,
+    /// Nits of diffuse white in HDR output.
+    pub paper_white: f32,
+    /// Whether the scene's post-processing effects are shown, which O toggles.
+    pub post_enabled: bool,
+    /// Filled in when spectral mode is first used, as fitting takes a moment.
+    pub rgb2spec_texture: wgpu::Texture,
+    pub rgb2spec_fitted: bool,
+    pub geometry_bind_group_layout: wgpu::BindGroupLayout,
     pub geometry_bind_group: wgpu::BindGroup,
+    pub material_bind_group_layout: wgpu::BindGroupLayout,
     pub material_bind_group: wgpu::BindGroup,
+    /// The image followed by the bloom mips, see `accumulate` in the shader.
     pub accumulate_buffer: wgpu::Buffer,
+    /// The exposure meter followed by light tracing contributions of the last two frames or the
+    /// photon lookup grid, see `counters` in the shader.
+    /// This and the buffers up to `reservoir_buffer` are placeholders until `allocate_integrator_buffers`
+    /// grows them for the first settings that use them.
+    pub counter_buffer: wgpu::Buffer,
+    /// Per pixel photon mapping state, see `sppm_pixels` in the shader.
+    pub sppm_pixel_buffer: wgpu::Buffer,
+    /// Metropolis chains and their normalisation, see `mlt` in the shader.
+    pub mlt_buffer: wgpu::Buffer,
+    /// Light samples of the last frame, kept across restarts for temporal reuse, see `reservoirs` in the shader.
+    pub reservoir_buffer: wgpu::Buffer,
+    /// Compositing passes, with a single unused entry unless they are recorded, see `aovs` in the shader.
+    pub aov_buffer: wgpu::Buffer,
+    pub aov_enabled: bool,
+    pub accumulate_bind_group_layout: wgpu::BindGroupLayout,
     pub accumulate_bind_group: wgpu::BindGroup,
+    pub shader_path: PathBuf,
+    pub scene: Scene,
+    pub scene_path: Option<PathBuf>,
+    pub scene_hash: u64,
+    /// Point in the scene's animation being shown, in seconds.
+    pub time: f32,
+    /// Distance between the eyes of stereo views in meters, kept in scene units on the camera.
+    pub interpupillary_distance: f32,
+    pub watcher: Option<FileWatcher>,
+    pub checkpoint_path: Option<PathBuf>,
+    pub checkpoint_interval: Duration,
+    pub last_checkpoint: Instant,
+    pub output_path: PathBuf,
 }
 
 impl State {
-    pub async fn new(window: Window) -> Self {
+    pub async fn new(window: Window, options: &Options, mut scene: Scene) -> Self {
         let size = window.inner_size();
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
             backends: wgpu::Backends::all(),
@@ -47,19 +140,29 @@ impl State {
                 force_fallback_adapter: false,
             },
         ).await.unwrap();
+        // the scene and accumulate buffers are packed into the default limit of eight
+        let storage_buffers = GEOMETRY_STORAGE_BUFFERS + MATERIAL_STORAGE_BUFFERS + ACCUMULATE_STORAGE_BUFFERS;
+        let available = adapter.limits().max_storage_buffers_per_shader_stage;
+        if available < storage_buffers {
+            log::error!(
+                "{} offers {} storage buffers per shader stage, but the renderer needs {}",
+                adapter.get_info().name, available, storage_buffers,
+            );
+            std::process::exit(1);
+        }
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
                 features: wgpu::Features::empty(),
-                limits: wgpu::Limits::default(),
+                limits: wgpu::Limits {
+                    max_storage_buffers_per_shader_stage: storage_buffers,
+                    ..Default::default()
+                },
                 label: None,
             },
             None,
         ).await.unwrap();
         let surface_caps = surface.get_capabilities(&adapter);
-        let surface_format = surface_caps.formats.iter()
-            .copied()
-            .find(|f| f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
+        let (surface_format, display_encoding) = color::surface_format(&surface_caps.formats, options.hdr);
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
@@ -70,20 +173,9 @@ impl State {
             view_formats: vec![],
         };
         surface.configure(&device, &config);
-        let shader = device.create_shader_module(include_wgsl!("../shader.wgsl"));
-        let camera = Camera {
-            position: (275.0, 275.0, -800.0).into(),
-            direction: (0.0, 0.0, 1.0).into(),
-            up: cgmath::Vector3::unit_y(),
-            width: 0.025,
-            height: 0.025,
-            focal_length: 0.035,
-            znear: 0.1,
-            zfar: 100.0,
-            frame_idx: 0,
-        };
+        let mut camera = Camera { stereo: options.stereo, shutter: options.shutter, ..Default::default() };
         let mut camera_uniform = CameraUniform::new();
-        camera_uniform.update(&camera);
+        camera_uniform.update(&camera, [size.width, size.height]);
         let camera_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Camera Buffer"),
@@ -91,18 +183,112 @@ impl State {
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
         );
+        scene.camera.apply(&mut camera);
+        camera.interpupillary_distance = options.ipd / scene.meters_per_unit;
+        scene.animate(0.0, &mut camera);
+        let sky_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Sky Buffer"),
+                contents: bytemuck::cast_slice(&[scene.sky_uniform()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let post_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Post Buffer"),
+                contents: bytemuck::cast_slice(&[scene.post.uniform()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let color_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Color Buffer"),
+                contents: bytemuck::cast_slice(&[scene.color.uniform(display_encoding, options.paper_white)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let settings = Settings::new(options);
+        let settings_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Settings Buffer"),
+                contents: bytemuck::cast_slice(&[settings.uniform()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let rgb2spec_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("RGB to Spectrum Texture"),
+            size: rgb2spec_extent(spectrum::TABLE_RESOLUTION),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let rgb2spec_view = rgb2spec_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // camera, sky, settings, the RGB to spectrum table and post-processing
         let camera_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
                     count: None,
-                }
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D3,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
             label: Some("camera_bind_group_layout"),
         });
@@ -112,118 +298,114 @@ impl State {
                 wgpu::BindGroupEntry {
                     binding: 0,
                     resource: camera_buffer.as_entire_binding(),
-                }
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: sky_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&rgb2spec_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: post_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: color_buffer.as_entire_binding(),
+                },
             ],
             label: Some("camera_bind_group"),
         });
-        let sphere_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Sphere Buffer"),
-                contents: bytemuck::cast_slice(SPHERES),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            }
-        );
+        // spheres, planes, quads, boxes, lights, IES profiles and the sky distribution in one buffer
         let geometry_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &(0..GEOMETRY_STORAGE_BUFFERS).map(read_only_storage_entry).collect::<Vec<_>>(),
+            label: Some("geometry_bind_group_layout"),
+        });
+        // materials, media and textures in one buffer, the density grid atlas and the textures' images
+        let material_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
+                read_only_storage_entry(0),
                 wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage {
-                            read_only: true,
-                        },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D3,
+                        multisampled: false,
                     },
                     count: None,
-                }
-            ],
-            label: Some("geometry_bind_group_layout"),
-        });
-        let geometry_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &geometry_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: sphere_buffer.as_entire_binding(),
                 },
-            ],
-            label: Some("geometry_bind_group"),
-        });
-        let material_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Material Buffer"),
-                contents: bytemuck::cast_slice(MATERIALS),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            }
-        );
-        let material_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
                 wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage {
-                            read_only: true,
-                        },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
                     },
                     count: None,
-                }
-            ],
-            label: Some("material_bind_group_layout"),
-        });
-        let material_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &material_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: material_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
                 },
             ],
-            label: Some("material_bind_group"),
+            label: Some("material_bind_group_layout"),
         });
+        let (geometry_bind_group, material_bind_group) = create_scene_bind_groups(
+            &device,
+            &queue,
+            &scene,
+            &geometry_bind_group_layout,
+            &material_bind_group_layout,
+        );
         let accumulate_buffer = device.create_buffer(
             &wgpu::BufferDescriptor {
                 label: Some("Accumulate buffer"),
-                size: (1024 * 1024 * 4 * std::mem::size_of::<f32>()) as wgpu::BufferAddress,
+                size: accumulation_size() + post::buffer_size(),
                 mapped_at_creation: false,
                 usage: wgpu::BufferUsages::COPY_DST
                     | wgpu::BufferUsages::COPY_SRC
                     | wgpu::BufferUsages::STORAGE,
             }
         );
+        let [counter_buffer, sppm_pixel_buffer, mlt_buffer, reservoir_buffer] =
+            integrator_buffer_sizes(&settings).map(|(label, size)| create_integrator_buffer(&device, label, size));
+        let aov_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("AOV buffer"),
+                size: aov::buffer_size(ACCUMULATE_SIZE * ACCUMULATE_SIZE, options.aov),
+                mapped_at_creation: false,
+                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+            }
+        );
         let accumulate_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage {
-                            read_only: false,
-                        },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }
-            ],
+            entries: &(0..ACCUMULATE_STORAGE_BUFFERS).map(read_write_storage_entry).collect::<Vec<_>>(),
             label: Some("accumulate_bind_group_layout"),
         });
-        let accumulate_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &accumulate_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: accumulate_buffer.as_entire_binding(),
-                },
+        let accumulate_bind_group = create_accumulate_bind_group(
+            &device,
+            &accumulate_bind_group_layout,
+            [
+                &accumulate_buffer,
+                &counter_buffer,
+                &sppm_pixel_buffer,
+                &mlt_buffer,
+                &reservoir_buffer,
+                &aov_buffer,
             ],
-            label: Some("accumulate_bind_group"),
-        });
-        let render_pipeline_layout =
+        );
+        let pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
+                label: Some("Pipeline Layout"),
                 bind_group_layouts: &[
                     &camera_bind_group_layout,
                     &geometry_bind_group_layout,
@@ -232,65 +414,97 @@ impl State {
                 ],
                 push_constant_ranges: &[],
             });
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vertex_main",
-                buffers: &[],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fragment_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleStrip,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
+        #[cfg(debug_assertions)]
+        if let Err(e) = layout::validate(include_str!("../shader.wgsl"), &layout::host_layouts()) {
+            panic!("host structs do not match shader.wgsl:\n{}", e);
+        }
+        let shader_source = options.watch
+            .then(|| read_shader(&options.shader))
+            .flatten();
+        let pipelines = shader_source
+            .and_then(|source| create_pipelines(&device, &pipeline_layout, config.format, wgpu::ShaderSource::Wgsl(Cow::Owned(source)))
+                .map_err(|e| log::error!("failed to compile {}: {}", options.shader.display(), e))
+                .ok())
+            .unwrap_or_else(|| create_pipelines(
+                &device,
+                &pipeline_layout,
+                config.format,
+                include_wgsl!("../shader.wgsl").source,
+            ).unwrap());
+        let watcher = options.watch
+            .then(|| {
+                let mut files = vec![options.shader.as_path()];
+                files.extend(options.scene.as_deref());
+                FileWatcher::new(&files)
+                    .map_err(|e| log::error!("failed to watch files: {}", e))
+                    .ok()
+            })
+            .flatten();
         let camera_controller = CameraController::new(6.4);
-        Self {
+        let scene_hash = checkpoint::scene_hash(&scene, &settings);
+        let mut state = Self {
             window,
             surface,
             device,
             queue,
             config,
             size,
-            render_pipeline,
+            pipelines,
+            pipeline_layout,
             camera,
             camera_controller,
             camera_uniform,
             camera_buffer,
             camera_bind_group,
+            restart: false,
+            sky_buffer,
+            settings,
+            settings_buffer,
+            post_buffer,
+            color_buffer,
+            display_encoding,
+            paper_white: options.paper_white,
+            post_enabled: true,
+            rgb2spec_texture,
+            rgb2spec_fitted: false,
+            geometry_bind_group_layout,
             geometry_bind_group,
+            material_bind_group_layout,
             material_bind_group,
             accumulate_buffer,
+            counter_buffer,
+            sppm_pixel_buffer,
+            mlt_buffer,
+            reservoir_buffer,
+            aov_buffer,
+            aov_enabled: options.aov,
+            accumulate_bind_group_layout,
             accumulate_bind_group,
-        }
+            shader_path: options.shader.clone(),
+            scene,
+            scene_path: options.scene.clone(),
+            scene_hash,
+            time: 0.0,
+            interpupillary_distance: options.ipd,
+            watcher,
+            checkpoint_path: options.checkpoint.clone(),
+            checkpoint_interval: Duration::from_secs(options.checkpoint_interval),
+            last_checkpoint: Instant::now(),
+            output_path: options.output.clone(),
+        };
+        state.fit_rgb2spec();
+        state.resume_checkpoint();
+        state
     }
     pub fn window(&self) -> &Window {
         &self.window
     }
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
+            // pixels map onto the accumulate buffer by their share of the window
+            if new_size != self.size {
+                self.restart = true;
+            }
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
@@ -298,19 +512,491 @@ impl State {
         }
     }
     pub fn input(&mut self, event: &WindowEvent) -> bool {
-        self.camera_controller.process_events(event)
+        self.camera_controller.process_events(event) || self.move_sun(event) || self.toggle_settings(event) || self.switch_projection(event) || self.toggle_post(event) || self.screenshot(event)
+    }
+    /// C cycles through the camera projections.
+    fn switch_projection(&mut self, event: &WindowEvent) -> bool {
+        let WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+                state: ElementState::Pressed,
+                virtual_keycode: Some(VirtualKeyCode::C),
+                ..
+            },
+            ..
+        } = event else {
+            return false;
+        };
+        self.camera.projection = self.camera.projection.next();
+        log::info!("{:?} projection", self.camera.projection);
+        self.restart = true;
+        true
+    }
+    /// O shows the image with or without post-processing.
+    fn toggle_post(&mut self, event: &WindowEvent) -> bool {
+        let WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+                state: ElementState::Pressed,
+                virtual_keycode: Some(VirtualKeyCode::O),
+                ..
+            },
+            ..
+        } = event else {
+            return false;
+        };
+        self.post_enabled = !self.post_enabled;
+        log::info!("post-processing {}", if self.post_enabled { "on" } else { "off" });
+        true
+    }
+    /// P writes the image so far to the output file.
+    fn screenshot(&mut self, event: &WindowEvent) -> bool {
+        let WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+                state: ElementState::Pressed,
+                virtual_keycode: Some(VirtualKeyCode::P),
+                ..
+            },
+            ..
+        } = event else {
+            return false;
+        };
+        self.save_image(&self.output_path);
+        true
+    }
+    /// M switches between RGB and spectral rendering, B cycles through the integrators,
+    /// R toggles reservoir resampling of direct lighting and V cycles through the debug views.
+    fn toggle_settings(&mut self, event: &WindowEvent) -> bool {
+        let WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+                state: ElementState::Pressed,
+                virtual_keycode: Some(keycode),
+                ..
+            },
+            ..
+        } = event else {
+            return false;
+        };
+        match keycode {
+            VirtualKeyCode::M => {
+                self.settings.spectral = !self.settings.spectral;
+                log::info!("{} rendering", if self.settings.spectral { "spectral" } else { "RGB" });
+                self.fit_rgb2spec();
+            }
+            VirtualKeyCode::B => {
+                self.settings.integrator = self.settings.integrator.next();
+                log::info!("{:?} integrator", self.settings.integrator);
+                self.allocate_integrator_buffers();
+            }
+            VirtualKeyCode::R => {
+                self.settings.restir = !self.settings.restir;
+                log::info!("direct lighting by {}", if self.settings.restir { "ReSTIR" } else { "next event estimation" });
+                self.allocate_integrator_buffers();
+            }
+            VirtualKeyCode::V => {
+                self.settings.debug_view = self.settings.debug_view.next();
+                log::info!("{:?} debug view", self.settings.debug_view);
+            }
+            _ => return false,
+        }
+        self.queue.write_buffer(&self.settings_buffer, 0, bytemuck::cast_slice(&[self.settings.uniform()]));
+        self.scene_hash = checkpoint::scene_hash(&self.scene, &self.settings);
+        self.restart = true;
+        true
+    }
+    /// I and K raise and lower the sun, J and L turn it around the zenith.
+    fn move_sun(&mut self, event: &WindowEvent) -> bool {
+        let WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+                state: ElementState::Pressed,
+                virtual_keycode: Some(keycode),
+                ..
+            },
+            ..
+        } = event else {
+            return false;
+        };
+        let Some(sky) = self.scene.sky.as_mut() else {
+            return false;
+        };
+        match keycode {
+            VirtualKeyCode::I => sky.elevation = (sky.elevation + 1.0).min(90.0),
+            VirtualKeyCode::K => sky.elevation = (sky.elevation - 1.0).max(-10.0),
+            VirtualKeyCode::J => sky.azimuth = (sky.azimuth - 5.0).rem_euclid(360.0),
+            VirtualKeyCode::L => sky.azimuth = (sky.azimuth + 5.0).rem_euclid(360.0),
+            _ => return false,
+        }
+        self.upload_scene();
+        self.restart = true;
+        true
+    }
+    fn fit_rgb2spec(&mut self) {
+        if !self.settings.spectral || self.rgb2spec_fitted {
+            return;
+        }
+        let start = Instant::now();
+        let table = Rgb2SpecTable::fit(spectrum::TABLE_RESOLUTION);
+        log::info!("fitted the RGB to spectrum table in {:.2?}", start.elapsed());
+        let extent = rgb2spec_extent(table.resolution);
+        self.queue.write_texture(
+            self.rgb2spec_texture.as_image_copy(),
+            bytemuck::cast_slice(&table.data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(extent.width * 4 * std::mem::size_of::<f32>() as u32),
+                rows_per_image: Some(extent.height),
+            },
+            extent,
+        );
+        self.rgb2spec_fitted = true;
+    }
+    /// Replaces the placeholders of the buffers only some integrators use the first time the
+    /// settings need them, as each takes tens of megabytes.
+    fn allocate_integrator_buffers(&mut self) {
+        let buffers = [
+            &mut self.counter_buffer,
+            &mut self.sppm_pixel_buffer,
+            &mut self.mlt_buffer,
+            &mut self.reservoir_buffer,
+        ];
+        let mut grown = false;
+        for (buffer, (label, size)) in buffers.into_iter().zip(integrator_buffer_sizes(&self.settings)) {
+            if buffer.size() < size {
+                *buffer = create_integrator_buffer(&self.device, label, size);
+                grown = true;
+            }
+        }
+        if !grown {
+            return;
+        }
+        self.accumulate_bind_group = create_accumulate_bind_group(
+            &self.device,
+            &self.accumulate_bind_group_layout,
+            [
+                &self.accumulate_buffer,
+                &self.counter_buffer,
+                &self.sppm_pixel_buffer,
+                &self.mlt_buffer,
+                &self.reservoir_buffer,
+                &self.aov_buffer,
+            ],
+        );
     }
     pub fn update(&mut self) {
+        if self.last_checkpoint.elapsed() >= self.checkpoint_interval {
+            self.save_checkpoint();
+        }
         self.camera_controller.update_camera(&mut self.camera);
-        self.camera_uniform.update(&self.camera);
+        if std::mem::take(&mut self.restart) {
+            self.camera.frame_idx = 0;
+        }
+        self.reload_changed_files();
+        self.camera_uniform.update(&self.camera, [self.size.width, self.size.height]);
         self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
     }
+    fn reload_changed_files(&mut self) {
+        let changed = match &self.watcher {
+            Some(watcher) => watcher.poll(),
+            None => return,
+        };
+        if changed.contains(&canonicalize(&self.shader_path)) && self.reload_shader() {
+            self.restart = true;
+        }
+        let scene_changed = self.scene_path.as_deref()
+            .is_some_and(|path| changed.contains(&canonicalize(path)));
+        if scene_changed && self.reload_scene() {
+            self.restart = true;
+        }
+    }
+    fn reload_shader(&mut self) -> bool {
+        let Some(source) = read_shader(&self.shader_path) else {
+            return false;
+        };
+        match create_pipelines(&self.device, &self.pipeline_layout, self.config.format, wgpu::ShaderSource::Wgsl(Cow::Owned(source))) {
+            Ok(pipelines) => {
+                log::info!("reloaded {}", self.shader_path.display());
+                self.pipelines = pipelines;
+                true
+            }
+            Err(e) => {
+                log::error!("failed to compile {}, keeping previous pipeline: {}", self.shader_path.display(), e);
+                false
+            }
+        }
+    }
+    fn reload_scene(&mut self) -> bool {
+        let Some(path) = self.scene_path.as_deref() else {
+            return false;
+        };
+        match Scene::load(path) {
+            Ok(scene) => {
+                log::info!("reloaded {}", path.display());
+                let sensor = scene.camera.sensor_size();
+                if sensor != self.scene.camera.sensor_size() {
+                    self.window.set_inner_size(window_size(sensor, self.camera.stereo));
+                }
+                // the projection chosen with C stays unless the scene changes its own
+                let projection = (scene.camera.projection == self.scene.camera.projection)
+                    .then_some(self.camera.projection);
+                self.scene = scene;
+                self.scene.camera.apply(&mut self.camera);
+                if let Some(projection) = projection {
+                    self.camera.projection = projection;
+                }
+                self.camera.interpupillary_distance = self.interpupillary_distance / self.scene.meters_per_unit;
+                self.scene.animate(self.time, &mut self.camera);
+                self.upload_scene();
+                true
+            }
+            Err(e) => {
+                log::error!("failed to load {}, keeping previous scene: {}", path.display(), e);
+                false
+            }
+        }
+    }
+    /// Shows the scene's animation at `time` seconds, starting the image over.
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+        self.scene.animate(time, &mut self.camera);
+        self.upload_scene();
+        self.restart = true;
+    }
+    fn upload_scene(&mut self) {
+        self.scene_hash = checkpoint::scene_hash(&self.scene, &self.settings);
+        self.queue.write_buffer(&self.sky_buffer, 0, bytemuck::cast_slice(&[self.scene.sky_uniform()]));
+        self.queue.write_buffer(&self.post_buffer, 0, bytemuck::cast_slice(&[self.scene.post.uniform()]));
+        let color = self.scene.color.uniform(self.display_encoding, self.paper_white);
+        self.queue.write_buffer(&self.color_buffer, 0, bytemuck::cast_slice(&[color]));
+        (self.geometry_bind_group, self.material_bind_group) = create_scene_bind_groups(
+            &self.device,
+            &self.queue,
+            &self.scene,
+            &self.geometry_bind_group_layout,
+            &self.material_bind_group_layout,
+        );
+    }
+    /// Saves the accumulation so far. Outside of `update` and `render`, `frame_idx`
+    /// is the index of the last rendered frame.
+    pub fn save_checkpoint(&mut self) {
+        self.last_checkpoint = Instant::now();
+        let Some(path) = self.checkpoint_path.as_deref() else {
+            return;
+        };
+        let checkpoint = Checkpoint {
+            frame_count: self.camera.frame_idx + 1,
+            width: ACCUMULATE_SIZE,
+            height: ACCUMULATE_SIZE,
+            scene_hash: self.scene_hash,
+            camera_hash: checkpoint::camera_hash(&self.camera),
+            camera: checkpoint::camera_parameters(&self.camera),
+            accumulation: self.read_accumulation(),
+        };
+        match checkpoint.save(path) {
+            Ok(()) => log::info!("saved {} frames to {}", checkpoint.frame_count, path.display()),
+            Err(e) => log::error!("failed to save checkpoint {}: {}", path.display(), e),
+        }
+    }
+    fn resume_checkpoint(&mut self) {
+        let Some(path) = self.checkpoint_path.as_deref() else {
+            return;
+        };
+        if !path.exists() {
+            return;
+        }
+        let checkpoint = match Checkpoint::load(path) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                log::warn!("failed to load checkpoint {}: {}", path.display(), e);
+                return;
+            }
+        };
+        if checkpoint.scene_hash != self.scene_hash {
+            log::warn!("checkpoint {} was rendered from a different scene, starting over", path.display());
+        } else if checkpoint.camera_hash != checkpoint::camera_hash(&self.camera) {
+            log::warn!("checkpoint {} was rendered from a different camera {:?}, starting over", path.display(), checkpoint.camera);
+        } else if self.settings.integrator == Integrator::Sppm {
+            log::warn!("checkpoint {} cannot restore photon mapping radii, starting over", path.display());
+        } else if self.settings.integrator == Integrator::Pssmlt {
+            log::warn!("checkpoint {} cannot restore Metropolis chains, starting over", path.display());
+        } else if checkpoint.width != ACCUMULATE_SIZE || checkpoint.height != ACCUMULATE_SIZE || checkpoint.frame_count == 0 {
+            log::warn!("checkpoint {} does not fit the accumulate buffer, starting over", path.display());
+        } else {
+            self.queue.write_buffer(&self.accumulate_buffer, 0, bytemuck::cast_slice(&checkpoint.accumulation));
+            // update() advances to frame_count before the next render
+            self.camera.frame_idx = checkpoint.frame_count - 1;
+            log::info!("resumed {} frames from {}", checkpoint.frame_count, path.display());
+        }
+    }
+    /// Writes the image, and the compositing passes if they are recorded, as an EXR.
+    pub fn save_image(&self, path: &Path) {
+        let accumulation = self.read_accumulation();
+        let aovs: Option<Vec<Aov>> = self.aov_enabled.then(|| self.read_buffer(&self.aov_buffer, self.aov_buffer.size()));
+        let chromaticities = self.scene.color.working_space.chromaticities();
+        match aov::write_exr(path, self.size.width, self.size.height, ACCUMULATE_SIZE, &accumulation, aovs.as_deref(), chromaticities) {
+            Ok(()) => log::info!("saved {} frames to {}", self.camera.frame_idx + 1, path.display()),
+            Err(e) => log::error!("failed to save {}: {}", path.display(), e),
+        }
+    }
+    fn read_accumulation(&self) -> Vec<f32> {
+        self.read_buffer(&self.accumulate_buffer, accumulation_size())
+    }
+    /// Reads the first `size` bytes of `buffer`.
+    fn read_buffer<T: bytemuck::Pod>(&self, buffer: &wgpu::Buffer, size: wgpu::BufferAddress) -> Vec<T> {
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback staging buffer"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback Encoder"),
+        });
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, size);
+        self.queue.submit(std::iter::once(encoder.finish()));
+        let slice = staging_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        self.device.poll(wgpu::Maintain::Wait);
+        let data = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        staging_buffer.unmap();
+        data
+    }
+    /// One progressive photon mapping iteration, which the render pass then folds into the image.
+    fn trace_photons(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.camera.frame_idx == 0 {
+            encoder.clear_buffer(&self.sppm_pixel_buffer, 0, None);
+        }
+        encoder.clear_buffer(&self.counter_buffer, exposure::buffer_size(), None);
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Photon Pass"),
+        });
+        compute_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        compute_pass.set_bind_group(1, &self.geometry_bind_group, &[]);
+        compute_pass.set_bind_group(2, &self.material_bind_group, &[]);
+        compute_pass.set_bind_group(3, &self.accumulate_bind_group, &[]);
+        compute_pass.set_pipeline(&self.pipelines.sppm_camera);
+        compute_pass.dispatch_workgroups(
+            self.size.width.div_ceil(sppm::CAMERA_WORKGROUP_SIZE),
+            self.size.height.div_ceil(sppm::CAMERA_WORKGROUP_SIZE),
+            1,
+        );
+        compute_pass.set_pipeline(&self.pipelines.sppm_grid);
+        compute_pass.dispatch_workgroups(ACCUMULATE_SIZE * ACCUMULATE_SIZE / sppm::WORKGROUP_SIZE, 1, 1);
+        compute_pass.set_pipeline(&self.pipelines.sppm_photons);
+        compute_pass.dispatch_workgroups(sppm::PHOTONS_PER_ITERATION / sppm::WORKGROUP_SIZE, 1, 1);
+    }
+    /// Picks this frame's light sample for every pixel, which the path tracer then uses at its first hit.
+    fn resample_lights(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("ReSTIR Pass"),
+        });
+        compute_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        compute_pass.set_bind_group(1, &self.geometry_bind_group, &[]);
+        compute_pass.set_bind_group(2, &self.material_bind_group, &[]);
+        compute_pass.set_bind_group(3, &self.accumulate_bind_group, &[]);
+        let workgroups_x = self.size.width.div_ceil(restir::WORKGROUP_SIZE);
+        let workgroups_y = self.size.height.div_ceil(restir::WORKGROUP_SIZE);
+        compute_pass.set_pipeline(&self.pipelines.restir_temporal);
+        compute_pass.dispatch_workgroups(workgroups_x, workgroups_y, 1);
+        compute_pass.set_pipeline(&self.pipelines.restir_spatial);
+        compute_pass.dispatch_workgroups(workgroups_x, workgroups_y, 1);
+    }
+    /// Advances every Metropolis chain, splatting into the half of the splats merged this frame.
+    fn run_chains(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.camera.frame_idx == 0 {
+            encoder.clear_buffer(&self.mlt_buffer, 0, None);
+        }
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Metropolis Pass"),
+        });
+        compute_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        compute_pass.set_bind_group(1, &self.geometry_bind_group, &[]);
+        compute_pass.set_bind_group(2, &self.material_bind_group, &[]);
+        compute_pass.set_bind_group(3, &self.accumulate_bind_group, &[]);
+        if self.camera.frame_idx == 0 {
+            compute_pass.set_pipeline(&self.pipelines.mlt_bootstrap);
+            compute_pass.dispatch_workgroups(mlt::CHAINS / mlt::WORKGROUP_SIZE, 1, 1);
+        }
+        compute_pass.set_pipeline(&self.pipelines.mlt_mutate);
+        compute_pass.dispatch_workgroups(mlt::CHAINS / mlt::WORKGROUP_SIZE, 1, 1);
+    }
+    /// Meters the image just rendered for the exposure of the next frame.
+    fn meter_exposure(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Exposure Pass"),
+        });
+        compute_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        compute_pass.set_bind_group(1, &self.geometry_bind_group, &[]);
+        compute_pass.set_bind_group(2, &self.material_bind_group, &[]);
+        compute_pass.set_bind_group(3, &self.accumulate_bind_group, &[]);
+        compute_pass.set_pipeline(&self.pipelines.exposure_histogram);
+        compute_pass.dispatch_workgroups(
+            self.size.width.div_ceil(exposure::WORKGROUP_SIZE),
+            self.size.height.div_ceil(exposure::WORKGROUP_SIZE),
+            1,
+        );
+        compute_pass.set_pipeline(&self.pipelines.exposure_average);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+    }
+    /// Builds the bloom mips and glare of the image just rendered, then draws the
+    /// post-processed image over it.
+    fn post_process(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        if self.scene.post.needs_mips() {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Bloom Pass"),
+            });
+            compute_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+            compute_pass.set_bind_group(1, &self.geometry_bind_group, &[]);
+            compute_pass.set_bind_group(2, &self.material_bind_group, &[]);
+            compute_pass.set_bind_group(3, &self.accumulate_bind_group, &[]);
+            // the shader tells the mips apart by the size of their dispatch
+            let workgroups = |level: u32| (post::BLOOM_SIZE >> level).div_ceil(post::WORKGROUP_SIZE);
+            compute_pass.set_pipeline(&self.pipelines.bloom_downsample);
+            for level in 0..post::BLOOM_LEVELS {
+                compute_pass.dispatch_workgroups(workgroups(level), workgroups(level), 1);
+            }
+            compute_pass.set_pipeline(&self.pipelines.bloom_upsample);
+            for level in (0..post::BLOOM_LEVELS - 1).rev() {
+                compute_pass.dispatch_workgroups(workgroups(level), workgroups(level), 1);
+            }
+            if self.scene.post.glare.is_some() {
+                let workgroups = post::GLARE_SIZE / post::WORKGROUP_SIZE;
+                compute_pass.set_pipeline(&self.pipelines.glare);
+                compute_pass.dispatch_workgroups(workgroups, workgroups, 1);
+            }
+        }
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Post Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.pipelines.post);
+        render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.geometry_bind_group, &[]);
+        render_pass.set_bind_group(2, &self.material_bind_group, &[]);
+        render_pass.set_bind_group(3, &self.accumulate_bind_group, &[]);
+        render_pass.draw(0..4, 0..1);
+    }
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
         let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
+        if self.camera.frame_idx == 0 {
+            encoder.clear_buffer(&self.counter_buffer, exposure::buffer_size(), None);
+        }
+        match self.settings.integrator {
+            _ if self.settings.debug_view != DebugView::Off => {}
+            Integrator::Sppm => self.trace_photons(&mut encoder),
+            Integrator::Pssmlt => self.run_chains(&mut encoder),
+            Integrator::Path if self.settings.restir => self.resample_lights(&mut encoder),
+            Integrator::Path | Integrator::Bdpt => {}
+        }
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
@@ -329,15 +1015,313 @@ impl State {
                 })],
                 depth_stencil_attachment: None,
             });
-            render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_pipeline(&self.pipelines.render);
             render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
             render_pass.set_bind_group(1, &self.geometry_bind_group, &[]);
             render_pass.set_bind_group(2, &self.material_bind_group, &[]);
             render_pass.set_bind_group(3, &self.accumulate_bind_group, &[]);
             render_pass.draw(0..4, 0..1);
         }
+        if self.camera.auto_exposure && self.settings.debug_view == DebugView::Off {
+            self.meter_exposure(&mut encoder);
+        }
+        if self.post_enabled && self.scene.post.enabled() && self.settings.debug_view == DebugView::Off {
+            self.post_process(&mut encoder, &view);
+        }
         self.queue.submit(std::iter::once(encoder.finish()));
         output.present();
         Ok(())
     }
+}
+
+/// Reads a shader from disk, rejecting it if its structs no longer match the host side.
+/// Window size for eye images 1024 pixels high in the shape of `sensor`.
+pub fn window_size(sensor: [f32; 2], stereo: Stereo) -> PhysicalSize<u32> {
+    let [across, down] = stereo.layout();
+    PhysicalSize::new((1024.0 * sensor[0] / sensor[1]).round() as u32 * across, 1024 * down)
+}
+
+fn read_shader(path: &Path) -> Option<String> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| log::error!("failed to read {}: {}", path.display(), e))
+        .ok()?;
+    match layout::validate(&source, &layout::host_layouts()) {
+        Ok(()) => Some(source),
+        Err(e) => {
+            log::error!("{} does not match the host structs:\n{}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn create_scene_bind_groups(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    scene: &Scene,
+    geometry_bind_group_layout: &wgpu::BindGroupLayout,
+    material_bind_group_layout: &wgpu::BindGroupLayout,
+) -> (wgpu::BindGroup, wgpu::BindGroup) {
+    let geometry_buffer = create_storage_buffer(device, "Geometry Buffer", bytemuck::bytes_of(&*scene.gpu_geometry()));
+    let geometry_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: geometry_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: geometry_buffer.as_entire_binding(),
+            },
+        ],
+        label: Some("geometry_bind_group"),
+    });
+    let shading_buffer = create_storage_buffer(device, "Shading Buffer", bytemuck::bytes_of(&*scene.gpu_shading()));
+    let ([width, height, depth], grid_atlas) = scene.grid_atlas();
+    let grid_texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Density Grid Texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: depth,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        bytemuck::cast_slice(&grid_atlas),
+    );
+    let grid_view = grid_texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let ([width, height, layers], images) = scene.image_array();
+    let image_texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Image Texture Array"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            // sRGB is decoded in the shader, so that linear images can share the array
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        &images,
+    );
+    let image_view = image_texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    let image_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("Image Sampler"),
+        address_mode_u: wgpu::AddressMode::Repeat,
+        address_mode_v: wgpu::AddressMode::Repeat,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+    let material_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: material_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: shading_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&grid_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&image_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Sampler(&image_sampler),
+            },
+        ],
+        label: Some("material_bind_group"),
+    });
+    (geometry_bind_group, material_bind_group)
+}
+
+/// The RGB to spectrum table, with the three tables for each largest channel stacked along z.
+fn rgb2spec_extent(resolution: usize) -> wgpu::Extent3d {
+    wgpu::Extent3d {
+        width: resolution as u32,
+        height: resolution as u32,
+        depth_or_array_layers: 3 * resolution as u32,
+    }
+}
+
+fn read_only_storage_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    storage_entry(binding, true)
+}
+
+fn read_write_storage_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    storage_entry(binding, false)
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage {
+                read_only,
+            },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+/// Labels and sizes of the buffers only some integrators use, each holding a single unused
+/// entry unless `settings` need it, after the exposure meter for the counters.
+fn integrator_buffer_sizes(settings: &Settings) -> [(&'static str, wgpu::BufferAddress); 4] {
+    let pixels = ACCUMULATE_SIZE * ACCUMULATE_SIZE;
+    let sppm = settings.integrator == Integrator::Sppm;
+    let pssmlt = settings.integrator == Integrator::Pssmlt;
+    let restir = settings.integrator == Integrator::Path && settings.restir;
+    // light tracing and the Metropolis chains splat into one half per frame parity
+    let splats = match settings.integrator {
+        Integrator::Bdpt | Integrator::Pssmlt => 2 * pixels as usize * 3,
+        Integrator::Path | Integrator::Sppm => 1,
+    };
+    let splats = (splats * std::mem::size_of::<i32>()) as wgpu::BufferAddress;
+    // the splats and the photon grid share the counters after the exposure meter
+    let counters = exposure::buffer_size() + splats.max(sppm::grid_buffer_size(pixels, sppm));
+    [
+        ("Counter buffer", counters),
+        ("SPPM pixel buffer", sppm::pixel_buffer_size(pixels, sppm)),
+        ("MLT buffer", mlt::state_buffer_size(pssmlt)),
+        ("Reservoir buffer", restir::buffer_size(pixels, restir)),
+    ]
+}
+
+fn create_integrator_buffer(device: &wgpu::Device, label: &str, size: wgpu::BufferAddress) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+    })
+}
+
+/// Binds `buffers` in the order of group 3 in the shader.
+fn create_accumulate_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffers: [&wgpu::Buffer; ACCUMULATE_STORAGE_BUFFERS as usize],
+) -> wgpu::BindGroup {
+    let entries: Vec<_> = buffers
+        .iter()
+        .enumerate()
+        .map(|(binding, buffer)| wgpu::BindGroupEntry {
+            binding: binding as u32,
+            resource: buffer.as_entire_binding(),
+        })
+        .collect();
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &entries,
+        label: Some("accumulate_bind_group"),
+    })
+}
+
+fn create_storage_buffer(device: &wgpu::Device, label: &str, contents: &[u8]) -> wgpu::Buffer {
+    device.create_buffer_init(
+        &wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        }
+    )
+}
+
+/// Bytes of the image at the front of the accumulate buffer, an RGB entry padded to four floats
+/// for every pixel.
+fn accumulation_size() -> wgpu::BufferAddress {
+    (ACCUMULATE_SIZE as usize * ACCUMULATE_SIZE as usize * 4 * std::mem::size_of::<f32>()) as wgpu::BufferAddress
+}
+
+/// Builds the pipelines inside a validation error scope, so that a broken
+/// shader is reported as an error instead of bringing the device down.
+fn create_pipelines(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    source: wgpu::ShaderSource,
+) -> Result<Pipelines, wgpu::Error> {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("shader.wgsl"),
+        source,
+    });
+    let render_pipeline = |label, entry_point| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vertex_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point,
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleStrip,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    });
+    let compute_pipeline = |entry_point| device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some(entry_point),
+        layout: Some(layout),
+        module: &shader,
+        entry_point,
+    });
+    let pipelines = Pipelines {
+        render: render_pipeline("Render Pipeline", "fragment_main"),
+        post: render_pipeline("Post Pipeline", "post_main"),
+        sppm_camera: compute_pipeline("sppm_camera"),
+        sppm_grid: compute_pipeline("sppm_grid"),
+        sppm_photons: compute_pipeline("sppm_photons"),
+        mlt_bootstrap: compute_pipeline("mlt_bootstrap"),
+        mlt_mutate: compute_pipeline("mlt_mutate"),
+        restir_temporal: compute_pipeline("restir_temporal"),
+        restir_spatial: compute_pipeline("restir_spatial"),
+        exposure_histogram: compute_pipeline("exposure_histogram"),
+        exposure_average: compute_pipeline("exposure_average"),
+        bloom_downsample: compute_pipeline("bloom_downsample"),
+        bloom_upsample: compute_pipeline("bloom_upsample"),
+        glare: compute_pipeline("glare"),
+    };
+    match pollster::block_on(device.pop_error_scope()) {
+        Some(e) => Err(e),
+        None => Ok(pipelines),
+    }
 }
\ No newline at end of file
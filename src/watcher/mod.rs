@@ -0,0 +1,59 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Watches a set of files and reports which of them changed since the last poll.
+///
+/// The parent directories are watched rather than the files themselves, so that
+/// editors which save by writing a temporary file and renaming it are picked up.
+pub struct FileWatcher
+{
+    _watcher: RecommendedWatcher,
+    receiver: Receiver<notify::Result<Event>>,
+    files: HashSet<PathBuf>,
+}
+
+impl FileWatcher
+{
+    pub fn new(files: &[&Path]) -> notify::Result<Self>
+    {
+        let (sender, receiver) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        let files: HashSet<PathBuf> = files.iter()
+            .map(|file| canonicalize(file))
+            .collect();
+        let directories: HashSet<&Path> = files.iter()
+            .filter_map(|file| file.parent())
+            .collect();
+        for directory in directories {
+            watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        }
+        Ok(Self {
+            _watcher: watcher,
+            receiver,
+            files,
+        })
+    }
+    pub fn poll(&self) -> HashSet<PathBuf>
+    {
+        let mut changed = HashSet::new();
+        for event in self.receiver.try_iter() {
+            match event {
+                Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                    changed.extend(event.paths.iter()
+                        .map(|path| canonicalize(path))
+                        .filter(|path| self.files.contains(path)));
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("file watcher error: {}", e),
+            }
+        }
+        changed
+    }
+}
+
+pub fn canonicalize(path: &Path) -> PathBuf
+{
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}